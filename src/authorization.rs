@@ -0,0 +1,242 @@
+use crate::data::{Gift, ListRole, User};
+use crate::error::AppError;
+use crate::repository::Repository;
+
+pub struct Authorization;
+
+impl Authorization {
+    pub fn can_create_list(user: &User) -> Result<(), AppError> {
+        match user.can_create {
+            true => Ok(()),
+            false => Err(AppError::Forbidden),
+        }
+    }
+
+    // Anyone on the list can see who's on it
+    pub async fn can_view_list(
+        user: &User,
+        list_uuid: String,
+        conn: &dyn Repository,
+    ) -> Result<ListRole, AppError> {
+        match conn
+            .role_of_user(list_uuid.to_owned(), user.uuid.to_owned())
+            .await
+        {
+            // Lists that don't exist are a bad link, not a locked door
+            Err(AppError::NotFound) => {
+                conn.list_from_uuid(list_uuid, user.uuid.to_owned()).await?;
+                Err(AppError::Forbidden)
+            }
+            result => result,
+        }
+    }
+
+    pub async fn can_manage_list(
+        user: &User,
+        list_uuid: String,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        match Authorization::can_view_list(user, list_uuid, conn).await? {
+            role if role.can_manage() => Ok(()),
+            _ => Err(AppError::Forbidden),
+        }
+    }
+
+    pub async fn can_view_wishlist(
+        user: &User,
+        list_uuid: String,
+        wishlist_user_uuid: String,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        Authorization::can_view_list(user, list_uuid.to_owned(), conn).await?;
+        match conn.role_of_user(list_uuid, wishlist_user_uuid).await? {
+            role if role.has_wishlist() => Ok(()),
+            _ => Err(AppError::NotFound),
+        }
+    }
+
+    pub async fn can_edit_wishlist(
+        user: &User,
+        list_uuid: String,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        match Authorization::can_view_list(user, list_uuid, conn).await? {
+            role if role.has_wishlist() => Ok(()),
+            _ => Err(AppError::Forbidden),
+        }
+    }
+
+    // Claiming needs a giving role on the list the gift belongs to
+    pub async fn can_claim_gift(
+        user: &User,
+        gift: &Gift,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        let list_uuid = conn.list_uuid_of_gift(gift.uuid.to_owned()).await?;
+        Authorization::can_view_wishlist(user, list_uuid.to_owned(), gift.owner.to_owned(), conn)
+            .await?;
+        match Authorization::can_view_list(user, list_uuid, conn).await? {
+            role if role.can_give() => Ok(()),
+            _ => Err(AppError::Forbidden),
+        }
+    }
+
+    pub fn can_edit_gift(user: &User, gift: &Gift) -> Result<(), AppError> {
+        match gift.owner == user.uuid {
+            true => Ok(()),
+            false => Err(AppError::Forbidden),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::Authorization;
+    use crate::data::{List, ListRole, User};
+    use crate::error::AppError;
+    use crate::memory::MemoryStore;
+    use crate::repository::Repository;
+    use crate::repository_tests::gift;
+
+    async fn new_user(conn: &dyn Repository, name: &str) -> User {
+        let uuid = Uuid::new_v4().to_string();
+        let user = User {
+            email: format!("{}@example.com", uuid),
+            uuid,
+            name: name.to_string(),
+            can_create: true,
+            is_admin: false,
+            is_me: false,
+        };
+        conn.create_user(user.to_owned()).await.unwrap();
+        user
+    }
+
+    #[rocket::async_test]
+    async fn roles_decide_who_does_what() {
+        let conn = MemoryStore::default();
+        let owner = new_user(&conn, "Ada").await;
+        let giver = new_user(&conn, "Grace").await;
+        let recipient = new_user(&conn, "Linus").await;
+        let outsider = new_user(&conn, "Ken").await;
+        let list_uuid = Uuid::new_v4().to_string();
+        conn.create_list(List {
+            uuid: list_uuid.to_owned(),
+            name: "Birthdays".to_string(),
+            owner: owner.uuid.to_owned(),
+            my_role: None,
+            can_manage: false,
+        })
+        .await
+        .unwrap();
+        for (user, role) in [(&giver, ListRole::Giver), (&recipient, ListRole::Recipient)] {
+            conn.add_user_to_list(user.uuid.to_owned(), list_uuid.to_owned(), role)
+                .await
+                .unwrap();
+        }
+        let gift_uuid = Uuid::new_v4().to_string();
+        conn.save_gift_list(
+            list_uuid.to_owned(),
+            owner.uuid.to_owned(),
+            0,
+            Vec::new(),
+            vec![gift(&gift_uuid, &owner.uuid, "book")],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        let gift = conn
+            .gift_from_uuid(gift_uuid, owner.uuid.to_owned())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            Authorization::can_view_list(&giver, list_uuid.to_owned(), &conn).await,
+            Ok(ListRole::Giver)
+        ));
+        assert!(matches!(
+            Authorization::can_view_list(&outsider, list_uuid.to_owned(), &conn).await,
+            Err(AppError::Forbidden)
+        ));
+        assert!(matches!(
+            Authorization::can_view_list(&owner, "missing".to_string(), &conn).await,
+            Err(AppError::NotFound)
+        ));
+
+        Authorization::can_manage_list(&owner, list_uuid.to_owned(), &conn)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Authorization::can_manage_list(&giver, list_uuid.to_owned(), &conn).await,
+            Err(AppError::Forbidden)
+        ));
+
+        // Givers have no wishlist to look at or edit
+        Authorization::can_view_wishlist(
+            &giver,
+            list_uuid.to_owned(),
+            recipient.uuid.to_owned(),
+            &conn,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            Authorization::can_view_wishlist(
+                &recipient,
+                list_uuid.to_owned(),
+                giver.uuid.to_owned(),
+                &conn
+            )
+            .await,
+            Err(AppError::NotFound)
+        ));
+        assert!(matches!(
+            Authorization::can_view_wishlist(
+                &outsider,
+                list_uuid.to_owned(),
+                owner.uuid.to_owned(),
+                &conn
+            )
+            .await,
+            Err(AppError::Forbidden)
+        ));
+        Authorization::can_edit_wishlist(&recipient, list_uuid.to_owned(), &conn)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Authorization::can_edit_wishlist(&giver, list_uuid.to_owned(), &conn).await,
+            Err(AppError::Forbidden)
+        ));
+
+        // Recipients only receive, and outsiders can't claim at all
+        Authorization::can_claim_gift(&giver, &gift, &conn)
+            .await
+            .unwrap();
+        for user in [&recipient, &outsider] {
+            assert!(matches!(
+                Authorization::can_claim_gift(user, &gift, &conn).await,
+                Err(AppError::Forbidden)
+            ));
+        }
+
+        Authorization::can_edit_gift(&owner, &gift).unwrap();
+        assert!(matches!(
+            Authorization::can_edit_gift(&giver, &gift),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn only_some_users_create_lists() {
+        let conn = MemoryStore::default();
+        let mut user = new_user(&conn, "Ada").await;
+        Authorization::can_create_list(&user).unwrap();
+        user.can_create = false;
+        assert!(matches!(
+            Authorization::can_create_list(&user),
+            Err(AppError::Forbidden)
+        ));
+    }
+}
@@ -0,0 +1,357 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket_sync_db_pools::rusqlite;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::data::{AppConfig, BackupFile};
+use crate::error::AppError;
+use crate::migrations::Migrations;
+use crate::repository::{Repository, StorageBackend};
+use crate::schedule::Scheduled;
+
+pub struct Backups;
+
+// Held by a running server, so a restore can tell the database is in use
+pub struct DatabaseLock {
+    _file: File,
+}
+
+impl Backups {
+    const PREFIX: &'static str = "gifts-";
+    const EXTENSION: &'static str = "sqlite";
+
+    // Copies go this many pages at a time, so writers only ever wait for one step
+    const STEP_PAGES: i32 = 64;
+    const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    // Named after when they were taken, so the newest sort last
+    fn taken_at(path: &Path) -> Option<i64> {
+        if path.extension()? != Backups::EXTENSION {
+            return None;
+        }
+        path.file_stem()?
+            .to_str()?
+            .strip_prefix(Backups::PREFIX)?
+            .parse()
+            .ok()
+    }
+
+    fn backup_error(error: std::io::Error) -> AppError {
+        AppError::Backend(format!("couldn't write the backup ({})", error))
+    }
+
+    // Only SQLite keeps its data in a file this can copy
+    pub fn supported(backend: StorageBackend) -> Result<(), AppError> {
+        match backend {
+            StorageBackend::Sqlite => Ok(()),
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres => Err(AppError::Validation(
+                "Backups only cover SQLite storage, back up Postgres with pg_dump".to_string(),
+            )),
+        }
+    }
+
+    fn database(figment: &Figment) -> Result<String, AppError> {
+        figment
+            .extract_inner("databases.sqlite_logs.url")
+            .map_err(|e| AppError::Backend(e.to_string()))
+    }
+
+    fn lock_database(database: &str) -> Result<DatabaseLock, AppError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", database))
+            .map_err(Backups::backup_error)?;
+        match file.try_lock() {
+            Ok(()) => Ok(DatabaseLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(AppError::Validation(
+                "The database is in use, stop the server first".to_string(),
+            )),
+            Err(TryLockError::Error(e)) => Err(Backups::backup_error(e)),
+        }
+    }
+
+    // Kept for as long as the server runs
+    pub fn lock() -> AdHoc {
+        AdHoc::try_on_ignite("Database Lock", |rocket| async move {
+            if rocket.state::<StorageBackend>() != Some(&StorageBackend::Sqlite) {
+                return Ok(rocket);
+            }
+            match Backups::database(rocket.figment()).and_then(|db| Backups::lock_database(&db)) {
+                Ok(lock) => Ok(rocket.manage(lock)),
+                Err(e) => {
+                    rocket::error!("Couldn't lock the database: {}", e);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    pub fn copy(from: &Connection, to: &mut Connection) -> rusqlite::Result<()> {
+        Backup::new(from, to)?.run_to_completion(Backups::STEP_PAGES, Backups::STEP_PAUSE, None)
+    }
+
+    pub fn list(config: &AppConfig) -> Result<Vec<BackupFile>, AppError> {
+        let entries = match fs::read_dir(&config.backup_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Backups::backup_error(e)),
+        };
+        let mut backups = vec![];
+        for entry in entries {
+            let entry = entry.map_err(Backups::backup_error)?;
+            if let Some(taken) = Backups::taken_at(&entry.path()) {
+                backups.push(BackupFile {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                    taken,
+                    size_kb: entry.metadata().map_err(Backups::backup_error)?.len() / 1024,
+                });
+            }
+        }
+        backups.sort_by_key(|b| b.taken);
+        Ok(backups)
+    }
+
+    // Gives a temporary name to copy into and the name to move it to once it's complete, so a
+    // half-written file never looks like a backup
+    fn reserve(config: &AppConfig, prefix: &str) -> Result<(PathBuf, PathBuf), AppError> {
+        let dir = Path::new(&config.backup_dir);
+        fs::create_dir_all(dir).map_err(Backups::backup_error)?;
+        let name = format!("{}{}", prefix, Backups::now());
+        let path = dir.join(&name).with_extension(Backups::EXTENSION);
+        if path.exists() {
+            return Err(AppError::Validation(
+                "A backup was just taken, try again in a moment".to_string(),
+            ));
+        }
+        let partial = dir.join(&name).with_extension("partial");
+        let _ = fs::remove_file(&partial);
+        Ok((partial, path))
+    }
+
+    pub async fn take(
+        backend: StorageBackend,
+        conn: &dyn Repository,
+        config: &AppConfig,
+    ) -> Result<PathBuf, AppError> {
+        Backups::supported(backend)?;
+        Backups::write(conn, config).await
+    }
+
+    async fn write(conn: &dyn Repository, config: &AppConfig) -> Result<PathBuf, AppError> {
+        let (partial, path) = Backups::reserve(config, Backups::PREFIX)?;
+        if let Err(e) = conn.backup(partial.to_owned()).await {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, &path).map_err(Backups::backup_error)?;
+        Backups::prune(config)?;
+        Ok(path)
+    }
+
+    fn prune(config: &AppConfig) -> Result<(), AppError> {
+        let backups = Backups::list(config)?;
+        let excess = backups.len().saturating_sub(config.backup_keep.max(1));
+        for backup in &backups[..excess] {
+            fs::remove_file(Path::new(&config.backup_dir).join(&backup.name))
+                .map_err(Backups::backup_error)?;
+        }
+        Ok(())
+    }
+
+    // Works on the database file rather than through the server, so a database that won't start
+    // can still be replaced, and refuses while a server has it open. What was there is saved
+    // first, outside the rotation since the backup being restored may well be the oldest one.
+    // Gives where it went and the backup's schema version.
+    pub fn restore(figment: &Figment, backup: &Path) -> Result<(PathBuf, i64), AppError> {
+        Backups::supported(StorageBackend::from_figment(figment).map_err(AppError::Validation)?)?;
+        let config: AppConfig = figment
+            .extract()
+            .map_err(|e| AppError::Backend(e.to_string()))?;
+        let database = Backups::database(figment)?;
+        let _lock = Backups::lock_database(&database)?;
+
+        let source = Connection::open_with_flags(backup, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let version = Migrations::check_backup(&source)
+            .map_err(|e| AppError::Validation(format!("Can't restore that backup: {}", e)))?;
+
+        let mut live = Connection::open(database)?;
+        let (partial, saved) = Backups::reserve(&config, "before-restore-")?;
+        Backups::copy(&live, &mut Connection::open(&partial)?)?;
+        fs::rename(&partial, &saved).map_err(Backups::backup_error)?;
+
+        Backups::copy(&source, &mut live)?;
+        Migrations::migrate(&mut live).map_err(AppError::Backend)?;
+        Ok((saved, version))
+    }
+
+    pub fn schedule() -> Scheduled {
+        Scheduled::new(
+            "Scheduled Backups",
+            |config, backend| {
+                let hours = config.backup_interval_hours as i64;
+                (hours > 0 && Backups::supported(backend).is_ok()).then_some(hours * 60 * 60)
+            },
+            // A restart is no reason for a backup
+            false,
+            // Only scheduled where backups are supported, so no need to check again
+            |conn, config| {
+                Box::pin(async move {
+                    match Backups::write(&*conn, &config).await {
+                        Ok(path) => rocket::info!("Backed up the database to {}", path.display()),
+                        Err(e) => rocket::error!("Scheduled backup failed: {}", e),
+                    }
+                })
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::Figment;
+    use rocket_sync_db_pools::rusqlite;
+    use rusqlite::{params, Connection};
+    use std::fs;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    use super::Backups;
+    use crate::data::AppConfig;
+    use crate::db::tests::storage;
+    use crate::db::DbConn;
+    use crate::error::AppError;
+    use crate::migrations::Migrations;
+    use crate::repository::StorageBackend;
+
+    // A fresh directory holding the live database and its backups
+    fn settings() -> (PathBuf, Figment) {
+        let dir = std::env::temp_dir().join(format!("gift-backups-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let figment = rocket::Config::figment()
+            .merge(("backup_dir", dir.join("backups")))
+            .merge(("backup_keep", 2))
+            .merge((
+                "databases.sqlite_logs.url",
+                dir.join("live.sqlite").to_str().unwrap(),
+            ));
+        (dir, figment)
+    }
+
+    // A migrated database with one user in it
+    fn database(path: &PathBuf, user: &str) {
+        let mut db = Connection::open(path).unwrap();
+        Migrations::migrate(&mut db).unwrap();
+        db.execute(
+            format!(
+                "INSERT INTO {} (user_uuid, email, name, can_create, is_admin) \
+                VALUES (?1, ?2, ?3, 0, 0)",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            params![user, format!("{}@example.com", user), user],
+        )
+        .unwrap();
+    }
+
+    fn users(path: &PathBuf) -> Vec<String> {
+        Connection::open(path)
+            .unwrap()
+            .prepare(format!("SELECT name FROM {}", DbConn::USER_TABLE).as_str())
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn only_the_newest_backups_are_kept() {
+        let (dir, figment) = settings();
+        let config: AppConfig = figment.extract().unwrap();
+        let backups = dir.join("backups");
+        fs::create_dir_all(&backups).unwrap();
+        for name in ["gifts-1.sqlite", "gifts-2.sqlite", "gifts-3.sqlite"] {
+            fs::write(backups.join(name), "").unwrap();
+        }
+        // Left behind by a backup that never finished
+        fs::write(backups.join("gifts-4.partial"), "").unwrap();
+
+        let (_rocket, conn) = storage().await;
+        let taken = Backups::take(StorageBackend::Sqlite, &*conn, &config)
+            .await
+            .unwrap();
+        let names: Vec<String> = Backups::list(&config)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "gifts-3.sqlite".to_string(),
+                taken.file_name().unwrap().to_string_lossy().to_string()
+            ]
+        );
+        assert!(backups.join("gifts-4.partial").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restores_save_the_current_database_first() {
+        let (dir, figment) = settings();
+        database(&dir.join("live.sqlite"), "Ada");
+        database(&dir.join("backup.sqlite"), "Grace");
+
+        let (saved, version) = Backups::restore(&figment, &dir.join("backup.sqlite")).unwrap();
+        assert!(version > 0);
+        assert_eq!(users(&dir.join("live.sqlite")), ["Grace"]);
+        assert_eq!(users(&saved), ["Ada"]);
+        // Kept out of the rotation
+        let config: AppConfig = figment.extract().unwrap();
+        assert!(Backups::list(&config).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restores_refuse_newer_schemas_and_running_servers() {
+        let (dir, figment) = settings();
+        database(&dir.join("live.sqlite"), "Ada");
+        database(&dir.join("backup.sqlite"), "Grace");
+
+        let lock = Backups::lock_database(dir.join("live.sqlite").to_str().unwrap()).unwrap();
+        assert!(matches!(
+            Backups::restore(&figment, &dir.join("backup.sqlite")),
+            Err(AppError::Validation(_))
+        ));
+        drop(lock);
+
+        Connection::open(dir.join("backup.sqlite"))
+            .unwrap()
+            .execute(
+                "INSERT INTO SCHEMA_VERSION (version, name, applied) \
+                VALUES (1000, 'from the future', 0)",
+                [],
+            )
+            .unwrap();
+        assert!(matches!(
+            Backups::restore(&figment, &dir.join("backup.sqlite")),
+            Err(AppError::Validation(_))
+        ));
+        assert_eq!(users(&dir.join("live.sqlite")), ["Ada"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
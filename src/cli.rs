@@ -0,0 +1,404 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::{Build, Ignite, Rocket};
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::backup::Backups;
+use crate::data::{AppConfig, User};
+use crate::email::Email;
+use crate::error::AppError;
+use crate::repository::{Repository, Storage, StorageBackend};
+use crate::webserver::WebServer;
+
+pub struct Cli;
+
+impl Cli {
+    const USAGE: &'static str = "Usage: gift_coordination admin <command>
+
+Commands:
+    users                                   List every user
+    lists                                   List every list and its members
+    create-user <name> <email> [--can-create] [--admin]
+    grant <email> [--admin]                 Allow a user to create lists (and administer)
+    revoke <email> [--admin]                Stop a user creating lists (and administering)
+    login-link <email>                      Issue and print a fresh login link
+    delete-user <email>                     Delete a user, passing their lists to a co-owner
+    backup                                  Back up the database now
+    restore <file>                          Replace the database with a backup, best done with
+                                            the server stopped";
+
+    // Just storage and settings, so none of the server's startup jobs run
+    pub fn rocket(figment: Figment) -> Rocket<Build> {
+        Storage::attach(rocket::custom(figment).attach(AdHoc::config::<AppConfig>()))
+    }
+
+    // Runs before the server starts up, since the database being replaced may not start
+    pub fn restore(args: &[String], figment: &Figment) -> Result<(), AppError> {
+        let file = match args {
+            [file] => Path::new(file),
+            _ => return Err(AppError::Validation(Cli::USAGE.to_string())),
+        };
+        if !file.is_file() {
+            return Err(AppError::Validation(format!(
+                "No backup at {}",
+                file.display()
+            )));
+        }
+        let (saved, version) = Backups::restore(figment, file)?;
+        println!("Saved the current database to {}", saved.display());
+        println!("Restored {} (schema version {})", file.display(), version);
+        Ok(())
+    }
+
+    pub async fn run(args: &[String], rocket: &Rocket<Ignite>) -> Result<(), AppError> {
+        let conn = Storage::get_one(rocket).await.expect("storage mounted");
+        let config = rocket.state::<AppConfig>().expect("app config");
+        let backend = *rocket.state::<StorageBackend>().expect("storage backend");
+        let command = args.first().map(String::as_str).unwrap_or("");
+        let flags: Vec<&str> = args
+            .iter()
+            .skip(1)
+            .filter(|a| a.starts_with("--"))
+            .map(String::as_str)
+            .collect();
+        let params: Vec<String> = args
+            .iter()
+            .skip(1)
+            .filter(|a| !a.starts_with("--"))
+            .cloned()
+            .collect();
+
+        match (command, params.as_slice()) {
+            ("users", []) => Cli::users(&*conn).await,
+            ("lists", []) => Cli::lists(&*conn).await,
+            ("create-user", [name, email]) => {
+                Cli::create_user(
+                    name.to_owned(),
+                    email.to_owned(),
+                    flags.contains(&"--can-create"),
+                    flags.contains(&"--admin"),
+                    config,
+                    &*conn,
+                )
+                .await
+            }
+            // Admin access only changes when asked for
+            ("grant", [email]) => {
+                let is_admin = flags.contains(&"--admin").then_some(true);
+                Cli::set_permissions(email.to_owned(), Some(true), is_admin, &*conn).await
+            }
+            ("revoke", [email]) => {
+                let is_admin = flags.contains(&"--admin").then_some(false);
+                Cli::set_permissions(email.to_owned(), Some(false), is_admin, &*conn).await
+            }
+            ("login-link", [email]) => Cli::login_link(email.to_owned(), config, &*conn).await,
+            ("delete-user", [email]) => Cli::delete_user(email.to_owned(), &*conn).await,
+            ("backup", []) => Cli::backup(backend, config, &*conn).await,
+            _ => Err(AppError::Validation(Cli::USAGE.to_string())),
+        }
+    }
+
+    async fn find_user(email: String, conn: &dyn Repository) -> Result<User, AppError> {
+        match conn.user_from_email(email.to_owned()).await {
+            Err(AppError::NotFound) => Err(AppError::Validation(format!(
+                "No user with email {}",
+                email
+            ))),
+            result => result,
+        }
+    }
+
+    async fn users(conn: &dyn Repository) -> Result<(), AppError> {
+        let users = conn.all_users("".to_string()).await?;
+        for user in users {
+            println!(
+                "{} <{}>{}{}",
+                user.name,
+                user.email,
+                if user.can_create { " [can create]" } else { "" },
+                if user.is_admin { " [admin]" } else { "" },
+            );
+        }
+        Ok(())
+    }
+
+    async fn lists(conn: &dyn Repository) -> Result<(), AppError> {
+        let lists = conn.all_lists().await?;
+        for list in lists {
+            println!("{} (owner: {}, {})", list.name, list.owner_name, list.uuid);
+            let members = conn.users_of_list(list.uuid, "".to_string()).await?;
+            for member in members {
+                println!(
+                    "    {} <{}> ({})",
+                    member.user.name,
+                    member.user.email,
+                    member.role.as_str()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_user(
+        name: String,
+        email: String,
+        can_create: bool,
+        is_admin: bool,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        if conn.user_from_email(email.to_owned()).await.is_ok() {
+            return Err(AppError::Validation(format!(
+                "A user with email {} already exists",
+                email
+            )));
+        }
+        let user = User {
+            uuid: Uuid::new_v4().to_string(),
+            email,
+            name,
+            can_create: can_create || is_admin,
+            is_admin,
+            is_me: false,
+        };
+        conn.create_user(user.to_owned()).await?;
+        println!("Created {} <{}>", user.name, user.email);
+        Cli::login_link(user.email, config, conn).await
+    }
+
+    async fn set_permissions(
+        email: String,
+        can_create: Option<bool>,
+        is_admin: Option<bool>,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        let mut user = Cli::find_user(email, conn).await?;
+        if user.is_admin && is_admin == Some(false) && WebServer::is_last_admin(&user, conn).await?
+        {
+            return Err(AppError::Validation(
+                "There has to be at least one administrator".to_string(),
+            ));
+        }
+        user.can_create = can_create.unwrap_or(user.can_create);
+        user.is_admin = is_admin.unwrap_or(user.is_admin);
+        conn.modify_user(user.to_owned()).await?;
+        println!(
+            "{} {} create lists and {} administer Goord",
+            user.name,
+            if user.can_create { "can" } else { "can't" },
+            if user.is_admin { "can" } else { "can't" },
+        );
+        Ok(())
+    }
+
+    async fn login_link(
+        email: String,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        let user = Cli::find_user(email, conn).await?;
+        let login_token = WebServer::issue_login_token(user.uuid, config, conn).await?;
+        println!(
+            "Login link (expires in {} hours): {}",
+            config.login_link_hours,
+            Email::site_login_link(login_token)
+        );
+        Ok(())
+    }
+
+    async fn delete_user(email: String, conn: &dyn Repository) -> Result<(), AppError> {
+        let user = Cli::find_user(email, conn).await?;
+        if user.is_admin && WebServer::is_last_admin(&user, conn).await? {
+            return Err(AppError::Validation(
+                "There has to be at least one administrator".to_string(),
+            ));
+        }
+        conn.delete_user(user.uuid).await?;
+        println!("Deleted {} <{}>", user.name, user.email);
+        Ok(())
+    }
+
+    async fn backup(
+        backend: StorageBackend,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<(), AppError> {
+        let path = Backups::take(backend, conn, config).await?;
+        println!("Backed up the database to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{Ignite, Rocket};
+
+    use super::Cli;
+    use crate::data::User;
+    use crate::error::AppError;
+    use crate::repository::Storage;
+
+    // One pooled connection, so the in-memory database lives as long as the test
+    async fn storage() -> Rocket<Ignite> {
+        let figment = rocket::Config::figment()
+            .merge(("databases.sqlite_logs.url", ":memory:"))
+            .merge(("databases.sqlite_logs.pool_size", 1));
+        Cli::rocket(figment)
+            .ignite()
+            .await
+            .expect("in-memory database set up")
+    }
+
+    async fn run(rocket: &Rocket<Ignite>, args: &[&str]) -> Result<(), AppError> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Cli::run(&args, rocket).await
+    }
+
+    async fn user(rocket: &Rocket<Ignite>, email: &str) -> Option<User> {
+        let conn = Storage::get_one(rocket).await.expect("storage mounted");
+        conn.user_from_email(email.to_string()).await.ok()
+    }
+
+    #[rocket::async_test]
+    async fn unknown_commands_print_usage() {
+        let rocket = storage().await;
+        for args in [
+            vec![],
+            vec!["nope"],
+            vec!["users", "extra"],
+            vec!["grant"],
+            vec!["grant", "ada@example.com", "grace@example.com"],
+            vec!["create-user", "Ada"],
+            vec!["delete-user"],
+        ] {
+            assert!(matches!(
+                run(&rocket, &args).await,
+                Err(AppError::Validation(usage)) if usage == Cli::USAGE
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn create_user_takes_flags_anywhere() {
+        let rocket = storage().await;
+        run(&rocket, &["create-user", "Ada", "ada@example.com"])
+            .await
+            .unwrap();
+        run(
+            &rocket,
+            &["create-user", "--admin", "Grace", "grace@example.com"],
+        )
+        .await
+        .unwrap();
+        run(
+            &rocket,
+            &["create-user", "Linus", "linus@example.com", "--can-create"],
+        )
+        .await
+        .unwrap();
+
+        let ada = user(&rocket, "ada@example.com").await.unwrap();
+        assert_eq!(
+            (ada.name.as_str(), ada.can_create, ada.is_admin),
+            ("Ada", false, false)
+        );
+        let grace = user(&rocket, "grace@example.com").await.unwrap();
+        assert_eq!((grace.can_create, grace.is_admin), (true, true));
+        let linus = user(&rocket, "linus@example.com").await.unwrap();
+        assert_eq!((linus.can_create, linus.is_admin), (true, false));
+
+        assert!(run(&rocket, &["create-user", "Ada", "ada@example.com"])
+            .await
+            .is_err());
+    }
+
+    #[rocket::async_test]
+    async fn grant_and_revoke_leave_admin_alone_without_the_flag() {
+        let rocket = storage().await;
+        run(
+            &rocket,
+            &["create-user", "Ada", "ada@example.com", "--admin"],
+        )
+        .await
+        .unwrap();
+        run(
+            &rocket,
+            &["create-user", "Grace", "grace@example.com", "--admin"],
+        )
+        .await
+        .unwrap();
+        let permissions = |user: Option<User>| user.map(|u| (u.can_create, u.is_admin));
+
+        run(&rocket, &["grant", "ada@example.com"]).await.unwrap();
+        assert_eq!(
+            permissions(user(&rocket, "ada@example.com").await),
+            Some((true, true))
+        );
+        run(&rocket, &["revoke", "ada@example.com"]).await.unwrap();
+        assert_eq!(
+            permissions(user(&rocket, "ada@example.com").await),
+            Some((false, true))
+        );
+        run(&rocket, &["revoke", "ada@example.com", "--admin"])
+            .await
+            .unwrap();
+        assert_eq!(
+            permissions(user(&rocket, "ada@example.com").await),
+            Some((false, false))
+        );
+        run(&rocket, &["grant", "--admin", "ada@example.com"])
+            .await
+            .unwrap();
+        assert_eq!(
+            permissions(user(&rocket, "ada@example.com").await),
+            Some((true, true))
+        );
+
+        assert!(run(&rocket, &["grant", "nobody@example.com"])
+            .await
+            .is_err());
+    }
+
+    #[rocket::async_test]
+    async fn the_last_admin_stays() {
+        let rocket = storage().await;
+        run(
+            &rocket,
+            &["create-user", "Ada", "ada@example.com", "--admin"],
+        )
+        .await
+        .unwrap();
+        assert!(run(&rocket, &["revoke", "--admin", "ada@example.com"])
+            .await
+            .is_err());
+        assert!(run(&rocket, &["delete-user", "ada@example.com"])
+            .await
+            .is_err());
+        assert!(user(&rocket, "ada@example.com").await.unwrap().is_admin);
+    }
+
+    #[rocket::async_test]
+    async fn the_other_commands_run() {
+        let rocket = storage().await;
+        run(&rocket, &["create-user", "Ada", "ada@example.com"])
+            .await
+            .unwrap();
+        run(&rocket, &["users"]).await.unwrap();
+        run(&rocket, &["lists"]).await.unwrap();
+        run(&rocket, &["login-link", "ada@example.com"])
+            .await
+            .unwrap();
+        assert!(run(&rocket, &["login-link", "nobody@example.com"])
+            .await
+            .is_err());
+
+        run(&rocket, &["delete-user", "ada@example.com"])
+            .await
+            .unwrap();
+        assert!(user(&rocket, "ada@example.com").await.is_none());
+        assert!(run(&rocket, &["delete-user", "ada@example.com"])
+            .await
+            .is_err());
+    }
+}
@@ -1,43 +1,297 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+// Config
+#[derive(Clone, Deserialize)]
+pub struct AppConfig {
+    #[serde(default = "AppConfig::default_login_link_hours")]
+    pub login_link_hours: i64,
+    pub setup_admin_name: Option<String>,
+    pub setup_admin_email: Option<String>,
+    #[serde(default = "AppConfig::default_backup_dir")]
+    pub backup_dir: String,
+    #[serde(default = "AppConfig::default_backup_keep")]
+    pub backup_keep: usize,
+    // 0 leaves backups to admins
+    #[serde(default = "AppConfig::default_backup_interval_hours")]
+    pub backup_interval_hours: u64,
+    // How long deleted lists and gifts can be restored for
+    #[serde(default = "AppConfig::default_trash_days")]
+    pub trash_days: i64,
+    // For prices given without one
+    #[serde(default = "AppConfig::default_currency")]
+    pub default_currency: String,
+}
+
+impl AppConfig {
+    fn default_login_link_hours() -> i64 {
+        72
+    }
+
+    fn default_backup_dir() -> String {
+        "./backups".to_string()
+    }
+
+    fn default_backup_keep() -> usize {
+        14
+    }
+
+    fn default_backup_interval_hours() -> u64 {
+        24
+    }
+
+    fn default_trash_days() -> i64 {
+        30
+    }
+
+    fn default_currency() -> String {
+        "USD".to_string()
+    }
+}
 
 // Page Data
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct User {
     pub uuid: String,
     pub email: String,
     pub name: String,
     pub can_create: bool,
+    pub is_admin: bool,
     pub is_me: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, FromFormField, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListRole {
+    #[field(value = "co_owner")]
+    CoOwner,
+    #[field(value = "member")]
+    Member,
+    #[field(value = "giver")]
+    Giver,
+    #[field(value = "recipient")]
+    Recipient,
+}
+
+impl ListRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListRole::CoOwner => "co_owner",
+            ListRole::Member => "member",
+            ListRole::Giver => "giver",
+            ListRole::Recipient => "recipient",
+        }
+    }
+
+    pub fn from_str(role: &str) -> ListRole {
+        match role {
+            "co_owner" => ListRole::CoOwner,
+            "giver" => ListRole::Giver,
+            "recipient" => ListRole::Recipient,
+            _ => ListRole::Member,
+        }
+    }
+
+    // Co-owners edit membership and list settings
+    pub fn can_manage(&self) -> bool {
+        *self == ListRole::CoOwner
+    }
+
+    // Givers only buy for others, so they have no gift ideas page
+    pub fn has_wishlist(&self) -> bool {
+        *self != ListRole::Giver
+    }
+
+    // Recipients only receive, so they don't claim gifts
+    pub fn can_give(&self) -> bool {
+        *self != ListRole::Recipient
+    }
+}
+
+#[derive(Clone, Serialize)]
 pub struct List {
     pub uuid: String,
     pub name: String,
     pub owner: String,
-    pub im_owner: bool,
+    pub my_role: Option<ListRole>,
+    pub can_manage: bool,
 }
 
 #[derive(Serialize)]
-pub struct Gift {
+pub struct ListMember {
+    #[serde(flatten)]
+    pub user: User,
+    pub role: ListRole,
+    pub has_wishlist: bool,
+}
+
+#[derive(Serialize)]
+pub struct AdminList {
     pub uuid: String,
-    pub owner: String,
+    pub name: String,
+    pub owner_name: String,
+    pub member_count: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, FromFormField, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GiftPriority {
+    #[field(value = "low")]
+    Low,
+    #[field(value = "medium")]
+    Medium,
+    #[field(value = "high")]
+    High,
+}
+
+impl GiftPriority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GiftPriority::Low => "low",
+            GiftPriority::Medium => "medium",
+            GiftPriority::High => "high",
+        }
+    }
+
+    pub fn from_str(priority: &str) -> GiftPriority {
+        match priority {
+            "low" => GiftPriority::Low,
+            "high" => GiftPriority::High,
+            _ => GiftPriority::Medium,
+        }
+    }
+}
+
+// Everything the recipient says about a gift, as opposed to who's buying it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GiftDetails {
+    pub title: String,
     pub url: String,
     pub comment: String,
-    pub claimed: bool,
-    pub claimed_by: Option<User>,
+    // In hundredths of the currency, so 12.50 is 1250
+    pub price: Option<i64>,
+    pub currency: String,
+    pub quantity: i64,
+    pub priority: GiftPriority,
+    // Size, colour and the like
+    pub notes: String,
+}
+
+// One giver's share of a gift
+#[derive(Clone, Serialize)]
+pub struct GiftClaim {
+    pub user_uuid: String,
+    pub name: String,
+    pub is_me: bool,
+    pub quantity: i64,
+}
+
+// Givers chipping in together for one gift, kept from its recipient
+#[derive(Clone, Serialize)]
+pub struct GroupGift {
+    pub gift_uuid: String,
+    pub organizer_uuid: String,
+    pub organizer_name: String,
+    pub organized_by_me: bool,
+    pub purchased: bool,
+    // Oldest first
+    pub pledges: Vec<Pledge>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Pledge {
+    pub user_uuid: String,
+    pub name: String,
+    pub is_me: bool,
+    // In hundredths of the gift's currency, like its price
+    pub amount: i64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Gift {
+    pub uuid: String,
+    pub owner: String,
+    #[serde(flatten)]
+    pub details: GiftDetails,
+    // Oldest first
+    pub claims: Vec<GiftClaim>,
     pub alternate_to_uuid: Option<String>,
+    pub version: i64,
+}
+
+impl Gift {
+    // How many of the wanted quantity nobody has claimed yet
+    pub fn remaining(&self) -> i64 {
+        let claimed: i64 = self.claims.iter().map(|c| c.quantity).sum();
+        (self.details.quantity - claimed).max(0)
+    }
+
+    // How many the user can have in all, counting what they've claimed already
+    pub fn left_for(&self, user_uuid: &str) -> i64 {
+        let others: i64 = self
+            .claims
+            .iter()
+            .filter(|c| c.user_uuid != user_uuid)
+            .map(|c| c.quantity)
+            .sum();
+        (self.details.quantity - others).max(0)
+    }
+
+    pub fn claim_of(&self, user_uuid: &str) -> Option<&GiftClaim> {
+        self.claims.iter().find(|c| c.user_uuid == user_uuid)
+    }
 }
 
 #[derive(Serialize)]
 pub struct ExportGift {
     pub uuid: String,
-    pub url: String,
-    pub comment: String,
+    #[serde(flatten)]
+    pub details: GiftDetails,
+    // Nothing left to claim
     pub claimed: bool,
-    pub claimed_by_name: String,
-    pub claimed_by_me: bool,
+    pub remaining: i64,
+    pub claims: Vec<GiftClaim>,
+    // How many the current user has claimed, 0 for none
+    pub my_claim: i64,
+    // Never filled in for the recipient
+    pub group: Option<GroupGift>,
     pub alternate_to_uuid: Option<String>,
+    pub version: i64,
+    // What the gift was when the current user claimed it, if it's been changed since
+    pub claimed_as: Option<GiftRevision>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct GiftRevision {
+    pub gift_uuid: String,
+    pub version: i64,
+    #[serde(flatten)]
+    pub details: GiftDetails,
+    pub author_name: Option<String>,
+    pub created: i64,
+}
+
+#[derive(Serialize)]
+pub struct DeletedList {
+    pub uuid: String,
+    pub name: String,
+    pub deleted: i64,
+}
+
+#[derive(Serialize)]
+pub struct DeletedGift {
+    pub uuid: String,
+    pub list_uuid: String,
+    pub list_name: String,
+    pub title: String,
+    pub url: String,
+    pub comment: String,
+    pub deleted: i64,
+}
+
+#[derive(Serialize)]
+pub struct SavedGift {
+    pub uuid: String,
+    pub version: i64,
 }
 
 // Email
@@ -46,6 +300,15 @@ pub struct InviteEmail {
     pub user_name: String,
     pub list_name: String,
     pub link: String,
+    pub link_hours: i64,
+    pub admin_email: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginEmail {
+    pub user_name: String,
+    pub link: String,
+    pub link_hours: i64,
     pub admin_email: String,
 }
 
@@ -54,13 +317,14 @@ pub struct InviteEmail {
 pub struct UserPage {
     pub current_user: User,
     pub lists: Vec<List>,
+    pub trash_days: i64,
 }
 
 #[derive(Serialize)]
 pub struct ListPage {
     pub list: List,
     pub current_user: User,
-    pub users: Vec<User>,
+    pub users: Vec<ListMember>,
 }
 
 #[derive(Serialize)]
@@ -69,6 +333,17 @@ pub struct ListUserPage {
     pub current_user: User,
     pub list: List,
     pub gifts_data: String,
+    pub version: i64,
+    pub default_currency: String,
+}
+
+#[derive(Serialize)]
+pub struct GiftHistoryPage {
+    pub user: User,
+    pub current_user: User,
+    pub list: List,
+    pub gift: Gift,
+    pub revisions: Vec<GiftRevision>,
 }
 
 #[derive(Serialize)]
@@ -78,11 +353,41 @@ pub struct ModifyListPage {
     pub users: Vec<ListUser>,
 }
 
+#[derive(Serialize)]
+pub struct AdminPage {
+    pub current_user: User,
+    pub users: Vec<User>,
+    pub lists: Vec<AdminList>,
+    pub can_back_up: bool,
+    pub backups: Vec<BackupFile>,
+}
+
+#[derive(Serialize)]
+pub struct TrashPage {
+    pub current_user: User,
+    pub lists: Vec<DeletedList>,
+    pub gifts: Vec<DeletedGift>,
+    pub trash_days: i64,
+}
+
+#[derive(Serialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub taken: i64,
+    pub size_kb: u64,
+}
+
 // Page Input
 #[derive(Clone, FromForm, Serialize)]
 pub struct ListUser {
     pub name: String,
     pub email: String,
+    pub role: ListRole,
+}
+
+#[derive(FromForm)]
+pub struct RequestLogin {
+    pub email: String,
 }
 
 #[derive(FromForm)]
@@ -103,27 +408,128 @@ pub struct DeleteList {
     pub uuid: String,
 }
 
+#[derive(FromForm)]
+pub struct RestoreList {
+    pub uuid: String,
+}
+
+#[derive(FromForm)]
+pub struct RestoreGift {
+    pub gift_uuid: String,
+}
+
 #[derive(FromForm)]
 pub struct ClaimGift {
     pub gift_uuid: String,
+    // Replaces whatever the user had claimed of it before
+    pub quantity: i64,
+}
+
+#[derive(FromForm)]
+pub struct UnclaimGift {
+    pub gift_uuid: String,
+}
+
+#[derive(FromForm)]
+pub struct StartGroupGift {
+    pub gift_uuid: String,
+}
+
+#[derive(FromForm)]
+pub struct CancelGroupGift {
+    pub gift_uuid: String,
 }
 
+#[derive(FromForm)]
+pub struct PledgeGift {
+    pub gift_uuid: String,
+    // As typed, like a price; empty or 0 takes the pledge back
+    pub amount: String,
+}
+
+#[derive(FromForm)]
+pub struct MarkPurchased {
+    pub gift_uuid: String,
+    pub purchased: bool,
+}
+
+// Checked and turned into GiftDetails by the web server
 #[derive(Clone, FromForm, Serialize)]
-pub struct FormGift {
-    pub uuid: String,
+pub struct FormGiftDetails {
+    pub title: String,
     pub url: String,
     pub comment: String,
+    // As typed, like 12.50, or empty for no price
+    pub price: String,
+    pub currency: String,
+    pub quantity: i64,
+    pub priority: GiftPriority,
+    pub notes: String,
+}
+
+#[derive(Clone, FromForm, Serialize)]
+pub struct FormGift {
+    pub uuid: String,
+    pub details: FormGiftDetails,
     pub alternate_to_uuid: String,
 }
 
 #[derive(FromForm)]
-pub struct ModifyGiftList {
+pub struct ImportGiftList {
     pub list_uuid: String,
+    pub version: i64,
     pub gifts: Vec<FormGift>,
 }
 
-// Page Auth
 #[derive(FromForm)]
-pub struct Auth {
-    pub user_token: String,
+pub struct AddGift {
+    pub list_uuid: String,
+    pub details: FormGiftDetails,
+    pub alternate_to_uuid: String,
+}
+
+#[derive(FromForm)]
+pub struct UpdateGift {
+    pub gift_uuid: String,
+    pub version: i64,
+    pub details: FormGiftDetails,
 }
+
+#[derive(FromForm)]
+pub struct DeleteGift {
+    pub gift_uuid: String,
+    pub version: i64,
+}
+
+#[derive(FromForm)]
+pub struct RevertGift {
+    pub gift_uuid: String,
+    pub version: i64,
+    pub revision: i64,
+}
+
+#[derive(FromForm)]
+pub struct ReorderGifts {
+    pub list_uuid: String,
+    pub version: i64,
+    pub gifts: Vec<String>,
+}
+
+#[derive(FromForm)]
+pub struct AdminModifyUser {
+    pub uuid: String,
+    pub name: String,
+    pub email: String,
+    pub can_create: bool,
+    pub is_admin: bool,
+}
+
+#[derive(FromForm)]
+pub struct AdminUserAction {
+    pub uuid: String,
+}
+
+// Page Auth
+pub struct CurrentUser(pub User);
+
+pub struct AdminUser(pub User);
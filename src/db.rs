@@ -1,167 +1,648 @@
-use rocket::{Build, Rocket};
 use rocket_sync_db_pools::{database, rusqlite};
-use rusqlite::{params, Error};
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
-use crate::data::{Gift, List, User};
+use crate::backup::Backups;
+use crate::data::{
+    AdminList, DeletedGift, DeletedList, Gift, GiftClaim, GiftDetails, GiftPriority, GiftRevision,
+    GroupGift, List, ListMember, ListRole, Pledge, User,
+};
+use crate::error::AppError;
+use crate::repository::Repository;
 
 #[database("sqlite_logs")]
 pub struct DbConn(rusqlite::Connection);
 
 impl DbConn {
-    const USER_TABLE: &'static str = "USERS";
-    const LIST_TO_USER_TABLE: &'static str = "LIST_USERS";
-    const LIST_TABLE: &'static str = "LISTS";
-    const LIST_TO_GIFT_TABLE: &'static str = "LIST_ITEMS";
-    const GIFT_TABLE: &'static str = "ITEMS";
-
-    pub async fn user_uuid_from_auth_token(
-        self: &DbConn,
-        auth_token: String,
-    ) -> Result<String, Error> {
+    pub const USER_TABLE: &'static str = "USERS";
+    pub const LIST_TO_USER_TABLE: &'static str = "LIST_USERS";
+    pub const LIST_TABLE: &'static str = "LISTS";
+    pub const LIST_TO_GIFT_TABLE: &'static str = "LIST_ITEMS";
+    pub const GIFT_TABLE: &'static str = "ITEMS";
+    pub const GIFT_REVISION_TABLE: &'static str = "ITEM_REVISIONS";
+    pub const CLAIM_TABLE: &'static str = "ITEM_CLAIMS";
+    pub const GROUP_GIFT_TABLE: &'static str = "GROUP_GIFTS";
+    pub const PLEDGE_TABLE: &'static str = "GROUP_PLEDGES";
+    pub const LOGIN_TOKEN_TABLE: &'static str = "LOGIN_TOKENS";
+    pub const SESSION_TABLE: &'static str = "SESSIONS";
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    // SQLite starts every connection with foreign keys off and the pool has no hook to turn them on
+    async fn run_with_fks<T, E, F>(self: &DbConn, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+    {
         self.run(move |db| {
+            db.execute_batch("PRAGMA foreign_keys = ON")?;
+            f(db)
+        })
+        .await
+    }
+
+    // The columns details_from_row reads, in its order
+    pub fn detail_columns(alias: &str) -> String {
+        [
+            "title", "url", "comment", "price", "currency", "quantity", "priority", "notes",
+        ]
+        .iter()
+        .map(|column| format!("{}.{}", alias, column))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+
+    fn details_from_row(row: &rusqlite::Row, first: usize) -> Result<GiftDetails, rusqlite::Error> {
+        Ok(GiftDetails {
+            title: row.get(first)?,
+            url: row.get(first + 1)?,
+            comment: row.get(first + 2)?,
+            price: row.get(first + 3)?,
+            currency: row.get(first + 4)?,
+            quantity: row.get(first + 5)?,
+            priority: GiftPriority::from_str(&row.get::<usize, String>(first + 6)?),
+            notes: row.get(first + 7)?,
+        })
+    }
+
+    fn bump_wishlist_version(
+        db: &rusqlite::Connection,
+        list_uuid: &str,
+        user_uuid: &str,
+    ) -> Result<i64, rusqlite::Error> {
+        db.query_row(
+            format!(
+                "UPDATE {} SET version = version + 1 \
+            WHERE list_uuid = ?1 AND user_uuid = ?2 RETURNING version",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            params![list_uuid, user_uuid],
+            |row| row.get(0),
+        )
+    }
+
+    fn insert_gift_row(
+        db: &rusqlite::Connection,
+        gift: &Gift,
+        list_uuid: &str,
+        version: i64,
+    ) -> Result<(), rusqlite::Error> {
+        let details = &gift.details;
+        db.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, title, url, comment, price, currency, \
+            quantity, priority, notes, alternate_to, version, position) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, \
+            (SELECT COALESCE(MAX(i.position), 0) + 1 FROM {} i \
+            INNER JOIN {} l ON l.gift_uuid = i.gift_uuid \
+            WHERE l.list_uuid = ?13 AND l.user_uuid = ?2))",
+                DbConn::GIFT_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            params![
+                gift.uuid,
+                gift.owner,
+                details.title,
+                details.url,
+                details.comment,
+                details.price,
+                details.currency,
+                details.quantity,
+                details.priority.as_str(),
+                details.notes,
+                gift.alternate_to_uuid,
+                version,
+                list_uuid
+            ],
+        )?;
+        db.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, list_uuid) \
+            VALUES (?1, ?2, ?3)",
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            params![gift.uuid, gift.owner, list_uuid],
+        )?;
+        DbConn::record_revision(db, gift, version)
+    }
+
+    // Claims are left alone so an owner's save can't undo one made in the meantime
+    fn update_gift_row(
+        db: &rusqlite::Connection,
+        gift: &Gift,
+        version: i64,
+    ) -> Result<(), rusqlite::Error> {
+        let details = &gift.details;
+        db.execute(
+            format!(
+                "UPDATE {} SET title = ?1, url = ?2, comment = ?3, price = ?4, currency = ?5, \
+            quantity = ?6, priority = ?7, notes = ?8, version = ?9 WHERE gift_uuid = ?10",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            params![
+                details.title,
+                details.url,
+                details.comment,
+                details.price,
+                details.currency,
+                details.quantity,
+                details.priority.as_str(),
+                details.notes,
+                version,
+                gift.uuid
+            ],
+        )?;
+        DbConn::record_revision(db, gift, version)
+    }
+
+    // Saves that leave the details as they were, like an import, add nothing
+    fn record_revision(
+        db: &rusqlite::Connection,
+        gift: &Gift,
+        version: i64,
+    ) -> Result<(), rusqlite::Error> {
+        let latest = db
+            .query_row(
+                format!(
+                    "SELECT {} FROM {} AS r WHERE r.gift_uuid = ?1 \
+                ORDER BY r.version DESC LIMIT 1",
+                    DbConn::detail_columns("r"),
+                    DbConn::GIFT_REVISION_TABLE
+                )
+                .as_str(),
+                params![gift.uuid],
+                |row| DbConn::details_from_row(row, 0),
+            )
+            .optional()?;
+        if latest.as_ref() == Some(&gift.details) {
+            return Ok(());
+        }
+        let details = &gift.details;
+        db.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, version, title, url, comment, price, currency, \
+            quantity, priority, notes, author, created) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                DbConn::GIFT_REVISION_TABLE
+            )
+            .as_str(),
+            params![
+                gift.uuid,
+                version,
+                details.title,
+                details.url,
+                details.comment,
+                details.price,
+                details.currency,
+                details.quantity,
+                details.priority.as_str(),
+                details.notes,
+                gift.owner,
+                DbConn::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Claims on the gifts the condition on c picks out, oldest first, keyed by gift
+    fn claims_where<P: rusqlite::Params>(
+        db: &rusqlite::Connection,
+        condition: &str,
+        params: P,
+        current_user_uuid: &str,
+    ) -> Result<HashMap<String, Vec<GiftClaim>>, rusqlite::Error> {
+        let mut statement = db.prepare(
+            format!(
+                "SELECT c.gift_uuid, c.user_uuid, u.name, c.quantity FROM {} AS c \
+            INNER JOIN {} AS u ON u.user_uuid = c.user_uuid \
+            WHERE {} ORDER BY c.created, c.rowid",
+                DbConn::CLAIM_TABLE,
+                DbConn::USER_TABLE,
+                condition
+            )
+            .as_str(),
+        )?;
+        let mut rows = statement.query(params)?;
+        let mut claims: HashMap<String, Vec<GiftClaim>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let user_uuid: String = row.get(1)?;
+            claims.entry(row.get(0)?).or_default().push(GiftClaim {
+                is_me: user_uuid == current_user_uuid,
+                user_uuid,
+                name: row.get(2)?,
+                quantity: row.get(3)?,
+            });
+        }
+        Ok(claims)
+    }
+
+    // Group gifts whose uuid is among those the query picks out, with their pledges oldest first
+    fn group_gifts_where(
+        db: &rusqlite::Connection,
+        gift_uuids: &str,
+        params: &[&dyn rusqlite::ToSql],
+        current_user_uuid: &str,
+    ) -> Result<Vec<GroupGift>, rusqlite::Error> {
+        let mut groups = db
+            .prepare(
+                format!(
+                    "SELECT g.gift_uuid, g.organizer, u.name, g.purchased FROM {} AS g \
+                INNER JOIN {} AS u ON u.user_uuid = g.organizer \
+                WHERE g.gift_uuid IN ({}) ORDER BY g.created, g.rowid",
+                    DbConn::GROUP_GIFT_TABLE,
+                    DbConn::USER_TABLE,
+                    gift_uuids
+                )
+                .as_str(),
+            )?
+            .query_map(params, |row| {
+                let organizer_uuid: String = row.get(1)?;
+                Ok(GroupGift {
+                    gift_uuid: row.get(0)?,
+                    organized_by_me: organizer_uuid == current_user_uuid,
+                    organizer_uuid,
+                    organizer_name: row.get(2)?,
+                    purchased: row.get::<usize, Option<i64>>(3)?.is_some(),
+                    pledges: Vec::new(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut statement = db.prepare(
+            format!(
+                "SELECT p.gift_uuid, p.user_uuid, u.name, p.amount FROM {} AS p \
+            INNER JOIN {} AS u ON u.user_uuid = p.user_uuid \
+            WHERE p.gift_uuid IN ({}) ORDER BY p.created, p.rowid",
+                DbConn::PLEDGE_TABLE,
+                DbConn::USER_TABLE,
+                gift_uuids
+            )
+            .as_str(),
+        )?;
+        let mut rows = statement.query(params)?;
+        while let Some(row) = rows.next()? {
+            let gift_uuid: String = row.get(0)?;
+            let user_uuid: String = row.get(1)?;
+            if let Some(group) = groups.iter_mut().find(|g| g.gift_uuid == gift_uuid) {
+                group.pledges.push(Pledge {
+                    is_me: user_uuid == current_user_uuid,
+                    user_uuid,
+                    name: row.get(2)?,
+                    amount: row.get(3)?,
+                });
+            }
+        }
+        Ok(groups)
+    }
+
+    // Expects the gift uuid and version, the details, then the author's name and the time
+    fn revision_from_row(row: &rusqlite::Row) -> Result<GiftRevision, rusqlite::Error> {
+        Ok(GiftRevision {
+            gift_uuid: row.get(0)?,
+            version: row.get(1)?,
+            details: DbConn::details_from_row(row, 2)?,
+            author_name: row.get(10)?,
+            created: row.get(11)?,
+        })
+    }
+
+    // Alternates go in the trash with their gift, stamped the same so they come back with it
+    fn trash_gift_row(
+        db: &rusqlite::Connection,
+        gift_uuid: &str,
+        deleted: i64,
+    ) -> Result<(), rusqlite::Error> {
+        db.execute(
+            format!(
+                "UPDATE {} SET deleted = ?2 \
+            WHERE (gift_uuid = ?1 OR alternate_to = ?1) AND deleted IS NULL",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            params![gift_uuid, deleted],
+        )?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for DbConn {
+    async fn consume_login_token(&self, login_token: String) -> Result<String, AppError> {
+        self.run_with_fks(move |db| {
             db.query_row(
                 format!(
-                    "SELECT user_uuid FROM {} WHERE auth_token = ?1",
-                    DbConn::USER_TABLE
+                    "DELETE FROM {} WHERE token = ?1 AND expires > ?2 RETURNING user_uuid",
+                    DbConn::LOGIN_TOKEN_TABLE
                 )
                 .as_str(),
-                params![auth_token],
+                params![login_token, DbConn::now()],
                 |row| row.get(0),
             )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn auth_token_from_user(self: &DbConn, user_uuid: String) -> Result<String, Error> {
-        self.run(move |db| {
+    async fn create_login_token(
+        &self,
+        user_uuid: String,
+        login_token: String,
+        lifetime_secs: i64,
+    ) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            // Issuing a new link invalidates any previous ones for this user
+            db.execute(
+                format!(
+                    "DELETE FROM {} WHERE user_uuid = ?1 OR expires <= ?2",
+                    DbConn::LOGIN_TOKEN_TABLE
+                )
+                .as_str(),
+                params![user_uuid, DbConn::now()],
+            )?;
+            db.execute(
+                format!(
+                    "INSERT INTO {} (token, user_uuid, expires) \
+                VALUES (?1, ?2, ?3)",
+                    DbConn::LOGIN_TOKEN_TABLE
+                )
+                .as_str(),
+                params![login_token, user_uuid, DbConn::now() + lifetime_secs],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_session(
+        &self,
+        user_uuid: String,
+        session_token: String,
+    ) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            db.execute(
+                format!(
+                    "INSERT INTO {} (session_token, user_uuid, created) \
+                VALUES (?1, ?2, ?3)",
+                    DbConn::SESSION_TABLE
+                )
+                .as_str(),
+                params![session_token, user_uuid, DbConn::now()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn user_uuid_from_session(&self, session_token: String) -> Result<String, AppError> {
+        self.run_with_fks(move |db| {
             db.query_row(
                 format!(
-                    "SELECT auth_token FROM {} WHERE user_uuid = ?1",
-                    DbConn::USER_TABLE
+                    "SELECT user_uuid FROM {} WHERE session_token = ?1",
+                    DbConn::SESSION_TABLE
+                )
+                .as_str(),
+                params![session_token],
+                |row| row.get(0),
+            )
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn delete_session(&self, session_token: String) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            db.execute(
+                format!(
+                    "DELETE FROM {} WHERE session_token = ?1",
+                    DbConn::SESSION_TABLE
                 )
                 .as_str(),
+                params![session_token],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sessions_of_user(&self, user_uuid: String) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            db.execute(
+                format!("DELETE FROM {} WHERE user_uuid = ?1", DbConn::SESSION_TABLE).as_str(),
                 params![user_uuid],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn user_count(&self) -> Result<i64, AppError> {
+        self.run_with_fks(move |db| {
+            db.query_row(
+                format!("SELECT COUNT(*) FROM {}", DbConn::USER_TABLE).as_str(),
+                [],
                 |row| row.get(0),
             )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn user_from_email(self: &DbConn, email: String) -> Result<User, Error> {
-        self.run(move |db| {
+    async fn all_users(&self, current_user_uuid: String) -> Result<Vec<User>, AppError> {
+        self.run_with_fks(move |db| {
+            db.prepare(
+                format!(
+                    "SELECT user_uuid, email, name, can_create, is_admin FROM {} ORDER BY name",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+            )?
+            .query_map([], |row| {
+                let user_uuid: String = row.get(0)?;
+                Ok(User {
+                    uuid: user_uuid.to_owned(),
+                    email: row.get(1)?,
+                    name: row.get(2)?,
+                    can_create: row.get(3)?,
+                    is_admin: row.get(4)?,
+                    is_me: current_user_uuid == user_uuid,
+                })
+            })?
+            .collect::<Result<Vec<User>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn user_from_email(&self, email: String) -> Result<User, AppError> {
+        self.run_with_fks(move |db| {
             db.query_row(
                 format!(
-                    "SELECT user_uuid, name, email, can_create FROM {} WHERE email = ?1",
+                    "SELECT user_uuid, name, email, can_create, is_admin FROM {} WHERE email = ?1",
                     DbConn::USER_TABLE
                 )
                 .as_str(),
                 params![email],
                 |row| {
                     Ok(User {
-                        uuid: row.get(0).unwrap(),
-                        name: row.get(1).unwrap(),
-                        email: row.get(2).unwrap(),
-                        can_create: row.get(3).unwrap(),
+                        uuid: row.get(0)?,
+                        name: row.get(1)?,
+                        email: row.get(2)?,
+                        can_create: row.get(3)?,
+                        is_admin: row.get(4)?,
                         is_me: false,
                     })
                 },
             )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn user_from_uuid(
-        self: &DbConn,
+    async fn user_from_uuid(
+        &self,
         user_uuid: String,
         current_uuid: String,
-    ) -> Result<User, Error> {
-        self.run(move |db| {
+    ) -> Result<User, AppError> {
+        self.run_with_fks(move |db| {
             db.query_row(
                 format!(
-                    "SELECT user_uuid, name, email, can_create FROM {} WHERE user_uuid = ?1",
+                    "SELECT user_uuid, name, email, can_create, is_admin FROM {} WHERE user_uuid = ?1",
                     DbConn::USER_TABLE
                 )
                 .as_str(),
                 params![user_uuid],
                 |row| {
                     Ok(User {
-                        uuid: row.get(0).unwrap(),
-                        name: row.get(1).unwrap(),
-                        email: row.get(2).unwrap(),
-                        can_create: row.get(3).unwrap(),
+                        uuid: row.get(0)?,
+                        name: row.get(1)?,
+                        email: row.get(2)?,
+                        can_create: row.get(3)?,
+                        is_admin: row.get(4)?,
                         is_me: current_uuid.eq(&user_uuid),
                     })
                 },
             )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn lists_of_user(self: &DbConn, user_uuid: String) -> Result<Vec<List>, Error> {
-        self.run(move |db| {
+    async fn lists_of_user(&self, user_uuid: String) -> Result<Vec<List>, AppError> {
+        self.run_with_fks(move |db| {
             db.prepare(
                 format!(
-                    "SELECT r.list_uuid, r.name, r.owner \
+                    "SELECT r.list_uuid, r.name, r.owner, l.role \
                 FROM {} l \
                 INNER JOIN {} r ON r.list_uuid = l.list_uuid \
-                WHERE l.user_uuid = ?1",
+                WHERE l.user_uuid = ?1 AND r.deleted IS NULL",
                     DbConn::LIST_TO_USER_TABLE,
                     DbConn::LIST_TABLE,
                 )
                 .as_str(),
             )?
             .query_map(params![user_uuid], |row| {
-                let owner: String = row.get(2).unwrap();
+                let role = ListRole::from_str(row.get::<usize, String>(3)?.as_str());
                 Ok(List {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    im_owner: user_uuid == owner.to_owned(),
-                    owner,
+                    uuid: row.get(0)?,
+                    name: row.get(1)?,
+                    owner: row.get(2)?,
+                    my_role: Some(role),
+                    can_manage: role.can_manage(),
                 })
-            })
-            .unwrap()
+            })?
             .collect::<Result<Vec<List>, _>>()
         })
         .await
+        .map_err(AppError::from)
+    }
+
+    async fn all_lists(&self) -> Result<Vec<AdminList>, AppError> {
+        self.run_with_fks(move |db| {
+            db.prepare(
+                format!(
+                    "SELECT l.list_uuid, l.name, u.name, \
+                (SELECT COUNT(*) FROM {} m WHERE m.list_uuid = l.list_uuid) \
+                FROM {} l \
+                LEFT JOIN {} u ON u.user_uuid = l.owner \
+                WHERE l.deleted IS NULL \
+                ORDER BY l.name",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::LIST_TABLE,
+                    DbConn::USER_TABLE,
+                )
+                .as_str(),
+            )?
+            .query_map([], |row| {
+                Ok(AdminList {
+                    uuid: row.get(0)?,
+                    name: row.get(1)?,
+                    owner_name: row.get(2).unwrap_or_default(),
+                    member_count: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<AdminList>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
     }
 
-    pub async fn list_from_uuid(
-        self: &DbConn,
+    async fn list_from_uuid(
+        &self,
         list_uuid: String,
         current_user_uuid: String,
-    ) -> Result<List, Error> {
-        self.run(move |db| {
+    ) -> Result<List, AppError> {
+        self.run_with_fks(move |db| {
             db.query_row(
                 format!(
-                    "SELECT list_uuid, name, owner FROM {} WHERE list_uuid = ?1",
-                    DbConn::LIST_TABLE
+                    "SELECT l.list_uuid, l.name, l.owner, m.role \
+                FROM {} l \
+                LEFT JOIN {} m ON m.list_uuid = l.list_uuid AND m.user_uuid = ?2 \
+                WHERE l.list_uuid = ?1 AND l.deleted IS NULL",
+                    DbConn::LIST_TABLE,
+                    DbConn::LIST_TO_USER_TABLE,
                 )
                 .as_str(),
-                params![list_uuid],
+                params![list_uuid, current_user_uuid],
                 |row| {
-                    let owner: String = row.get(2).unwrap();
+                    let role = row
+                        .get::<usize, Option<String>>(3)?
+                        .map(|r| ListRole::from_str(r.as_str()));
                     Ok(List {
-                        uuid: row.get(0).unwrap(),
-                        name: row.get(1).unwrap(),
-                        im_owner: current_user_uuid == owner.to_owned(),
-                        owner,
+                        uuid: row.get(0)?,
+                        name: row.get(1)?,
+                        owner: row.get(2)?,
+                        my_role: role,
+                        can_manage: role.is_some_and(|r| r.can_manage()),
                     })
                 },
             )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn users_of_list(
-        self: &DbConn,
+    async fn users_of_list(
+        &self,
         list_uuid: String,
         current_user_uuid: String,
-    ) -> Result<Vec<User>, Error> {
-        self.run(move |db| {
+    ) -> Result<Vec<ListMember>, AppError> {
+        self.run_with_fks(move |db| {
             db.prepare(
                 format!(
-                    "SELECT r.user_uuid, r.email, r.name, r.can_create \
+                    "SELECT r.user_uuid, r.email, r.name, r.can_create, r.is_admin, l.role \
                 FROM {} l \
                 INNER JOIN {} r ON r.user_uuid = l.user_uuid \
                 WHERE l.list_uuid = ?1",
@@ -171,526 +652,1040 @@ impl DbConn {
                 .as_str(),
             )?
             .query_map(params![list_uuid], |row| {
-                let user_uuid: String = row.get(0).unwrap();
-                Ok(User {
-                    uuid: user_uuid.to_owned(),
-                    email: row.get(1).unwrap(),
-                    name: row.get(2).unwrap(),
-                    can_create: row.get(3).unwrap(),
-                    is_me: current_user_uuid == user_uuid,
+                let user_uuid: String = row.get(0)?;
+                let role = ListRole::from_str(row.get::<usize, String>(5)?.as_str());
+                Ok(ListMember {
+                    user: User {
+                        uuid: user_uuid.to_owned(),
+                        email: row.get(1)?,
+                        name: row.get(2)?,
+                        can_create: row.get(3)?,
+                        is_admin: row.get(4)?,
+                        is_me: current_user_uuid == user_uuid,
+                    },
+                    role,
+                    has_wishlist: role.has_wishlist(),
                 })
-            })
-            .unwrap()
-            .collect::<Result<Vec<User>, _>>()
+            })?
+            .collect::<Result<Vec<ListMember>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn role_of_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<ListRole, AppError> {
+        self.run_with_fks(move |db| {
+            db.query_row(
+                format!(
+                    "SELECT m.role FROM {} m \
+                INNER JOIN {} l ON l.list_uuid = m.list_uuid \
+                WHERE m.list_uuid = ?1 AND m.user_uuid = ?2 AND l.deleted IS NULL",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                params![list_uuid, user_uuid],
+                |row| Ok(ListRole::from_str(row.get::<usize, String>(0)?.as_str())),
+            )
         })
         .await
+        .map_err(AppError::from)
     }
 
-    pub async fn gifts_of_list_user(
-        self: &DbConn,
+    async fn wishlist_version(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<i64, AppError> {
+        self.run_with_fks(move |db| {
+            db.query_row(
+                format!(
+                    "SELECT version FROM {} WHERE list_uuid = ?1 AND user_uuid = ?2",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                params![list_uuid, user_uuid],
+                |row| row.get(0),
+            )
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn list_uuid_of_gift(&self, gift_uuid: String) -> Result<String, AppError> {
+        self.run_with_fks(move |db| {
+            db.query_row(
+                format!(
+                    "SELECT list_uuid FROM {} WHERE gift_uuid = ?1",
+                    DbConn::LIST_TO_GIFT_TABLE
+                )
+                .as_str(),
+                params![gift_uuid],
+                |row| row.get(0),
+            )
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn gifts_of_list_user(
+        &self,
         list_uuid: String,
         user_uuid: String,
         current_user_uuid: String,
-    ) -> Result<Vec<Gift>, Error> {
-        self.run(move |db| {
-            db.prepare(format!(
-                "SELECT r.gift_uuid, r.user_uuid, r.url, r.comment, r.claimed, r.alternate_to, z.user_uuid, z.name \
+    ) -> Result<Vec<Gift>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let mut gifts = db.prepare(format!(
+                "SELECT r.gift_uuid, r.user_uuid, r.alternate_to, r.version, {} \
                 FROM {} l \
                 INNER JOIN {} r ON r.gift_uuid = l.gift_uuid \
-                LEFT JOIN {} z ON z.user_uuid = r.claimed_by \
-                WHERE l.list_uuid = ?1 AND l.user_uuid = ?2",
+                WHERE l.list_uuid = ?1 AND l.user_uuid = ?2 AND r.deleted IS NULL \
+                ORDER BY r.alternate_to IS NOT NULL, r.position",
+                DbConn::detail_columns("r"),
                 DbConn::LIST_TO_GIFT_TABLE,
                 DbConn::GIFT_TABLE,
-                DbConn::USER_TABLE,
             ).as_str())?
                 .query_map(params![list_uuid, user_uuid],
                            |row| {
-                               let user = match row.get::<usize, String>(6) {
-                                   Ok(uuid) => Some(User {
-                                       uuid: uuid.to_owned(),
-                                       email: "".to_string(),
-                                       name: row.get(7).unwrap(),
-                                       can_create: false,
-                                       is_me: uuid == current_user_uuid,
-                                   }),
-                                   Err(_) => None,
-                               };
                                Ok(Gift {
-                                   uuid: row.get(0).unwrap(),
-                                   owner: row.get(1).unwrap(),
-                                   url: row.get(2).unwrap(),
-                                   comment: row.get(3).unwrap(),
-                                   claimed: row.get(4).unwrap(),
-                                   alternate_to_uuid: row.get(5).unwrap(),
-                                   claimed_by: user,
+                                   uuid: row.get(0)?,
+                                   owner: row.get(1)?,
+                                   details: DbConn::details_from_row(row, 4)?,
+                                   alternate_to_uuid: row.get(2)?,
+                                   claims: Vec::new(),
+                                   version: row.get(3)?,
                                })
                            }
-                ).unwrap()
-                .collect::<Result<Vec<Gift>, _>>()
+                )?
+                .collect::<Result<Vec<Gift>, _>>()?;
+            let mut claims = DbConn::claims_where(
+                db,
+                format!(
+                    "c.gift_uuid IN (SELECT gift_uuid FROM {} WHERE list_uuid = ?1 AND user_uuid = ?2)",
+                    DbConn::LIST_TO_GIFT_TABLE
+                )
+                .as_str(),
+                params![list_uuid, user_uuid],
+                &current_user_uuid,
+            )?;
+            for gift in gifts.iter_mut() {
+                gift.claims = claims.remove(&gift.uuid).unwrap_or_default();
+            }
+            Ok(gifts)
         }).await
+        .map_err(AppError::from)
     }
 
-    pub async fn gift_from_uuid(
-        self: &DbConn,
+    async fn gift_from_uuid(
+        &self,
         gift_uuid: String,
         current_user_uuid: String,
-    ) -> Result<Gift, Error> {
-        self.run(move |db| {
-            db.query_row(format!(
-                "SELECT l.gift_uuid, l.user_uuid, l.url, l.comment, l.claimed, l.alternate_to, r.user_uuid, r.name \
+    ) -> Result<Gift, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let mut gift = db.query_row(
+                format!(
+                    "SELECT l.gift_uuid, l.user_uuid, l.alternate_to, l.version, {} \
                 FROM {} AS l \
-                LEFT JOIN {} AS r ON r.user_uuid = l.claimed_by \
-                WHERE l.gift_uuid = ?1",
-                DbConn::GIFT_TABLE,
-                DbConn::USER_TABLE,
-            ).as_str(),
-                         params![gift_uuid],
-                         |row| {
-                             let user = match row.get::<usize, String>(6) {
-                                 Ok(uuid) => Some(User {
-                                     uuid: uuid.to_owned(),
-                                     email: "".to_string(),
-                                     name: row.get(7).unwrap(),
-                                     can_create: false,
-                                     is_me: uuid == current_user_uuid,
-                                 }),
-                                 Err(_) => None,
-                             };
-                             Ok(Gift {
-                                 uuid: row.get(0).unwrap(),
-                                 owner: row.get(1).unwrap(),
-                                 url: row.get(2).unwrap(),
-                                 comment: row.get(3).unwrap(),
-                                 claimed: row.get(4).unwrap(),
-                                 alternate_to_uuid: row.get(5).unwrap(),
-                                 claimed_by: user,
-                             })
-                         },
-            )
-        }).await
+                INNER JOIN {} AS m ON m.gift_uuid = l.gift_uuid \
+                INNER JOIN {} AS s ON s.list_uuid = m.list_uuid \
+                WHERE l.gift_uuid = ?1 AND l.deleted IS NULL AND s.deleted IS NULL",
+                    DbConn::detail_columns("l"),
+                    DbConn::GIFT_TABLE,
+                    DbConn::LIST_TO_GIFT_TABLE,
+                    DbConn::LIST_TABLE,
+                )
+                .as_str(),
+                params![gift_uuid],
+                |row| {
+                    Ok(Gift {
+                        uuid: row.get(0)?,
+                        owner: row.get(1)?,
+                        details: DbConn::details_from_row(row, 4)?,
+                        alternate_to_uuid: row.get(2)?,
+                        claims: Vec::new(),
+                        version: row.get(3)?,
+                    })
+                },
+            )?;
+            gift.claims = DbConn::claims_where(
+                db,
+                "c.gift_uuid = ?1",
+                params![gift_uuid],
+                &current_user_uuid,
+            )?
+            .remove(&gift_uuid)
+            .unwrap_or_default();
+            Ok(gift)
+        })
+        .await
+        .map_err(AppError::from)
     }
 
-    pub async fn create_user(self: &DbConn, user: User, auth_token: String) {
-        self.run(move |db| {
+    async fn create_user(&self, user: User) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "INSERT INTO {} (user_uuid, auth_token, email, name, can_create) \
+                    "INSERT INTO {} (user_uuid, email, name, can_create, is_admin) \
                 VALUES (?1, ?2, ?3, ?4, ?5)",
                     DbConn::USER_TABLE
                 )
                 .as_str(),
                 params![
                     user.uuid,
-                    auth_token,
                     user.email,
                     user.name,
-                    user.can_create
+                    user.can_create,
+                    user.is_admin
                 ],
             )
         })
-        .await
-        .unwrap();
+        .await?;
+        Ok(())
     }
 
-    pub async fn add_user_to_list(self: &DbConn, user_uuid: String, list_uuid: String) {
-        self.run(move |db| {
+    async fn add_user_to_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "INSERT INTO {} (list_uuid, user_uuid) \
-                VALUES (?1, ?2)",
+                    "INSERT INTO {} (list_uuid, user_uuid, role) \
+                VALUES (?1, ?2, ?3)",
                     DbConn::LIST_TO_USER_TABLE
                 )
                 .as_str(),
-                params![list_uuid, user_uuid],
+                params![list_uuid, user_uuid, role.as_str()],
             )
         })
-        .await
-        .unwrap();
+        .await?;
+        Ok(())
     }
 
-    pub async fn create_list(self: &DbConn, list: List) {
-        self.run(move |db| {
+    async fn set_list_role(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "INSERT INTO {} (list_uuid, name, owner) \
-                VALUES (?1, ?2, ?3)",
-                    DbConn::LIST_TABLE
+                    "UPDATE {} SET role = ?1 WHERE list_uuid = ?2 AND user_uuid = ?3",
+                    DbConn::LIST_TO_USER_TABLE
                 )
                 .as_str(),
-                params![list.uuid, list.name, list.owner],
+                params![role.as_str(), list_uuid, user_uuid],
             )
-            .unwrap();
-            db.execute(
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_user_from_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+    ) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            let tx = db.transaction()?;
+            // Their claims and pledges on this list are released, the group gifts they organize
+            // there are called off and their wishlist goes with them
+            for (table, column) in [
+                (DbConn::CLAIM_TABLE, "user_uuid"),
+                (DbConn::PLEDGE_TABLE, "user_uuid"),
+                (DbConn::GROUP_GIFT_TABLE, "organizer"),
+            ] {
+                tx.execute(
+                    format!(
+                        "DELETE FROM {} WHERE {} = ?1 AND gift_uuid IN (\
+                    SELECT gift_uuid FROM {} WHERE list_uuid = ?2)",
+                        table,
+                        column,
+                        DbConn::LIST_TO_GIFT_TABLE
+                    )
+                    .as_str(),
+                    params![user_uuid, list_uuid],
+                )?;
+            }
+            tx.execute(
                 format!(
-                    "INSERT INTO {} (list_uuid, user_uuid) \
-                VALUES (?1, ?2)",
+                    "DELETE FROM {} WHERE user_uuid = ?1 AND list_uuid = ?2",
                     DbConn::LIST_TO_USER_TABLE
                 )
                 .as_str(),
-                params![list.uuid, list.owner],
-            )
-            .unwrap();
+                params![user_uuid, list_uuid],
+            )?;
+            tx.commit()
         })
-        .await;
+        .await
+        .map_err(AppError::from)
     }
 
-    pub async fn create_gift(self: &DbConn, gift: Gift, list_uuid: String) {
-        self.run(move |db| {
-            db.execute(format!(
-                "INSERT INTO {} (gift_uuid, user_uuid, url, comment, claimed, claimed_by, alternate_to) \
-                VALUES (?1, ?2, ?3, ?4, 0, 'None', ?5)",
-                DbConn::GIFT_TABLE
-            ).as_str(),
-                       params![gift.uuid, gift.owner, gift.url, gift.comment, gift.alternate_to_uuid]
-            ).unwrap();
-            db.execute(format!(
-                "INSERT INTO {} (gift_uuid, user_uuid, list_uuid) \
+    async fn create_list(&self, list: List) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
+            let tx = db.transaction()?;
+            tx.execute(
+                format!(
+                    "INSERT INTO {} (list_uuid, name, owner) \
                 VALUES (?1, ?2, ?3)",
-                DbConn::LIST_TO_GIFT_TABLE
-            ).as_str(),
-                       params![gift.uuid, gift.owner, list_uuid]
-            ).unwrap();
-        }).await;
-    }
-
-    pub async fn modify_user(self: &DbConn, user: User) {
-        self.run(move |db| {
-            db.execute(
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                params![list.uuid, list.name, list.owner],
+            )?;
+            tx.execute(
                 format!(
-                    "UPDATE {} SET name = ?1, email = ?2, can_crease = ?3 \
-                WHERE user_uuid = ?4",
-                    DbConn::USER_TABLE
+                    "INSERT INTO {} (list_uuid, user_uuid, role) \
+                VALUES (?1, ?2, ?3)",
+                    DbConn::LIST_TO_USER_TABLE
                 )
                 .as_str(),
-                params![user.name, user.email, user.can_create, user.uuid],
-            )
+                params![list.uuid, list.owner, ListRole::CoOwner.as_str()],
+            )?;
+            tx.commit()
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
     }
 
-    pub async fn modify_list(self: &DbConn, list: List) {
-        self.run(move |db| {
-            db.execute(
+    async fn save_gift_list(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        removed: Vec<String>,
+        added: Vec<Gift>,
+        modified: Vec<Gift>,
+    ) -> Result<bool, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let version = base_version + 1;
+            let bumped = tx.execute(
                 format!(
-                    "UPDATE {} SET name = ?1 \
-                WHERE list_uuid = ?2",
-                    DbConn::LIST_TABLE
+                    "UPDATE {} SET version = ?4 \
+                WHERE list_uuid = ?1 AND user_uuid = ?2 AND version = ?3",
+                    DbConn::LIST_TO_USER_TABLE
                 )
                 .as_str(),
-                params![list.name, list.uuid],
-            )
+                params![list_uuid, user_uuid, base_version, version],
+            )?;
+            if bumped == 0 {
+                return Ok(false);
+            }
+            let deleted = DbConn::now();
+            for gift_uuid in &removed {
+                DbConn::trash_gift_row(&tx, gift_uuid, deleted)?;
+            }
+            for gift in &added {
+                DbConn::insert_gift_row(&tx, gift, &list_uuid, version)?;
+            }
+            for gift in &modified {
+                DbConn::update_gift_row(&tx, gift, version)?;
+            }
+            tx.commit()?;
+            Ok(true)
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
     }
 
-    pub async fn modify_gift(self: &DbConn, gift: Gift) {
-        self.run(move |db| {
-            db.execute(
+    async fn add_gift(&self, list_uuid: String, gift: Gift) -> Result<i64, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let version = DbConn::bump_wishlist_version(&tx, &list_uuid, &gift.owner)?;
+            DbConn::insert_gift_row(&tx, &gift, &list_uuid, version)?;
+            tx.commit()?;
+            Ok(version)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn update_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let version = DbConn::bump_wishlist_version(&tx, &list_uuid, &gift.owner)?;
+            let details = &gift.details;
+            let changed = tx.execute(
                 format!(
-                    "UPDATE {} SET url = ?1, comment = ?2, claimed = ?3, claimed_by = ?5 \
-                WHERE gift_uuid = ?4",
+                    "UPDATE {} SET title = ?1, url = ?2, comment = ?3, price = ?4, currency = ?5, \
+                quantity = ?6, priority = ?7, notes = ?8, version = ?9 \
+                WHERE gift_uuid = ?10 AND version = ?11 AND deleted IS NULL",
                     DbConn::GIFT_TABLE
                 )
                 .as_str(),
                 params![
-                    gift.url,
-                    gift.comment,
-                    gift.claimed,
+                    details.title,
+                    details.url,
+                    details.comment,
+                    details.price,
+                    details.currency,
+                    details.quantity,
+                    details.priority.as_str(),
+                    details.notes,
+                    version,
                     gift.uuid,
-                    gift.claimed_by
-                        .unwrap_or(User {
-                            uuid: "None".to_string(),
-                            email: "".to_string(),
-                            name: "".to_string(),
-                            can_create: false,
-                            is_me: false,
-                        })
-                        .uuid
+                    base_version
                 ],
-            )
+            )?;
+            if changed == 0 {
+                return Ok(None);
+            }
+            DbConn::record_revision(&tx, &gift, version)?;
+            tx.commit()?;
+            Ok(Some(version))
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
     }
 
-    pub async fn delete_user(self: &DbConn, user_uuid: String) {
-        // Unclaim items from user
-        self.run(move |db| {
-            db.execute(
-                format!("DELETE FROM {} WHERE user_uuid = ?1", DbConn::USER_TABLE).as_str(),
-                params![user_uuid],
-            )
-            .unwrap();
-            db.execute(
+    async fn delete_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let version = DbConn::bump_wishlist_version(&tx, &list_uuid, &gift.owner)?;
+            let deleted = DbConn::now();
+            let changed = tx.execute(
                 format!(
-                    "DELETE FROM {} WHERE user_uuid = ?1",
-                    DbConn::LIST_TO_USER_TABLE
+                    "UPDATE {} SET deleted = ?3 \
+                WHERE gift_uuid = ?1 AND version = ?2 AND deleted IS NULL",
+                    DbConn::GIFT_TABLE
                 )
                 .as_str(),
-                params![user_uuid],
-            )
-            .unwrap();
+                params![gift.uuid, base_version, deleted],
+            )?;
+            if changed == 0 {
+                return Ok(None);
+            }
+            DbConn::trash_gift_row(&tx, &gift.uuid, deleted)?;
+            tx.commit()?;
+            Ok(Some(version))
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn reorder_gifts(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        order: Vec<String>,
+    ) -> Result<Option<i64>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let version = tx
+                .query_row(
+                    format!(
+                        "UPDATE {} SET version = version + 1 \
+                    WHERE list_uuid = ?1 AND user_uuid = ?2 AND version = ?3 RETURNING version",
+                        DbConn::LIST_TO_USER_TABLE
+                    )
+                    .as_str(),
+                    params![list_uuid, user_uuid, base_version],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if version.is_none() {
+                return Ok(None);
+            }
+            for (position, gift_uuid) in order.iter().enumerate() {
+                tx.execute(
+                    format!(
+                        "UPDATE {} SET position = ?1 WHERE gift_uuid = ?2",
+                        DbConn::GIFT_TABLE
+                    )
+                    .as_str(),
+                    params![position as i64 + 1, gift_uuid],
+                )?;
+            }
+            tx.commit()?;
+            Ok(version)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn modify_user(&self, user: User) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "DELETE FROM {} l \
-                INNER JOIN {} r ON r.gift_uuid = l.gift_uuid \
-                INNER JOIN {} z ON z.list_uuid = r.list_uuid \
-                WHERE z.owner = ?1",
-                    DbConn::GIFT_TABLE,
-                    DbConn::LIST_TO_GIFT_TABLE,
-                    DbConn::LIST_TABLE
+                    "UPDATE {} SET name = ?1, email = ?2, can_create = ?3, is_admin = ?4 \
+                WHERE user_uuid = ?5",
+                    DbConn::USER_TABLE
                 )
                 .as_str(),
-                params![user_uuid],
+                params![
+                    user.name,
+                    user.email,
+                    user.can_create,
+                    user.is_admin,
+                    user.uuid
+                ],
             )
-            .unwrap();
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn modify_list(&self, list: List) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "DELETE FROM {} l \
-                INNER JOIN {} r ON r.list_uuid = l.list_uuid \
-                WHERE r.owner = ?1",
-                    DbConn::LIST_TO_GIFT_TABLE,
+                    "UPDATE {} SET name = ?1 \
+                WHERE list_uuid = ?2",
                     DbConn::LIST_TABLE
                 )
                 .as_str(),
-                params![user_uuid],
-            )
-            .unwrap();
-            db.execute(
-                format!("DELETE FROM {} WHERE owner = ?1", DbConn::LIST_TABLE).as_str(),
-                params![user_uuid],
-            )
-            .unwrap();
-            db.execute(
-                format!("DELETE FROM {} WHERE user_uuid = ?1", DbConn::GIFT_TABLE).as_str(),
-                params![user_uuid],
+                params![list.name, list.uuid],
             )
-            .unwrap();
         })
-        .await;
+        .await?;
+        Ok(())
     }
 
-    pub async fn delete_list(self: &DbConn, list_uuid: String) {
-        self.run(move |db| {
-            db.execute(
-                format!("DELETE FROM {} WHERE list_uuid = ?1", DbConn::LIST_TABLE).as_str(),
-                params![list_uuid],
-            )
-            .unwrap();
-            db.execute(
+    async fn gift_revisions(&self, gift_uuid: String) -> Result<Vec<GiftRevision>, AppError> {
+        self.run_with_fks(move |db| {
+            db.prepare(
                 format!(
-                    "DELETE FROM {} WHERE list_uuid = ?1",
-                    DbConn::LIST_TO_USER_TABLE
+                    "SELECT r.gift_uuid, r.version, {}, u.name, r.created \
+                FROM {} AS r \
+                LEFT JOIN {} AS u ON u.user_uuid = r.author \
+                WHERE r.gift_uuid = ?1 \
+                ORDER BY r.version DESC",
+                    DbConn::detail_columns("r"),
+                    DbConn::GIFT_REVISION_TABLE,
+                    DbConn::USER_TABLE
                 )
                 .as_str(),
-                params![list_uuid],
-            )
-            .unwrap();
-            db.execute(
+            )?
+            .query_map(params![gift_uuid], DbConn::revision_from_row)?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn claimed_revisions(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        claimer_uuid: String,
+    ) -> Result<Vec<GiftRevision>, AppError> {
+        self.run_with_fks(move |db| {
+            // Coming back from the trash stamps a gift without a new revision, so the claim was
+            // made on the latest one at or before the version it was claimed at
+            db.prepare(
                 format!(
-                    "DELETE FROM {} l \
-                INNER JOIN {} r ON r.gift_uuid = l.gift_uuid \
-                WHERE l.list_uuid = ?1",
+                    "SELECT r.gift_uuid, r.version, {}, u.name, r.created \
+                FROM {} AS l \
+                INNER JOIN {} AS i ON i.gift_uuid = l.gift_uuid \
+                INNER JOIN {} AS c ON c.gift_uuid = i.gift_uuid \
+                INNER JOIN {} AS r ON r.gift_uuid = i.gift_uuid \
+                LEFT JOIN {} AS u ON u.user_uuid = r.author \
+                WHERE l.list_uuid = ?1 AND l.user_uuid = ?2 AND c.user_uuid = ?3 \
+                AND i.deleted IS NULL \
+                AND r.version = (SELECT MAX(version) FROM {} \
+                    WHERE gift_uuid = i.gift_uuid AND version <= c.version)",
+                    DbConn::detail_columns("r"),
+                    DbConn::LIST_TO_GIFT_TABLE,
                     DbConn::GIFT_TABLE,
-                    DbConn::LIST_TO_GIFT_TABLE
-                )
-                .as_str(),
-                params![list_uuid],
-            )
-            .unwrap();
-            db.execute(
-                format!(
-                    "DELETE FROM {} WHERE list_uuid = ?1",
-                    DbConn::LIST_TO_GIFT_TABLE
+                    DbConn::CLAIM_TABLE,
+                    DbConn::GIFT_REVISION_TABLE,
+                    DbConn::USER_TABLE,
+                    DbConn::GIFT_REVISION_TABLE
                 )
                 .as_str(),
-                params![list_uuid],
-            )
-            .unwrap();
+            )?
+            .query_map(
+                params![list_uuid, user_uuid, claimer_uuid],
+                DbConn::revision_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()
         })
-        .await;
+        .await
+        .map_err(AppError::from)
     }
 
-    pub async fn delete_gift(self: &DbConn, gift_uuid: String) {
-        self.run(move |db| {
-            db.execute(
+    async fn claim_gift(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        quantity: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            // One statement, so nobody else's claim can land between the check and the write
+            let changed = db.execute(
                 format!(
-                    "DELETE FROM {} WHERE gift_uuid = ?1",
-                    DbConn::LIST_TO_GIFT_TABLE
+                    "INSERT INTO {} (gift_uuid, user_uuid, quantity, version, created) \
+                SELECT i.gift_uuid, ?2, ?3, i.version, ?4 FROM {} AS i \
+                WHERE i.gift_uuid = ?1 AND i.deleted IS NULL AND i.quantity - (\
+                    SELECT COALESCE(SUM(quantity), 0) FROM {} \
+                    WHERE gift_uuid = ?1 AND user_uuid != ?2) >= ?3 \
+                AND NOT EXISTS (SELECT 1 FROM {} WHERE gift_uuid = ?1) \
+                ON CONFLICT (gift_uuid, user_uuid) \
+                DO UPDATE SET quantity = excluded.quantity, version = excluded.version",
+                    DbConn::CLAIM_TABLE,
+                    DbConn::GIFT_TABLE,
+                    DbConn::CLAIM_TABLE,
+                    DbConn::GROUP_GIFT_TABLE
                 )
                 .as_str(),
-                params![gift_uuid],
-            )
-            .unwrap();
-            db.execute(
-                format!("DELETE FROM {} WHERE gift_uuid = ?1", DbConn::GIFT_TABLE).as_str(),
-                params![gift_uuid],
-            )
-            .unwrap();
+                params![gift_uuid, user_uuid, quantity, DbConn::now()],
+            )?;
+            if changed > 0 {
+                return Ok(None);
+            }
+            // Group gifts have none left to claim on their own
+            let left = db
+                .query_row(
+                    format!(
+                        "SELECT i.quantity - (\
+                        SELECT COALESCE(SUM(quantity), 0) FROM {} \
+                        WHERE gift_uuid = ?1 AND user_uuid != ?2) \
+                    FROM {} AS i WHERE i.gift_uuid = ?1 AND i.deleted IS NULL \
+                    AND NOT EXISTS (SELECT 1 FROM {} WHERE gift_uuid = ?1)",
+                        DbConn::CLAIM_TABLE,
+                        DbConn::GIFT_TABLE,
+                        DbConn::GROUP_GIFT_TABLE
+                    )
+                    .as_str(),
+                    params![gift_uuid, user_uuid],
+                    |row| row.get::<usize, i64>(0),
+                )
+                .optional()?;
+            Ok(Some(left.unwrap_or(0).max(0)))
         })
-        .await;
+        .await
+        .map_err(AppError::from)
     }
 
-    pub async fn init_db(rocket: Rocket<Build>) -> Rocket<Build> {
-        let conn = DbConn::get_one(&rocket).await.expect("database mounted");
-
-        conn.run(move |db| {
+    async fn unclaim_gift(&self, gift_uuid: String, user_uuid: String) -> Result<bool, AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "CREATE TABLE IF NOT EXISTS {} (
-                        user_uuid   TEXT PRIMARY KEY,
-                        auth_token  TEXT NOT NULL,
-                        email       TEXT NOT NULL,
-                        name        TEXT NOT NULL,
-                        can_create  BOOL NOT NULL
-                        )",
-                    DbConn::USER_TABLE
+                    "DELETE FROM {} WHERE gift_uuid = ?1 AND user_uuid = ?2",
+                    DbConn::CLAIM_TABLE
                 )
                 .as_str(),
-                [],
+                params![gift_uuid, user_uuid],
             )
         })
         .await
-        .unwrap();
+        .map(|changed| changed > 0)
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
-            db.execute(
+    async fn group_gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<GroupGift>, AppError> {
+        self.run_with_fks(move |db| {
+            DbConn::group_gifts_where(
+                db,
                 format!(
-                    "CREATE INDEX IF NOT EXISTS tokens ON {}(auth_token)",
-                    DbConn::USER_TABLE
+                    "SELECT gift_uuid FROM {} WHERE list_uuid = ?1 AND user_uuid = ?2",
+                    DbConn::LIST_TO_GIFT_TABLE
                 )
                 .as_str(),
-                [],
+                params![list_uuid, user_uuid],
+                &current_user_uuid,
             )
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+    }
+
+    async fn group_gift(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Option<GroupGift>, AppError> {
+        self.run_with_fks(move |db| {
+            DbConn::group_gifts_where(db, "?1", params![gift_uuid], &current_user_uuid)
+        })
+        .await
+        .map(|groups| groups.into_iter().next())
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
+    async fn start_group_gift(
+        &self,
+        gift_uuid: String,
+        organizer_uuid: String,
+    ) -> Result<bool, AppError> {
+        self.run_with_fks(move |db| {
+            // Checked in the same statement, so a claim can't slip in first
             db.execute(
                 format!(
-                    "CREATE INDEX IF NOT EXISTS emails ON {}(email)",
-                    DbConn::USER_TABLE
+                    "INSERT INTO {} (gift_uuid, organizer, created) \
+                SELECT ?1, ?2, ?3 WHERE NOT EXISTS (SELECT 1 FROM {} WHERE gift_uuid = ?1) \
+                ON CONFLICT (gift_uuid) DO NOTHING",
+                    DbConn::GROUP_GIFT_TABLE,
+                    DbConn::CLAIM_TABLE
                 )
                 .as_str(),
-                [],
+                params![gift_uuid, organizer_uuid, DbConn::now()],
             )
         })
         .await
-        .unwrap();
+        .map(|changed| changed > 0)
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
+    async fn cancel_group_gift(&self, gift_uuid: String) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "CREATE TABLE IF NOT EXISTS {} (
-                        list_uuid   TEXT PRIMARY KEY,
-                        name        TEXT NOT NULL,
-                        owner       TEXT NOT NULL
-                        )",
-                    DbConn::LIST_TABLE
+                    "DELETE FROM {} WHERE gift_uuid = ?1",
+                    DbConn::GROUP_GIFT_TABLE
                 )
                 .as_str(),
-                [],
+                params![gift_uuid],
             )
         })
+        .await?;
+        Ok(())
+    }
+
+    async fn pledge(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        amount: i64,
+    ) -> Result<bool, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            // Marking it purchased can't land between the check and the write
+            let tx = db.transaction()?;
+            let open = tx
+                .query_row(
+                    format!(
+                        "SELECT 1 FROM {} WHERE gift_uuid = ?1 AND purchased IS NULL",
+                        DbConn::GROUP_GIFT_TABLE
+                    )
+                    .as_str(),
+                    params![gift_uuid],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !open {
+                return Ok(false);
+            }
+            if amount == 0 {
+                tx.execute(
+                    format!(
+                        "DELETE FROM {} WHERE gift_uuid = ?1 AND user_uuid = ?2",
+                        DbConn::PLEDGE_TABLE
+                    )
+                    .as_str(),
+                    params![gift_uuid, user_uuid],
+                )?;
+            } else {
+                tx.execute(
+                    format!(
+                        "INSERT INTO {} (gift_uuid, user_uuid, amount, created) \
+                    VALUES (?1, ?2, ?3, ?4) \
+                    ON CONFLICT (gift_uuid, user_uuid) DO UPDATE SET amount = excluded.amount",
+                        DbConn::PLEDGE_TABLE
+                    )
+                    .as_str(),
+                    params![gift_uuid, user_uuid, amount, DbConn::now()],
+                )?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
+    async fn set_group_gift_purchased(
+        &self,
+        gift_uuid: String,
+        purchased: bool,
+    ) -> Result<(), AppError> {
+        let purchased = if purchased { Some(DbConn::now()) } else { None };
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "CREATE TABLE IF NOT EXISTS {} (
-                        list_uuid   TEXT,
-                        user_uuid   TEXT
-                        )",
-                    DbConn::LIST_TO_USER_TABLE
+                    "UPDATE {} SET purchased = ?2 WHERE gift_uuid = ?1",
+                    DbConn::GROUP_GIFT_TABLE
                 )
                 .as_str(),
-                [],
+                params![gift_uuid, purchased],
             )
         })
-        .await
-        .unwrap();
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_uuid: String) -> Result<(), AppError> {
+        let blocked = self
+            .run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+                let tx = db.transaction()?;
+                // The co-owner who joined first takes over each list they created
+                tx.execute(
+                    format!(
+                        "UPDATE {lists} SET owner = (\
+                        SELECT m.user_uuid FROM {members} AS m \
+                        WHERE m.list_uuid = {lists}.list_uuid AND m.user_uuid != ?1 \
+                        AND m.role = ?2 ORDER BY m.rowid LIMIT 1) \
+                    WHERE owner = ?1 AND EXISTS (\
+                        SELECT 1 FROM {members} AS m \
+                        WHERE m.list_uuid = {lists}.list_uuid AND m.user_uuid != ?1 \
+                        AND m.role = ?2)",
+                        lists = DbConn::LIST_TABLE,
+                        members = DbConn::LIST_TO_USER_TABLE
+                    )
+                    .as_str(),
+                    params![user_uuid, ListRole::CoOwner.as_str()],
+                )?;
+                let blocked = tx
+                    .prepare(
+                        format!(
+                            "SELECT l.name FROM {} AS l \
+                        WHERE l.owner = ?1 AND l.deleted IS NULL AND EXISTS (\
+                            SELECT 1 FROM {} AS m \
+                            WHERE m.list_uuid = l.list_uuid AND m.user_uuid != ?1) \
+                        ORDER BY l.name",
+                            DbConn::LIST_TABLE,
+                            DbConn::LIST_TO_USER_TABLE
+                        )
+                        .as_str(),
+                    )?
+                    .query_map(params![user_uuid], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?;
+                if !blocked.is_empty() {
+                    return Ok(blocked);
+                }
+                // Their remaining lists, memberships, gifts, claims, pledges, group gifts, tokens
+                // and sessions cascade
+                tx.execute(
+                    format!("DELETE FROM {} WHERE user_uuid = ?1", DbConn::USER_TABLE).as_str(),
+                    params![user_uuid],
+                )?;
+                tx.commit()?;
+                Ok(blocked)
+            })
+            .await?;
+        if !blocked.is_empty() {
+            return Err(AppError::only_co_owner(&blocked));
+        }
+        Ok(())
+    }
 
-        conn.run(move |db| {
+    async fn delete_list(&self, list_uuid: String) -> Result<(), AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "CREATE INDEX IF NOT EXISTS list_map_list ON {}(list_uuid)",
-                    DbConn::LIST_TO_USER_TABLE
+                    "UPDATE {} SET deleted = ?2 WHERE list_uuid = ?1 AND deleted IS NULL",
+                    DbConn::LIST_TABLE
                 )
                 .as_str(),
-                [],
+                params![list_uuid, DbConn::now()],
             )
         })
-        .await
-        .unwrap();
+        .await?;
+        Ok(())
+    }
 
-        conn.run(move |db| {
+    async fn restore_list(&self, list_uuid: String) -> Result<bool, AppError> {
+        self.run_with_fks(move |db| {
             db.execute(
                 format!(
-                    "CREATE INDEX IF NOT EXISTS list_map_user ON {}(user_uuid)",
-                    DbConn::LIST_TO_USER_TABLE
+                    "UPDATE {} SET deleted = NULL WHERE list_uuid = ?1 AND deleted IS NOT NULL",
+                    DbConn::LIST_TABLE
                 )
                 .as_str(),
-                [],
+                params![list_uuid],
             )
         })
         .await
-        .unwrap();
+        .map(|changed| changed > 0)
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
-            db.execute(
+    async fn restore_gift(
+        &self,
+        list_uuid: String,
+        gift_uuid: String,
+        user_uuid: String,
+    ) -> Result<Option<i64>, AppError> {
+        self.run_with_fks(move |db| -> Result<_, rusqlite::Error> {
+            let tx = db.transaction()?;
+            let deleted: Option<i64> = tx
+                .query_row(
+                    format!(
+                        "SELECT deleted FROM {} \
+                    WHERE gift_uuid = ?1 AND user_uuid = ?2 AND deleted IS NOT NULL",
+                        DbConn::GIFT_TABLE
+                    )
+                    .as_str(),
+                    params![gift_uuid, user_uuid],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let deleted = match deleted {
+                Some(deleted) => deleted,
+                None => return Ok(None),
+            };
+            let version = DbConn::bump_wishlist_version(&tx, &list_uuid, &user_uuid)?;
+            // Stamped with the new version so open pages see it as added
+            tx.execute(
                 format!(
-                    "CREATE TABLE IF NOT EXISTS {} (
-                        gift_uuid       TEXT PRIMARY KEY,
-                        user_uuid       TEXT NOT NULL,
-                        url             TEXT NOT NULL,
-                        comment         TEXT NOT NULL,
-                        claimed         BOOL NOT NULL,
-                        claimed_by      TEXT NOT NULL,
-                        alternate_to    TEXT
-                        )",
+                    "UPDATE {} SET deleted = NULL, version = ?3 \
+                WHERE (gift_uuid = ?1 OR alternate_to = ?1) AND deleted = ?2",
                     DbConn::GIFT_TABLE
                 )
                 .as_str(),
-                [],
-            )
+                params![gift_uuid, deleted, version],
+            )?;
+            tx.commit()?;
+            Ok(Some(version))
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
-            db.execute(
+    async fn deleted_lists_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedList>, AppError> {
+        self.run_with_fks(move |db| {
+            db.prepare(
                 format!(
-                    "CREATE TABLE IF NOT EXISTS {} (
-                        list_uuid   TEXT,
-                        user_uuid   TEXT,
-                        gift_uuid   TEXT
-                        )",
-                    DbConn::LIST_TO_GIFT_TABLE
+                    "SELECT l.list_uuid, l.name, l.deleted \
+                FROM {} l \
+                INNER JOIN {} m ON m.list_uuid = l.list_uuid \
+                WHERE m.user_uuid = ?1 AND m.role = ?2 AND l.deleted > ?3 \
+                ORDER BY l.deleted DESC",
+                    DbConn::LIST_TABLE,
+                    DbConn::LIST_TO_USER_TABLE,
                 )
                 .as_str(),
-                [],
-            )
+            )?
+            .query_map(
+                params![user_uuid, ListRole::CoOwner.as_str(), since],
+                |row| {
+                    Ok(DeletedList {
+                        uuid: row.get(0)?,
+                        name: row.get(1)?,
+                        deleted: row.get(2)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<DeletedList>, _>>()
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+    }
 
-        conn.run(move |db| {
-            db.execute(
+    async fn deleted_gifts_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedGift>, AppError> {
+        self.run_with_fks(move |db| {
+            db.prepare(
                 format!(
-                    "CREATE INDEX IF NOT EXISTS item_map_list ON {}(list_uuid)",
-                    DbConn::LIST_TO_GIFT_TABLE
+                    "SELECT i.gift_uuid, m.list_uuid, l.name, i.title, i.url, i.comment, i.deleted \
+                FROM {} i \
+                INNER JOIN {} m ON m.gift_uuid = i.gift_uuid \
+                INNER JOIN {} l ON l.list_uuid = m.list_uuid \
+                LEFT JOIN {} a ON a.gift_uuid = i.alternate_to \
+                WHERE i.user_uuid = ?1 AND i.deleted > ?2 \
+                AND l.deleted IS NULL AND a.deleted IS NULL \
+                ORDER BY i.deleted DESC",
+                    DbConn::GIFT_TABLE,
+                    DbConn::LIST_TO_GIFT_TABLE,
+                    DbConn::LIST_TABLE,
+                    DbConn::GIFT_TABLE,
                 )
                 .as_str(),
-                [],
-            )
+            )?
+            .query_map(params![user_uuid, since], |row| {
+                Ok(DeletedGift {
+                    uuid: row.get(0)?,
+                    list_uuid: row.get(1)?,
+                    list_name: row.get(2)?,
+                    title: row.get(3)?,
+                    url: row.get(4)?,
+                    comment: row.get(5)?,
+                    deleted: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<DeletedGift>, _>>()
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+    }
+
+    async fn purge_trash(&self, before: i64) -> Result<usize, AppError> {
+        self.run_with_fks(move |db| {
+            let tx = db.transaction()?;
+            // Gifts on a purged list go with it through the foreign keys
+            let mut purged = 0;
+            for table in [DbConn::LIST_TABLE, DbConn::GIFT_TABLE] {
+                purged += tx.execute(
+                    format!("DELETE FROM {} WHERE deleted < ?1", table).as_str(),
+                    params![before],
+                )?;
+            }
+            tx.commit()?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    async fn backup(&self, path: PathBuf) -> Result<(), AppError> {
+        self.run(move |db| {
+            let mut copy = rusqlite::Connection::open(path)?;
+            Backups::copy(db, &mut copy)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+}
 
-        rocket
+#[cfg(test)]
+pub(crate) mod tests {
+    use rocket::{Ignite, Rocket};
+
+    use crate::repository::Storage;
+    use crate::repository_tests::repository_cases;
+
+    // One pooled connection, so the in-memory database lives as long as the test
+    pub(crate) async fn storage() -> (Rocket<Ignite>, Storage) {
+        let figment = rocket::Config::figment()
+            .merge(("databases.sqlite_logs.url", ":memory:"))
+            .merge(("databases.sqlite_logs.pool_size", 1));
+        let rocket = Storage::attach(rocket::custom(figment))
+            .ignite()
+            .await
+            .expect("in-memory database set up");
+        let conn = Storage::get_one(&rocket).await.expect("storage mounted");
+        (rocket, conn)
     }
+
+    repository_cases!(storage());
 }
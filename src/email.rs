@@ -1,14 +1,14 @@
-use crate::data::InviteEmail;
+use crate::data::{InviteEmail, LoginEmail};
+use crate::error::AppError;
 use config_file::FromConfigFile;
 use lettre::transport::smtp::response::Response;
-use lettre::transport::smtp::Error;
 use lettre::{
     message::{header, Mailbox, MultiPart, SinglePart},
     transport::smtp::authentication::Credentials,
     Message, SmtpTransport, Transport,
 };
 use rocket_dyn_templates::handlebars::Handlebars;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub struct Email {
     creds: Credentials,
@@ -28,69 +28,130 @@ struct EmailConfig {
     website_root: String,
 }
 
+// Printing a login link only needs the address, not working mail settings
+#[derive(Deserialize)]
+struct SiteConfig {
+    website_root: String,
+}
+
 impl Email {
-    pub fn build() -> Email {
-        let config = EmailConfig::from_config_file("config.toml").unwrap();
+    pub fn build() -> Result<Email, AppError> {
+        let config = EmailConfig::from_config_file("config.toml")
+            .map_err(|e| AppError::Backend(format!("couldn't read config.toml ({})", e)))?;
         let creds = Credentials::new(config.access_key, config.secret_key);
-        Email {
+        Ok(Email {
             creds,
             email_endpoint: config.email_endpoint,
             email_from: config.email_from,
             admin_email: config.admin_email,
             website_root: config.website_root,
-        }
+        })
     }
 
-    fn create_relay(self: Email) -> SmtpTransport {
-        SmtpTransport::starttls_relay(self.email_endpoint.as_str())
-            .unwrap()
+    fn create_relay(self: Email) -> Result<SmtpTransport, AppError> {
+        Ok(SmtpTransport::starttls_relay(self.email_endpoint.as_str())?
             .credentials(self.creds)
-            .build()
+            .build())
+    }
+
+    fn login_link(self: &Email, user_auth_token: String) -> String {
+        format!("{}login/{}", self.website_root.as_str(), user_auth_token)
+    }
+
+    // Without config.toml the site's address is left for whoever reads it to fill in
+    pub fn site_login_link(user_auth_token: String) -> String {
+        match SiteConfig::from_config_file("config.toml") {
+            Ok(config) => format!("{}login/{}", config.website_root, user_auth_token),
+            Err(_) => format!("<website root>/login/{}", user_auth_token),
+        }
     }
 
     pub fn send_invite_email(
         self: Email,
         list_name: String,
         user_auth_token: String,
+        link_hours: i64,
+        user_name: String,
+        user_email: String,
+    ) -> Result<Response, AppError> {
+        let data = InviteEmail {
+            user_name: user_name.to_owned(),
+            list_name: list_name.to_owned(),
+            link: self.login_link(user_auth_token),
+            link_hours,
+            admin_email: self.admin_email.to_string(),
+        };
+        self.send_templated_email(
+            "email_invite",
+            format!("You've been invited to the {} wishlist!", list_name),
+            &data,
+            user_name,
+            user_email,
+        )
+    }
+
+    pub fn send_login_email(
+        self: Email,
+        user_auth_token: String,
+        link_hours: i64,
         user_name: String,
         user_email: String,
-    ) -> Result<Response, Error> {
-        let img = std::fs::read("./static/header.png").unwrap();
+    ) -> Result<Response, AppError> {
+        let data = LoginEmail {
+            user_name: user_name.to_owned(),
+            link: self.login_link(user_auth_token),
+            link_hours,
+            admin_email: self.admin_email.to_string(),
+        };
+        self.send_templated_email(
+            "email_login",
+            "Your Goord login link".to_string(),
+            &data,
+            user_name,
+            user_email,
+        )
+    }
+
+    fn send_templated_email<T: Serialize>(
+        self: Email,
+        template_name: &str,
+        subject: String,
+        data: &T,
+        user_name: String,
+        user_email: String,
+    ) -> Result<Response, AppError> {
+        let img = std::fs::read("./static/header.png")
+            .map_err(|e| AppError::Backend(format!("couldn't read the email header ({})", e)))?;
         let mut handlebars = Handlebars::new();
         handlebars
-            .register_template_file("email_invite", "./templates/email_invite.html.hbs")
-            .unwrap();
-        let emailed_from: Mailbox = self.email_from.parse().unwrap();
-        let emailed_to: Mailbox = format!("{} <{}>", user_name, user_email).parse().unwrap();
+            .register_template_file(
+                template_name,
+                format!("./templates/{}.html.hbs", template_name),
+            )
+            .map_err(|e| AppError::Backend(e.to_string()))?;
+        let body = handlebars
+            .render(template_name, data)
+            .map_err(|e| AppError::Backend(e.to_string()))?;
+        let emailed_from: Mailbox = self
+            .email_from
+            .parse()
+            .map_err(|_| AppError::Backend("email_from isn't a valid address".to_string()))?;
+        let emailed_to: Mailbox =
+            format!("{} <{}>", user_name, user_email)
+                .parse()
+                .map_err(|_| {
+                    AppError::Validation(format!("{} isn't a valid email address", user_email))
+                })?;
         let email = Message::builder()
             .from(emailed_from)
             .to(emailed_to)
-            .subject(format!(
-                "You've been invited to the {} wishlist!",
-                list_name
-            ))
+            .subject(subject)
             .multipart(
                 MultiPart::related()
                     .singlepart(
                         SinglePart::builder()
                             .header(header::ContentType::TEXT_HTML)
-                            .body(
-                                handlebars
-                                    .render(
-                                        "email_invite",
-                                        &InviteEmail {
-                                            user_name,
-                                            list_name,
-                                            link: format!(
-                                                "{}login/{}",
-                                                self.website_root.as_str(),
-                                                user_auth_token
-                                            ),
-                                            admin_email: self.admin_email.to_string(),
-                                        },
-                                    )
-                                    .unwrap(),
-                            ),
+                            .body(body),
                     )
                     .singlepart(
                         SinglePart::builder()
@@ -100,8 +161,8 @@ impl Email {
                             .body(img),
                     ),
             )
-            .unwrap();
-        let mailer = self.create_relay();
-        mailer.send(&email)
+            .map_err(|e| AppError::Backend(e.to_string()))?;
+        let mailer = self.create_relay()?;
+        Ok(mailer.send(&email)?)
     }
 }
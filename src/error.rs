@@ -0,0 +1,82 @@
+use rocket::http::{Method, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+#[cfg(feature = "postgres")]
+use rocket_sync_db_pools::postgres;
+use rocket_sync_db_pools::rusqlite;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    Forbidden,
+    Validation(String),
+    Conflict(String),
+    Backend(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "That doesn't exist"),
+            AppError::Forbidden => write!(f, "You don't have access to that"),
+            AppError::Validation(message) => write!(f, "{}", message),
+            AppError::Conflict(message) => write!(f, "{}", message),
+            AppError::Backend(message) => write!(f, "Something went wrong: {}", message),
+        }
+    }
+}
+
+impl AppError {
+    // Deleting someone mustn't take a list other people still use down with them
+    pub fn only_co_owner(lists: &[String]) -> AppError {
+        AppError::Validation(format!(
+            "They're the only co-owner of {}, so make someone else a co-owner first",
+            lists.join(", ")
+        ))
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error {
+            rusqlite::Error::QueryReturnedNoRows => AppError::NotFound,
+            e => AppError::Backend(e.to_string()),
+        }
+    }
+}
+
+// Lookups that can come back empty check for that themselves, so anything here is a real failure
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for AppError {
+    fn from(error: postgres::Error) -> Self {
+        AppError::Backend(error.to_string())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for AppError {
+    fn from(error: lettre::transport::smtp::Error) -> Self {
+        AppError::Backend(format!("couldn't send email ({})", error))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AppError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = match self {
+            AppError::NotFound => Status::NotFound,
+            AppError::Forbidden => Status::Forbidden,
+            AppError::Validation(_) => Status::BadRequest,
+            AppError::Conflict(_) => Status::Conflict,
+            AppError::Backend(_) => Status::InternalServerError,
+        };
+        if let AppError::Backend(message) = &self {
+            rocket::error!("Error handling {}: {}", req.uri(), message);
+        }
+        // Pages get the catcher templates, form posts get a message to show
+        match (req.method(), &self) {
+            (Method::Get, AppError::Validation(_)) => (status, self.to_string()).respond_to(req),
+            (Method::Get, _) => Err(status),
+            _ => (status, self.to_string()).respond_to(req),
+        }
+    }
+}
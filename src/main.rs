@@ -1,38 +1,93 @@
 #[macro_use]
 extern crate rocket;
 
+mod authorization;
+mod backup;
+mod cli;
+// Rocket's FromForm derive allows `private_in_public`, a lint newer compilers have dropped
+#[allow(renamed_and_removed_lints)]
 mod data;
 mod db;
 mod email;
+mod error;
+#[cfg(test)]
+mod memory;
+mod migrations;
+#[cfg(feature = "postgres")]
+mod pg;
+#[cfg(feature = "postgres")]
+mod pg_migrations;
+mod repository;
+#[cfg(test)]
+mod repository_tests;
+mod schedule;
+mod trash;
 mod webserver;
 
+use rocket::config::LogLevel;
 use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
 use rocket::form::Form;
 use rocket::fs::{relative, FileServer};
 use rocket::http::{CookieJar, Status};
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::RawJson;
 use rocket::response::Redirect;
+use rocket::{Build, Config, Rocket, State};
 use rocket_dyn_templates::Template;
 
-use crate::data::{Auth, ClaimGift, CreateList, DeleteList, ModifyGiftList, ModifyList};
-use crate::db::DbConn;
+use crate::backup::Backups;
+use crate::cli::Cli;
+use crate::data::{
+    AddGift, AdminModifyUser, AdminUser, AdminUserAction, AppConfig, CancelGroupGift, ClaimGift,
+    CreateList, CurrentUser, DeleteGift, DeleteList, ImportGiftList, MarkPurchased, ModifyList,
+    PledgeGift, ReorderGifts, RequestLogin, RestoreGift, RestoreList, RevertGift, StartGroupGift,
+    UnclaimGift, UpdateGift,
+};
+use crate::error::AppError;
+use crate::repository::{Storage, StorageBackend};
+use crate::trash::Trash;
 use crate::webserver::WebServer;
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Auth {
+impl<'r> FromRequest<'r> for CurrentUser {
     type Error = ();
 
-    async fn from_request(req: &'r Request<'_>) -> Outcome<Auth, Self::Error> {
-        let user_uuid = WebServer::get_user_cookie(req.cookies());
-        return match user_uuid {
-            Ok(_) => Outcome::Success(Auth {
-                user_token: "".to_string(),
-            }),
-            Err(_) => Outcome::Failure((Status::NotFound, ())),
+    async fn from_request(req: &'r Request<'_>) -> Outcome<CurrentUser, Self::Error> {
+        // Resolved once per request, however many guards ask for it
+        let current_user = req
+            .local_cache_async(async {
+                let conn = req.guard::<Storage>().await.succeeded()?;
+                WebServer::get_current_user(req.cookies(), &*conn)
+                    .await
+                    .ok()
+            })
+            .await;
+        return match current_user {
+            Some(u) => Outcome::Success(CurrentUser(u.clone())),
+            None => Outcome::Failure((Status::Unauthorized, ())),
         };
     }
 }
 
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<AdminUser, Self::Error> {
+        let current_user = rocket::outcome::try_outcome!(req.guard::<CurrentUser>().await);
+        return match current_user.0.is_admin {
+            true => Outcome::Success(AdminUser(current_user.0)),
+            false => Outcome::Failure((Status::Forbidden, ())),
+        };
+    }
+}
+
+#[catch(401)]
+async fn unauthorized() -> Redirect {
+    Redirect::to(uri!("/"))
+}
+
 #[catch(default)]
 async fn default_error() -> Template {
     WebServer::not_found().await
@@ -54,120 +109,325 @@ async fn index() -> Template {
 }
 
 #[get("/login/<usertoken>")]
-async fn login(usertoken: String, cookies: &CookieJar<'_>, conn: DbConn) -> Redirect {
-    WebServer::login(usertoken, cookies, &conn).await
+async fn login(
+    usertoken: String,
+    cookies: &CookieJar<'_>,
+    conn: Storage,
+) -> Result<Redirect, AppError> {
+    WebServer::login(usertoken, cookies, &*conn).await
+}
+
+#[post("/logout")]
+async fn logout(cookies: &CookieJar<'_>, conn: Storage) -> Result<Redirect, AppError> {
+    WebServer::logout(cookies, &*conn).await
+}
+
+#[post("/logout/everywhere")]
+async fn logout_everywhere(
+    current_user: CurrentUser,
+    cookies: &CookieJar<'_>,
+    conn: Storage,
+) -> Result<Redirect, AppError> {
+    WebServer::logout_everywhere(current_user.0, cookies, &*conn).await
+}
+
+#[post("/sendlogin", data = "<request>")]
+async fn send_login_link(
+    request: Form<RequestLogin>,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::send_login_link(request, config, &*conn).await
 }
 
 #[get("/user")]
-async fn user_page(cookies: &CookieJar<'_>, conn: DbConn, _auth: Auth) -> Template {
-    WebServer::user_page(cookies, &conn).await
+async fn user_page(
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::user_page(current_user.0, config, &*conn).await
+}
+
+#[get("/trash")]
+async fn trash_page(
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::trash_page(current_user.0, config, &*conn).await
+}
+
+#[post("/trash/restorelist", data = "<list>")]
+async fn restore_list(
+    list: Form<RestoreList>,
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::restore_list(list, current_user.0, config, &*conn).await
+}
+
+#[post("/trash/restoregift", data = "<gift>")]
+async fn restore_gift(
+    gift: Form<RestoreGift>,
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::restore_gift(gift, current_user.0, config, &*conn).await
+}
+
+#[get("/admin")]
+async fn admin_page(
+    admin: AdminUser,
+    config: &State<AppConfig>,
+    backend: &State<StorageBackend>,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::admin_page(admin.0, config, **backend, &*conn).await
+}
+
+#[post("/admin/backup")]
+async fn admin_backup(
+    _admin: AdminUser,
+    config: &State<AppConfig>,
+    backend: &State<StorageBackend>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::admin_backup(config, **backend, &*conn).await
+}
+
+#[post("/admin/modifyuser", data = "<user>")]
+async fn admin_modify_user(
+    user: Form<AdminModifyUser>,
+    admin: AdminUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::admin_modify_user(user, admin.0, &*conn).await
+}
+
+#[post("/admin/resetlogin", data = "<user>")]
+async fn admin_reset_login(
+    user: Form<AdminUserAction>,
+    admin: AdminUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::admin_reset_login(user, admin.0, config, &*conn).await
+}
+
+#[post("/admin/deleteuser", data = "<user>")]
+async fn admin_delete_user(
+    user: Form<AdminUserAction>,
+    admin: AdminUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::admin_delete_user(user, admin.0, &*conn).await
 }
 
 #[get("/list/<list_uuid>")]
 async fn list_page(
     list_uuid: String,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> Template {
-    WebServer::list_page(list_uuid, cookies, &conn).await
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::list_page(list_uuid, current_user.0, &*conn).await
 }
 
 #[get("/list/<list_uuid>/<user_uuid>")]
 async fn list_user_page(
     list_uuid: String,
     user_uuid: String,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> Template {
-    WebServer::list_user_page(list_uuid, user_uuid, cookies, &conn).await
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::list_user_page(list_uuid, user_uuid, current_user.0, config, &*conn).await
 }
 
 #[get("/createlist")]
-async fn create_list_page(cookies: &CookieJar<'_>, conn: DbConn, _auth: Auth) -> Template {
-    WebServer::create_list_page(cookies, &conn).await
+async fn create_list_page(current_user: CurrentUser) -> Result<Template, AppError> {
+    WebServer::create_list_page(current_user.0).await
 }
 
 #[post("/createlist", data = "<list>")]
 async fn create_list(
     list: Form<CreateList>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::create_list(list, cookies, &conn).await
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::create_list(list, current_user.0, config, &*conn).await
 }
 
 #[get("/modifylist/<list_uuid>")]
 async fn modify_list_page(
     list_uuid: String,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> Template {
-    WebServer::modify_list_page(list_uuid, cookies, &conn).await
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::modify_list_page(list_uuid, current_user.0, &*conn).await
 }
 
 #[post("/modifylist", data = "<list>")]
 async fn modify_list(
     list: Form<ModifyList>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::modify_list(list, cookies, &conn).await
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::modify_list(list, current_user.0, config, &*conn).await
 }
 
 #[post("/deletelist", data = "<list>")]
 async fn delete_list(
     list: Form<DeleteList>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::delete_list(list, cookies, &conn).await
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::delete_list(list, current_user.0, &*conn).await
 }
 
 #[post("/claim", data = "<claim>")]
 async fn claim_gift(
     claim: Form<ClaimGift>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::claim_gift(claim, cookies, &conn).await
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::claim_gift(claim, current_user.0, &*conn).await
 }
 
 #[post("/unclaim", data = "<claim>")]
 async fn unclaim_gift(
-    claim: Form<ClaimGift>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::unclaim_gift(claim, cookies, &conn).await
+    claim: Form<UnclaimGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::unclaim_gift(claim, current_user.0, &*conn).await
 }
 
-#[post("/modifygiftlist", data = "<gifts>")]
-async fn modify_item_list(
-    gifts: Form<ModifyGiftList>,
-    cookies: &CookieJar<'_>,
-    conn: DbConn,
-    _auth: Auth,
-) -> String {
-    WebServer::modify_gift_list(gifts, cookies, &conn).await
+#[post("/group/start", data = "<start>")]
+async fn start_group_gift(
+    start: Form<StartGroupGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::start_group_gift(start, current_user.0, &*conn).await
+}
+
+#[post("/group/cancel", data = "<cancel>")]
+async fn cancel_group_gift(
+    cancel: Form<CancelGroupGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::cancel_group_gift(cancel, current_user.0, &*conn).await
+}
+
+#[post("/group/pledge", data = "<pledge>")]
+async fn pledge(
+    pledge: Form<PledgeGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::pledge(pledge, current_user.0, &*conn).await
+}
+
+#[post("/group/purchased", data = "<mark>")]
+async fn mark_purchased(
+    mark: Form<MarkPurchased>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::mark_purchased(mark, current_user.0, &*conn).await
+}
+
+#[post("/gift/add", data = "<gift>")]
+async fn add_gift(
+    gift: Form<AddGift>,
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<RawJson<String>, AppError> {
+    WebServer::add_gift(gift, current_user.0, config, &*conn).await
 }
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
+#[post("/gift/update", data = "<gift>")]
+async fn update_gift(
+    gift: Form<UpdateGift>,
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<RawJson<String>, AppError> {
+    WebServer::update_gift(gift, current_user.0, config, &*conn).await
+}
+
+#[post("/gift/delete", data = "<gift>")]
+async fn delete_gift(
+    gift: Form<DeleteGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<RawJson<String>, AppError> {
+    WebServer::delete_gift(gift, current_user.0, &*conn).await
+}
+
+#[get("/gift/<gift_uuid>/history")]
+async fn gift_history_page(
+    gift_uuid: String,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<Template, AppError> {
+    WebServer::gift_history_page(gift_uuid, current_user.0, &*conn).await
+}
+
+#[post("/gift/revert", data = "<gift>")]
+async fn revert_gift(
+    gift: Form<RevertGift>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<RawJson<String>, AppError> {
+    WebServer::revert_gift(gift, current_user.0, &*conn).await
+}
+
+#[post("/gift/reorder", data = "<order>")]
+async fn reorder_gifts(
+    order: Form<ReorderGifts>,
+    current_user: CurrentUser,
+    conn: Storage,
+) -> Result<RawJson<String>, AppError> {
+    WebServer::reorder_gifts(order, current_user.0, &*conn).await
+}
+
+#[post("/importgiftlist", data = "<gifts>")]
+async fn import_gift_list(
+    gifts: Form<ImportGiftList>,
+    current_user: CurrentUser,
+    config: &State<AppConfig>,
+    conn: Storage,
+) -> Result<String, AppError> {
+    WebServer::import_gift_list(gifts, current_user.0, config, &*conn).await
+}
+
+fn rocket(figment: Figment) -> Rocket<Build> {
+    let rocket = rocket::custom(figment)
         .mount(
             "/",
             routes![
                 index,
                 login,
+                logout,
+                logout_everywhere,
+                send_login_link,
                 user_page,
+                trash_page,
+                restore_list,
+                restore_gift,
+                admin_page,
+                admin_modify_user,
+                admin_reset_login,
+                admin_delete_user,
+                admin_backup,
                 list_page,
                 list_user_page,
                 create_list_page,
@@ -177,12 +437,241 @@ fn rocket() -> _ {
                 delete_list,
                 claim_gift,
                 unclaim_gift,
-                modify_item_list,
+                start_group_gift,
+                cancel_group_gift,
+                pledge,
+                mark_purchased,
+                add_gift,
+                update_gift,
+                delete_gift,
+                gift_history_page,
+                revert_gift,
+                reorder_gifts,
+                import_gift_list,
             ],
         )
         .mount("/", FileServer::from(relative!("static")))
-        .register("/", catchers![internal_error, access_denied, default_error])
+        .register(
+            "/",
+            catchers![internal_error, access_denied, unauthorized, default_error],
+        )
         .attach(Template::fairing())
-        .attach(DbConn::fairing())
-        .attach(AdHoc::on_ignite("Rusqlite Init", DbConn::init_db))
+        .attach(AdHoc::config::<AppConfig>())
+        .attach(Backups::lock());
+    Storage::attach(rocket)
+        .attach(AdHoc::try_on_ignite(
+            "First Admin Setup",
+            WebServer::setup_first_admin,
+        ))
+        .attach(Backups::schedule())
+        .attach(Trash::schedule())
+}
+
+#[rocket::main]
+// Boxed since rocket::Error is large enough for clippy to object
+async fn main() -> Result<(), Box<rocket::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("admin") {
+        let figment = Config::figment().merge(("log_level", LogLevel::Off));
+        if args.get(1).map(String::as_str) == Some("restore") {
+            if let Err(e) = Cli::restore(&args[2..], &figment) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        let rocket = Cli::rocket(figment).ignite().await.map_err(Box::new)?;
+        if let Err(e) = Cli::run(&args[1..], &rocket).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    rocket(Config::figment())
+        .launch()
+        .await
+        .map(|_| ())
+        .map_err(Box::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::fairing::AdHoc;
+    use rocket::http::{ContentType, Cookie, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket_dyn_templates::Template;
+    use uuid::Uuid;
+
+    use crate::data::{AppConfig, User};
+    use crate::repository::Storage;
+    use crate::webserver::WebServer;
+
+    // One pooled connection, so the in-memory database lives as long as the test
+    async fn client() -> Client {
+        let figment = rocket::Config::figment()
+            .merge(("databases.sqlite_logs.url", ":memory:"))
+            .merge(("databases.sqlite_logs.pool_size", 1));
+        let rocket = rocket::custom(figment).mount(
+            "/",
+            routes![
+                super::admin_page,
+                super::admin_modify_user,
+                super::admin_delete_user
+            ],
+        );
+        let rocket = Storage::attach(
+            rocket
+                .attach(Template::fairing())
+                .attach(AdHoc::config::<AppConfig>()),
+        );
+        Client::tracked(rocket).await.expect("test server")
+    }
+
+    // Returns the user's uuid and a cookie for a session of theirs
+    async fn sign_in(client: &Client, name: &str, is_admin: bool) -> (String, Cookie<'static>) {
+        let conn = Storage::get_one(client.rocket())
+            .await
+            .expect("storage mounted");
+        let uuid = Uuid::new_v4().to_string();
+        conn.create_user(User {
+            uuid: uuid.to_owned(),
+            email: format!("{}@example.com", uuid),
+            name: name.to_string(),
+            can_create: true,
+            is_admin,
+            is_me: false,
+        })
+        .await
+        .unwrap();
+        let session = Uuid::new_v4().to_string();
+        conn.create_session(uuid.to_owned(), session.to_owned())
+            .await
+            .unwrap();
+        (uuid, Cookie::new(WebServer::USER_COOKIE, session))
+    }
+
+    async fn is_admin(client: &Client, uuid: &str) -> Option<bool> {
+        let conn = Storage::get_one(client.rocket())
+            .await
+            .expect("storage mounted");
+        conn.user_from_uuid(uuid.to_string(), "".to_string())
+            .await
+            .ok()
+            .map(|u| u.is_admin)
+    }
+
+    async fn post(client: &Client, path: &str, cookie: &Cookie<'static>, body: String) -> String {
+        client
+            .post(path)
+            .private_cookie(cookie.to_owned())
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap()
+    }
+
+    fn modify_form(uuid: &str, name: &str, is_admin: bool) -> String {
+        format!(
+            "uuid={}&name={}&email={}%40example.com&can_create=true&is_admin={}",
+            uuid, name, uuid, is_admin
+        )
+    }
+
+    #[rocket::async_test]
+    async fn only_admins_reach_the_admin_pages() {
+        let client = client().await;
+        let (user, cookie) = sign_in(&client, "Grace", false).await;
+
+        let response = client.get("/admin").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/admin")
+            .private_cookie(cookie.to_owned())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        for path in ["/admin/modifyuser", "/admin/deleteuser"] {
+            let response = client
+                .post(path)
+                .private_cookie(cookie.to_owned())
+                .header(ContentType::Form)
+                .body(modify_form(&user, "Grace", true))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+        assert_eq!(is_admin(&client, &user).await, Some(false));
+    }
+
+    #[rocket::async_test]
+    async fn admins_manage_users() {
+        let client = client().await;
+        let (_admin, cookie) = sign_in(&client, "Ada", true).await;
+        let (user, _) = sign_in(&client, "Grace", false).await;
+        let response = client
+            .get("/admin")
+            .private_cookie(cookie.to_owned())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let reply = post(
+            &client,
+            "/admin/modifyuser",
+            &cookie,
+            modify_form(&user, "Grace", true),
+        )
+        .await;
+        assert_eq!(reply, "Success!");
+        assert_eq!(is_admin(&client, &user).await, Some(true));
+
+        let reply = post(
+            &client,
+            "/admin/deleteuser",
+            &cookie,
+            format!("uuid={}", user),
+        )
+        .await;
+        assert_eq!(reply, "Success!");
+        assert_eq!(is_admin(&client, &user).await, None);
+    }
+
+    #[rocket::async_test]
+    async fn the_last_admin_stays() {
+        let client = client().await;
+        let (admin, cookie) = sign_in(&client, "Ada", true).await;
+
+        let reply = post(
+            &client,
+            "/admin/modifyuser",
+            &cookie,
+            modify_form(&admin, "Ada", false),
+        )
+        .await;
+        assert_eq!(reply, "There has to be at least one administrator");
+        let reply = post(
+            &client,
+            "/admin/deleteuser",
+            &cookie,
+            format!("uuid={}", admin),
+        )
+        .await;
+        assert_eq!(reply, "There has to be at least one administrator");
+        assert_eq!(is_admin(&client, &admin).await, Some(true));
+
+        // With another admin around, demoting one is fine
+        let (other, _) = sign_in(&client, "Grace", true).await;
+        let reply = post(
+            &client,
+            "/admin/modifyuser",
+            &cookie,
+            modify_form(&other, "Grace", false),
+        )
+        .await;
+        assert_eq!(reply, "Success!");
+        assert_eq!(is_admin(&client, &other).await, Some(false));
+    }
 }
@@ -0,0 +1,1175 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::{
+    AdminList, DeletedGift, DeletedList, Gift, GiftClaim, GiftDetails, GiftRevision, GroupGift,
+    List, ListMember, ListRole, Pledge, User,
+};
+use crate::error::AppError;
+use crate::repository::Repository;
+
+struct StoredList {
+    uuid: String,
+    name: String,
+    owner: String,
+    deleted: Option<i64>,
+}
+
+struct Membership {
+    list_uuid: String,
+    user_uuid: String,
+    role: ListRole,
+    version: i64,
+}
+
+struct StoredGift {
+    uuid: String,
+    owner: String,
+    list_uuid: String,
+    details: GiftDetails,
+    // Oldest first
+    claims: Vec<StoredClaim>,
+    group: Option<StoredGroup>,
+    alternate_to: Option<String>,
+    version: i64,
+    position: i64,
+    deleted: Option<i64>,
+    // Oldest first
+    revisions: Vec<StoredRevision>,
+}
+
+struct StoredClaim {
+    user_uuid: String,
+    quantity: i64,
+    version: i64,
+}
+
+struct StoredGroup {
+    organizer: String,
+    purchased: Option<i64>,
+    // Oldest first
+    pledges: Vec<StoredPledge>,
+}
+
+struct StoredPledge {
+    user_uuid: String,
+    amount: i64,
+}
+
+struct StoredRevision {
+    version: i64,
+    details: GiftDetails,
+    author: String,
+    created: i64,
+}
+
+struct LoginToken {
+    token: String,
+    user_uuid: String,
+    expires: i64,
+}
+
+struct Session {
+    token: String,
+    user_uuid: String,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    users: Vec<User>,
+    login_tokens: Vec<LoginToken>,
+    sessions: Vec<Session>,
+    lists: Vec<StoredList>,
+    members: Vec<Membership>,
+    gifts: Vec<StoredGift>,
+}
+
+// Keeps everything in process, with the same rules the SQLite schema enforces, so the
+// repository cases can check the two against each other
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+impl MemoryStore {
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl MemoryData {
+    fn user(&self, user_uuid: &str) -> Option<&User> {
+        self.users.iter().find(|u| u.uuid == user_uuid)
+    }
+
+    fn live_list(&self, list_uuid: &str) -> Option<&StoredList> {
+        self.lists
+            .iter()
+            .find(|l| l.uuid == list_uuid && l.deleted.is_none())
+    }
+
+    fn member(&mut self, list_uuid: &str, user_uuid: &str) -> Option<&mut Membership> {
+        self.members
+            .iter_mut()
+            .find(|m| m.list_uuid == list_uuid && m.user_uuid == user_uuid)
+    }
+
+    fn gift(&self, gift_uuid: &str, current_user_uuid: &str) -> Option<Gift> {
+        let stored = self
+            .gifts
+            .iter()
+            .find(|g| g.uuid == gift_uuid && g.deleted.is_none())?;
+        self.live_list(&stored.list_uuid)?;
+        let claims = stored
+            .claims
+            .iter()
+            .filter_map(|claim| {
+                let claimer = self.user(&claim.user_uuid)?;
+                Some(GiftClaim {
+                    user_uuid: claimer.uuid.to_owned(),
+                    name: claimer.name.to_owned(),
+                    is_me: claimer.uuid == current_user_uuid,
+                    quantity: claim.quantity,
+                })
+            })
+            .collect();
+        Some(Gift {
+            uuid: stored.uuid.to_owned(),
+            owner: stored.owner.to_owned(),
+            details: stored.details.to_owned(),
+            claims,
+            alternate_to_uuid: stored.alternate_to.to_owned(),
+            version: stored.version,
+        })
+    }
+
+    fn group_gift(&self, stored: &StoredGift, current_user_uuid: &str) -> Option<GroupGift> {
+        let group = stored.group.as_ref()?;
+        let organizer = self.user(&group.organizer)?;
+        let pledges = group
+            .pledges
+            .iter()
+            .filter_map(|pledge| {
+                let giver = self.user(&pledge.user_uuid)?;
+                Some(Pledge {
+                    user_uuid: giver.uuid.to_owned(),
+                    name: giver.name.to_owned(),
+                    is_me: giver.uuid == current_user_uuid,
+                    amount: pledge.amount,
+                })
+            })
+            .collect();
+        Some(GroupGift {
+            gift_uuid: stored.uuid.to_owned(),
+            organizer_uuid: organizer.uuid.to_owned(),
+            organizer_name: organizer.name.to_owned(),
+            organized_by_me: organizer.uuid == current_user_uuid,
+            purchased: group.purchased.is_some(),
+            pledges,
+        })
+    }
+
+    // Their pledges go and so do the group gifts they organize
+    fn release_groups<F: Fn(&StoredGift) -> bool>(&mut self, user_uuid: &str, affected: F) {
+        for gift in self.gifts.iter_mut().filter(|g| affected(g)) {
+            if gift
+                .group
+                .as_ref()
+                .is_some_and(|group| group.organizer == user_uuid)
+            {
+                gift.group = None;
+            }
+            if let Some(group) = gift.group.as_mut() {
+                group.pledges.retain(|p| p.user_uuid != user_uuid);
+            }
+        }
+    }
+
+    fn bump_wishlist_version(&mut self, list_uuid: &str, user_uuid: &str) -> Result<i64, AppError> {
+        let member = self
+            .member(list_uuid, user_uuid)
+            .ok_or(AppError::NotFound)?;
+        member.version += 1;
+        Ok(member.version)
+    }
+
+    fn insert_gift(&mut self, gift: &Gift, list_uuid: &str, version: i64) -> Result<(), AppError> {
+        if self.gifts.iter().any(|g| g.uuid == gift.uuid) {
+            return Err(AppError::Backend("gift already exists".to_string()));
+        }
+        let position = self
+            .gifts
+            .iter()
+            .filter(|g| g.list_uuid == list_uuid && g.owner == gift.owner)
+            .map(|g| g.position)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        self.gifts.push(StoredGift {
+            uuid: gift.uuid.to_owned(),
+            owner: gift.owner.to_owned(),
+            list_uuid: list_uuid.to_owned(),
+            details: gift.details.to_owned(),
+            claims: Vec::new(),
+            group: None,
+            alternate_to: gift.alternate_to_uuid.to_owned(),
+            version,
+            position,
+            deleted: None,
+            revisions: Vec::new(),
+        });
+        self.record_revision(gift, version);
+        Ok(())
+    }
+
+    // Saves that leave the details as they were, like an import, add nothing
+    fn record_revision(&mut self, gift: &Gift, version: i64) {
+        let stored = match self.gifts.iter_mut().find(|g| g.uuid == gift.uuid) {
+            Some(stored) => stored,
+            None => return,
+        };
+        if stored
+            .revisions
+            .last()
+            .is_some_and(|r| r.details == gift.details)
+        {
+            return;
+        }
+        stored.revisions.push(StoredRevision {
+            version,
+            details: gift.details.to_owned(),
+            author: gift.owner.to_owned(),
+            created: MemoryStore::now(),
+        });
+    }
+
+    fn revision(&self, gift_uuid: &str, revision: &StoredRevision) -> GiftRevision {
+        GiftRevision {
+            gift_uuid: gift_uuid.to_owned(),
+            version: revision.version,
+            details: revision.details.to_owned(),
+            author_name: self.user(&revision.author).map(|u| u.name.to_owned()),
+            created: revision.created,
+        }
+    }
+
+    // Alternates go in the trash with their gift, stamped the same so they come back with it
+    fn trash_gift(&mut self, gift_uuid: &str, deleted: i64) {
+        for gift in self.gifts.iter_mut() {
+            let in_group =
+                gift.uuid == gift_uuid || gift.alternate_to.as_deref() == Some(gift_uuid);
+            if in_group && gift.deleted.is_none() {
+                gift.deleted = Some(deleted);
+            }
+        }
+    }
+
+    // Alternates of a removed gift stand on their own, like ON DELETE SET NULL
+    fn remove_gifts<F: Fn(&StoredGift) -> bool>(&mut self, removed: F) {
+        let removed_uuids = self
+            .gifts
+            .iter()
+            .filter(|g| removed(g))
+            .map(|g| g.uuid.to_owned())
+            .collect::<Vec<_>>();
+        self.gifts.retain(|g| !removed_uuids.contains(&g.uuid));
+        for gift in self.gifts.iter_mut() {
+            if gift
+                .alternate_to
+                .as_ref()
+                .is_some_and(|alt| removed_uuids.contains(alt))
+            {
+                gift.alternate_to = None;
+            }
+        }
+    }
+
+    fn remove_list(&mut self, list_uuid: &str) {
+        self.lists.retain(|l| l.uuid != list_uuid);
+        self.members.retain(|m| m.list_uuid != list_uuid);
+        self.remove_gifts(|g| g.list_uuid == list_uuid);
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for MemoryStore {
+    async fn consume_login_token(&self, login_token: String) -> Result<String, AppError> {
+        let mut data = self.data();
+        let now = MemoryStore::now();
+        let index = data
+            .login_tokens
+            .iter()
+            .position(|t| t.token == login_token && t.expires > now)
+            .ok_or(AppError::NotFound)?;
+        Ok(data.login_tokens.remove(index).user_uuid)
+    }
+
+    async fn create_login_token(
+        &self,
+        user_uuid: String,
+        login_token: String,
+        lifetime_secs: i64,
+    ) -> Result<(), AppError> {
+        let mut data = self.data();
+        let now = MemoryStore::now();
+        data.login_tokens
+            .retain(|t| t.user_uuid != user_uuid && t.expires > now);
+        data.login_tokens.push(LoginToken {
+            token: login_token,
+            user_uuid,
+            expires: now + lifetime_secs,
+        });
+        Ok(())
+    }
+
+    async fn create_session(
+        &self,
+        user_uuid: String,
+        session_token: String,
+    ) -> Result<(), AppError> {
+        self.data().sessions.push(Session {
+            token: session_token,
+            user_uuid,
+        });
+        Ok(())
+    }
+
+    async fn user_uuid_from_session(&self, session_token: String) -> Result<String, AppError> {
+        self.data()
+            .sessions
+            .iter()
+            .find(|s| s.token == session_token)
+            .map(|s| s.user_uuid.to_owned())
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn delete_session(&self, session_token: String) -> Result<(), AppError> {
+        self.data().sessions.retain(|s| s.token != session_token);
+        Ok(())
+    }
+
+    async fn delete_sessions_of_user(&self, user_uuid: String) -> Result<(), AppError> {
+        self.data().sessions.retain(|s| s.user_uuid != user_uuid);
+        Ok(())
+    }
+
+    async fn user_count(&self) -> Result<i64, AppError> {
+        Ok(self.data().users.len() as i64)
+    }
+
+    async fn all_users(&self, current_user_uuid: String) -> Result<Vec<User>, AppError> {
+        let mut users = self
+            .data()
+            .users
+            .iter()
+            .map(|u| User {
+                is_me: u.uuid == current_user_uuid,
+                ..u.clone()
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(users)
+    }
+
+    async fn user_from_email(&self, email: String) -> Result<User, AppError> {
+        self.data()
+            .users
+            .iter()
+            .find(|u| u.email == email)
+            .cloned()
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn user_from_uuid(
+        &self,
+        user_uuid: String,
+        current_uuid: String,
+    ) -> Result<User, AppError> {
+        self.data()
+            .user(&user_uuid)
+            .map(|u| User {
+                is_me: u.uuid == current_uuid,
+                ..u.clone()
+            })
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn create_user(&self, user: User) -> Result<(), AppError> {
+        let mut data = self.data();
+        if data.user(&user.uuid).is_some() {
+            return Err(AppError::Backend("user already exists".to_string()));
+        }
+        data.users.push(User {
+            is_me: false,
+            ..user
+        });
+        Ok(())
+    }
+
+    async fn modify_user(&self, user: User) -> Result<(), AppError> {
+        if let Some(stored) = self.data().users.iter_mut().find(|u| u.uuid == user.uuid) {
+            stored.name = user.name;
+            stored.email = user.email;
+            stored.can_create = user.can_create;
+            stored.is_admin = user.is_admin;
+        }
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_uuid: String) -> Result<(), AppError> {
+        let mut data = self.data();
+        // The co-owner who joined first takes over each list they created
+        let heirs = data
+            .lists
+            .iter()
+            .filter(|l| l.owner == user_uuid)
+            .map(|l| {
+                let heir = data
+                    .members
+                    .iter()
+                    .find(|m| {
+                        m.list_uuid == l.uuid
+                            && m.user_uuid != user_uuid
+                            && m.role == ListRole::CoOwner
+                    })
+                    .map(|m| m.user_uuid.to_owned());
+                (l.uuid.to_owned(), heir)
+            })
+            .collect::<Vec<_>>();
+        let mut blocked = data
+            .lists
+            .iter()
+            .filter(|l| {
+                l.deleted.is_none()
+                    && heirs.contains(&(l.uuid.to_owned(), None))
+                    && data
+                        .members
+                        .iter()
+                        .any(|m| m.list_uuid == l.uuid && m.user_uuid != user_uuid)
+            })
+            .map(|l| l.name.to_owned())
+            .collect::<Vec<_>>();
+        if !blocked.is_empty() {
+            blocked.sort();
+            return Err(AppError::only_co_owner(&blocked));
+        }
+        for (list_uuid, heir) in heirs {
+            match heir {
+                Some(heir) => {
+                    if let Some(list) = data.lists.iter_mut().find(|l| l.uuid == list_uuid) {
+                        list.owner = heir;
+                    }
+                }
+                None => data.remove_list(&list_uuid),
+            }
+        }
+        data.members.retain(|m| m.user_uuid != user_uuid);
+        data.remove_gifts(|g| g.owner == user_uuid);
+        for gift in data.gifts.iter_mut() {
+            gift.claims.retain(|c| c.user_uuid != user_uuid);
+        }
+        data.release_groups(&user_uuid, |_| true);
+        data.login_tokens.retain(|t| t.user_uuid != user_uuid);
+        data.sessions.retain(|s| s.user_uuid != user_uuid);
+        data.users.retain(|u| u.uuid != user_uuid);
+        Ok(())
+    }
+
+    async fn lists_of_user(&self, user_uuid: String) -> Result<Vec<List>, AppError> {
+        let data = self.data();
+        Ok(data
+            .members
+            .iter()
+            .filter(|m| m.user_uuid == user_uuid)
+            .filter_map(|m| {
+                let list = data.live_list(&m.list_uuid)?;
+                Some(List {
+                    uuid: list.uuid.to_owned(),
+                    name: list.name.to_owned(),
+                    owner: list.owner.to_owned(),
+                    my_role: Some(m.role),
+                    can_manage: m.role.can_manage(),
+                })
+            })
+            .collect())
+    }
+
+    async fn all_lists(&self) -> Result<Vec<AdminList>, AppError> {
+        let data = self.data();
+        let mut lists = data
+            .lists
+            .iter()
+            .filter(|l| l.deleted.is_none())
+            .map(|l| AdminList {
+                uuid: l.uuid.to_owned(),
+                name: l.name.to_owned(),
+                owner_name: data
+                    .user(&l.owner)
+                    .map(|u| u.name.to_owned())
+                    .unwrap_or_default(),
+                member_count: data
+                    .members
+                    .iter()
+                    .filter(|m| m.list_uuid == l.uuid)
+                    .count() as i64,
+            })
+            .collect::<Vec<_>>();
+        lists.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(lists)
+    }
+
+    async fn list_from_uuid(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<List, AppError> {
+        let data = self.data();
+        let list = data.live_list(&list_uuid).ok_or(AppError::NotFound)?;
+        let role = data
+            .members
+            .iter()
+            .find(|m| m.list_uuid == list_uuid && m.user_uuid == current_user_uuid)
+            .map(|m| m.role);
+        Ok(List {
+            uuid: list.uuid.to_owned(),
+            name: list.name.to_owned(),
+            owner: list.owner.to_owned(),
+            my_role: role,
+            can_manage: role.is_some_and(|r| r.can_manage()),
+        })
+    }
+
+    async fn create_list(&self, list: List) -> Result<(), AppError> {
+        let mut data = self.data();
+        if data.user(&list.owner).is_none() {
+            return Err(AppError::NotFound);
+        }
+        if data.lists.iter().any(|l| l.uuid == list.uuid) {
+            return Err(AppError::Backend("list already exists".to_string()));
+        }
+        data.members.push(Membership {
+            list_uuid: list.uuid.to_owned(),
+            user_uuid: list.owner.to_owned(),
+            role: ListRole::CoOwner,
+            version: 0,
+        });
+        data.lists.push(StoredList {
+            uuid: list.uuid,
+            name: list.name,
+            owner: list.owner,
+            deleted: None,
+        });
+        Ok(())
+    }
+
+    async fn modify_list(&self, list: List) -> Result<(), AppError> {
+        if let Some(stored) = self.data().lists.iter_mut().find(|l| l.uuid == list.uuid) {
+            stored.name = list.name;
+        }
+        Ok(())
+    }
+
+    async fn delete_list(&self, list_uuid: String) -> Result<(), AppError> {
+        if let Some(list) = self
+            .data()
+            .lists
+            .iter_mut()
+            .find(|l| l.uuid == list_uuid && l.deleted.is_none())
+        {
+            list.deleted = Some(MemoryStore::now());
+        }
+        Ok(())
+    }
+
+    async fn restore_list(&self, list_uuid: String) -> Result<bool, AppError> {
+        match self
+            .data()
+            .lists
+            .iter_mut()
+            .find(|l| l.uuid == list_uuid && l.deleted.is_some())
+        {
+            Some(list) => {
+                list.deleted = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn users_of_list(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<ListMember>, AppError> {
+        let data = self.data();
+        Ok(data
+            .members
+            .iter()
+            .filter(|m| m.list_uuid == list_uuid)
+            .filter_map(|m| {
+                let user = data.user(&m.user_uuid)?;
+                Some(ListMember {
+                    user: User {
+                        is_me: user.uuid == current_user_uuid,
+                        ..user.clone()
+                    },
+                    role: m.role,
+                    has_wishlist: m.role.has_wishlist(),
+                })
+            })
+            .collect())
+    }
+
+    async fn role_of_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<ListRole, AppError> {
+        let mut data = self.data();
+        data.live_list(&list_uuid).ok_or(AppError::NotFound)?;
+        data.member(&list_uuid, &user_uuid)
+            .map(|m| m.role)
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn add_user_to_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        let mut data = self.data();
+        if data.user(&user_uuid).is_none() || !data.lists.iter().any(|l| l.uuid == list_uuid) {
+            return Err(AppError::NotFound);
+        }
+        if data.member(&list_uuid, &user_uuid).is_some() {
+            return Err(AppError::Backend("already on the list".to_string()));
+        }
+        data.members.push(Membership {
+            list_uuid,
+            user_uuid,
+            role,
+            version: 0,
+        });
+        Ok(())
+    }
+
+    async fn set_list_role(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        if let Some(member) = self.data().member(&list_uuid, &user_uuid) {
+            member.role = role;
+        }
+        Ok(())
+    }
+
+    async fn remove_user_from_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+    ) -> Result<(), AppError> {
+        let mut data = self.data();
+        for gift in data.gifts.iter_mut().filter(|g| g.list_uuid == list_uuid) {
+            gift.claims.retain(|c| c.user_uuid != user_uuid);
+        }
+        data.release_groups(&user_uuid, |g| g.list_uuid == list_uuid);
+        data.members
+            .retain(|m| !(m.list_uuid == list_uuid && m.user_uuid == user_uuid));
+        data.remove_gifts(|g| g.list_uuid == list_uuid && g.owner == user_uuid);
+        Ok(())
+    }
+
+    async fn wishlist_version(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<i64, AppError> {
+        self.data()
+            .member(&list_uuid, &user_uuid)
+            .map(|m| m.version)
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn list_uuid_of_gift(&self, gift_uuid: String) -> Result<String, AppError> {
+        self.data()
+            .gifts
+            .iter()
+            .find(|g| g.uuid == gift_uuid)
+            .map(|g| g.list_uuid.to_owned())
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<Gift>, AppError> {
+        let data = self.data();
+        let mut stored = data
+            .gifts
+            .iter()
+            .filter(|g| g.list_uuid == list_uuid && g.owner == user_uuid && g.deleted.is_none())
+            .collect::<Vec<_>>();
+        stored.sort_by_key(|g| (g.alternate_to.is_some(), g.position));
+        Ok(stored
+            .iter()
+            .filter_map(|g| data.gift(&g.uuid, &current_user_uuid))
+            .collect())
+    }
+
+    async fn gift_from_uuid(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Gift, AppError> {
+        self.data()
+            .gift(&gift_uuid, &current_user_uuid)
+            .ok_or(AppError::NotFound)
+    }
+
+    async fn save_gift_list(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        removed: Vec<String>,
+        added: Vec<Gift>,
+        modified: Vec<Gift>,
+    ) -> Result<bool, AppError> {
+        let mut data = self.data();
+        let version = match data.member(&list_uuid, &user_uuid) {
+            Some(member) if member.version == base_version => base_version + 1,
+            _ => return Ok(false),
+        };
+        // Check everything up front so a failure leaves the wishlist as it was
+        for gift in &added {
+            if data.gifts.iter().any(|g| g.uuid == gift.uuid) {
+                return Err(AppError::Backend("gift already exists".to_string()));
+            }
+        }
+        data.member(&list_uuid, &user_uuid)
+            .ok_or(AppError::NotFound)?
+            .version = version;
+        let deleted = MemoryStore::now();
+        for gift_uuid in &removed {
+            data.trash_gift(gift_uuid, deleted);
+        }
+        for gift in &added {
+            data.insert_gift(gift, &list_uuid, version)?;
+        }
+        for gift in &modified {
+            if let Some(stored) = data.gifts.iter_mut().find(|g| g.uuid == gift.uuid) {
+                stored.details = gift.details.to_owned();
+                stored.version = version;
+            }
+            data.record_revision(gift, version);
+        }
+        Ok(true)
+    }
+
+    async fn add_gift(&self, list_uuid: String, gift: Gift) -> Result<i64, AppError> {
+        let mut data = self.data();
+        if data.member(&list_uuid, &gift.owner).is_none() {
+            return Err(AppError::NotFound);
+        }
+        if data.gifts.iter().any(|g| g.uuid == gift.uuid) {
+            return Err(AppError::Backend("gift already exists".to_string()));
+        }
+        let version = data.bump_wishlist_version(&list_uuid, &gift.owner)?;
+        data.insert_gift(&gift, &list_uuid, version)?;
+        Ok(version)
+    }
+
+    async fn update_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        let mut data = self.data();
+        let unchanged = data
+            .gifts
+            .iter()
+            .any(|g| g.uuid == gift.uuid && g.version == base_version && g.deleted.is_none());
+        if data.member(&list_uuid, &gift.owner).is_none() {
+            return Err(AppError::NotFound);
+        }
+        if !unchanged {
+            return Ok(None);
+        }
+        let version = data.bump_wishlist_version(&list_uuid, &gift.owner)?;
+        if let Some(stored) = data.gifts.iter_mut().find(|g| g.uuid == gift.uuid) {
+            stored.details = gift.details.to_owned();
+            stored.version = version;
+        }
+        data.record_revision(&gift, version);
+        Ok(Some(version))
+    }
+
+    async fn delete_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        let mut data = self.data();
+        let unchanged = data
+            .gifts
+            .iter()
+            .any(|g| g.uuid == gift.uuid && g.version == base_version && g.deleted.is_none());
+        if data.member(&list_uuid, &gift.owner).is_none() {
+            return Err(AppError::NotFound);
+        }
+        if !unchanged {
+            return Ok(None);
+        }
+        let version = data.bump_wishlist_version(&list_uuid, &gift.owner)?;
+        data.trash_gift(&gift.uuid, MemoryStore::now());
+        Ok(Some(version))
+    }
+
+    async fn restore_gift(
+        &self,
+        list_uuid: String,
+        gift_uuid: String,
+        user_uuid: String,
+    ) -> Result<Option<i64>, AppError> {
+        let mut data = self.data();
+        let deleted = match data
+            .gifts
+            .iter()
+            .find(|g| g.uuid == gift_uuid && g.owner == user_uuid)
+            .and_then(|g| g.deleted)
+        {
+            Some(deleted) => deleted,
+            None => return Ok(None),
+        };
+        let version = data.bump_wishlist_version(&list_uuid, &user_uuid)?;
+        for gift in data.gifts.iter_mut() {
+            let in_group = gift.uuid == gift_uuid || gift.alternate_to.as_ref() == Some(&gift_uuid);
+            if in_group && gift.deleted == Some(deleted) {
+                gift.deleted = None;
+                gift.version = version;
+            }
+        }
+        Ok(Some(version))
+    }
+
+    async fn deleted_lists_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedList>, AppError> {
+        let data = self.data();
+        let mut lists = data
+            .lists
+            .iter()
+            .filter(|l| l.deleted.is_some_and(|deleted| deleted > since))
+            .filter(|l| {
+                data.members.iter().any(|m| {
+                    m.list_uuid == l.uuid && m.user_uuid == user_uuid && m.role.can_manage()
+                })
+            })
+            .map(|l| DeletedList {
+                uuid: l.uuid.to_owned(),
+                name: l.name.to_owned(),
+                deleted: l.deleted.unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        lists.sort_by_key(|l| -l.deleted);
+        Ok(lists)
+    }
+
+    async fn deleted_gifts_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedGift>, AppError> {
+        let data = self.data();
+        let mut gifts = data
+            .gifts
+            .iter()
+            .filter(|g| g.owner == user_uuid && g.deleted.is_some_and(|deleted| deleted > since))
+            .filter(|g| match &g.alternate_to {
+                Some(main) => data
+                    .gifts
+                    .iter()
+                    .any(|m| &m.uuid == main && m.deleted.is_none()),
+                None => true,
+            })
+            .filter_map(|g| {
+                let list = data.live_list(&g.list_uuid)?;
+                Some(DeletedGift {
+                    uuid: g.uuid.to_owned(),
+                    list_uuid: list.uuid.to_owned(),
+                    list_name: list.name.to_owned(),
+                    title: g.details.title.to_owned(),
+                    url: g.details.url.to_owned(),
+                    comment: g.details.comment.to_owned(),
+                    deleted: g.deleted.unwrap_or_default(),
+                })
+            })
+            .collect::<Vec<_>>();
+        gifts.sort_by_key(|g| -g.deleted);
+        Ok(gifts)
+    }
+
+    async fn purge_trash(&self, before: i64) -> Result<usize, AppError> {
+        let mut data = self.data();
+        let expired = |deleted: Option<i64>| deleted.is_some_and(|deleted| deleted < before);
+        let lists = data
+            .lists
+            .iter()
+            .filter(|l| expired(l.deleted))
+            .map(|l| l.uuid.to_owned())
+            .collect::<Vec<_>>();
+        let gifts = data.gifts.iter().filter(|g| expired(g.deleted)).count();
+        for list_uuid in &lists {
+            data.remove_list(list_uuid);
+        }
+        data.remove_gifts(|g| expired(g.deleted));
+        Ok(lists.len() + gifts)
+    }
+
+    async fn reorder_gifts(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        order: Vec<String>,
+    ) -> Result<Option<i64>, AppError> {
+        let mut data = self.data();
+        let version = match data.member(&list_uuid, &user_uuid) {
+            Some(member) if member.version == base_version => {
+                member.version += 1;
+                member.version
+            }
+            _ => return Ok(None),
+        };
+        for gift in data.gifts.iter_mut() {
+            if let Some(position) = order.iter().position(|uuid| *uuid == gift.uuid) {
+                gift.position = position as i64 + 1;
+            }
+        }
+        Ok(Some(version))
+    }
+
+    async fn gift_revisions(&self, gift_uuid: String) -> Result<Vec<GiftRevision>, AppError> {
+        let data = self.data();
+        Ok(data
+            .gifts
+            .iter()
+            .filter(|g| g.uuid == gift_uuid)
+            .flat_map(|g| g.revisions.iter().rev())
+            .map(|r| data.revision(&gift_uuid, r))
+            .collect())
+    }
+
+    async fn claimed_revisions(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        claimer_uuid: String,
+    ) -> Result<Vec<GiftRevision>, AppError> {
+        let data = self.data();
+        Ok(data
+            .gifts
+            .iter()
+            .filter(|g| g.list_uuid == list_uuid && g.owner == user_uuid && g.deleted.is_none())
+            .filter_map(|g| {
+                // Coming back from the trash stamps a gift without a new revision, so the claim
+                // was made on the latest one at or before the version it was claimed at
+                let claim = g.claims.iter().find(|c| c.user_uuid == claimer_uuid)?;
+                g.revisions
+                    .iter()
+                    .rev()
+                    .find(|r| r.version <= claim.version)
+                    .map(|r| data.revision(&g.uuid, r))
+            })
+            .collect())
+    }
+
+    async fn claim_gift(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        quantity: i64,
+    ) -> Result<Option<i64>, AppError> {
+        let mut data = self.data();
+        let gift = match data
+            .gifts
+            .iter_mut()
+            .find(|g| g.uuid == gift_uuid && g.deleted.is_none())
+        {
+            Some(gift) if gift.group.is_none() => gift,
+            _ => return Ok(Some(0)),
+        };
+        let others: i64 = gift
+            .claims
+            .iter()
+            .filter(|c| c.user_uuid != user_uuid)
+            .map(|c| c.quantity)
+            .sum();
+        let left = gift.details.quantity - others;
+        if quantity > left {
+            return Ok(Some(left.max(0)));
+        }
+        let version = gift.version;
+        match gift.claims.iter_mut().find(|c| c.user_uuid == user_uuid) {
+            Some(claim) => {
+                claim.quantity = quantity;
+                claim.version = version;
+            }
+            None => gift.claims.push(StoredClaim {
+                user_uuid,
+                quantity,
+                version,
+            }),
+        }
+        Ok(None)
+    }
+
+    async fn unclaim_gift(&self, gift_uuid: String, user_uuid: String) -> Result<bool, AppError> {
+        let mut data = self.data();
+        match data.gifts.iter_mut().find(|g| g.uuid == gift_uuid) {
+            Some(gift) => {
+                let before = gift.claims.len();
+                gift.claims.retain(|c| c.user_uuid != user_uuid);
+                Ok(gift.claims.len() < before)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn group_gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<GroupGift>, AppError> {
+        let data = self.data();
+        Ok(data
+            .gifts
+            .iter()
+            .filter(|g| g.list_uuid == list_uuid && g.owner == user_uuid)
+            .filter_map(|g| data.group_gift(g, &current_user_uuid))
+            .collect())
+    }
+
+    async fn group_gift(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Option<GroupGift>, AppError> {
+        let data = self.data();
+        Ok(data
+            .gifts
+            .iter()
+            .find(|g| g.uuid == gift_uuid)
+            .and_then(|g| data.group_gift(g, &current_user_uuid)))
+    }
+
+    async fn start_group_gift(
+        &self,
+        gift_uuid: String,
+        organizer_uuid: String,
+    ) -> Result<bool, AppError> {
+        let mut data = self.data();
+        match data.gifts.iter_mut().find(|g| g.uuid == gift_uuid) {
+            Some(gift) if gift.group.is_none() && gift.claims.is_empty() => {
+                gift.group = Some(StoredGroup {
+                    organizer: organizer_uuid,
+                    purchased: None,
+                    pledges: Vec::new(),
+                });
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    async fn cancel_group_gift(&self, gift_uuid: String) -> Result<(), AppError> {
+        if let Some(gift) = self.data().gifts.iter_mut().find(|g| g.uuid == gift_uuid) {
+            gift.group = None;
+        }
+        Ok(())
+    }
+
+    async fn pledge(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        amount: i64,
+    ) -> Result<bool, AppError> {
+        let mut data = self.data();
+        let group = match data
+            .gifts
+            .iter_mut()
+            .find(|g| g.uuid == gift_uuid)
+            .and_then(|g| g.group.as_mut())
+        {
+            Some(group) if group.purchased.is_none() => group,
+            _ => return Ok(false),
+        };
+        match group.pledges.iter_mut().find(|p| p.user_uuid == user_uuid) {
+            Some(_) if amount == 0 => group.pledges.retain(|p| p.user_uuid != user_uuid),
+            Some(pledge) => pledge.amount = amount,
+            None if amount == 0 => {}
+            None => group.pledges.push(StoredPledge { user_uuid, amount }),
+        }
+        Ok(true)
+    }
+
+    async fn set_group_gift_purchased(
+        &self,
+        gift_uuid: String,
+        purchased: bool,
+    ) -> Result<(), AppError> {
+        if let Some(group) = self
+            .data()
+            .gifts
+            .iter_mut()
+            .find(|g| g.uuid == gift_uuid)
+            .and_then(|g| g.group.as_mut())
+        {
+            group.purchased = if purchased {
+                Some(MemoryStore::now())
+            } else {
+                None
+            };
+        }
+        Ok(())
+    }
+
+    async fn backup(&self, _path: PathBuf) -> Result<(), AppError> {
+        Err(AppError::Validation(
+            "Memory storage has nothing on disk to back up".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStore;
+    use crate::repository::Repository;
+    use crate::repository_tests::repository_cases;
+
+    async fn storage() -> ((), Box<dyn Repository>) {
+        ((), Box::new(MemoryStore::default()))
+    }
+
+    repository_cases!(storage());
+}
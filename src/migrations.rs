@@ -0,0 +1,1287 @@
+use rocket::{Build, Rocket};
+use rocket_sync_db_pools::rusqlite;
+use rusqlite::{ffi, params, Connection};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::ListRole;
+use crate::db::DbConn;
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+pub struct Migrations;
+
+impl Migrations {
+    const VERSION_TABLE: &'static str = "SCHEMA_VERSION";
+
+    // Append new steps to the end, never edit or reorder ones that have shipped
+    const STEPS: &'static [Migration] = &[
+        Migration {
+            version: 1,
+            name: "initial schema",
+            apply: Migrations::initial_schema,
+        },
+        Migration {
+            version: 2,
+            name: "login tokens and sessions",
+            apply: Migrations::login_tokens_and_sessions,
+        },
+        Migration {
+            version: 3,
+            name: "admin users",
+            apply: Migrations::admin_users,
+        },
+        Migration {
+            version: 4,
+            name: "list roles",
+            apply: Migrations::list_roles,
+        },
+        Migration {
+            version: 5,
+            name: "foreign keys",
+            apply: Migrations::foreign_keys,
+        },
+        Migration {
+            version: 6,
+            name: "edit versions",
+            apply: Migrations::edit_versions,
+        },
+        Migration {
+            version: 7,
+            name: "gift positions",
+            apply: Migrations::gift_positions,
+        },
+        Migration {
+            version: 8,
+            name: "nullable claims",
+            apply: Migrations::nullable_claims,
+        },
+        Migration {
+            version: 9,
+            name: "trash",
+            apply: Migrations::trash,
+        },
+        Migration {
+            version: 10,
+            name: "gift revisions",
+            apply: Migrations::gift_revisions,
+        },
+        Migration {
+            version: 11,
+            name: "gift details",
+            apply: Migrations::gift_details,
+        },
+        Migration {
+            version: 12,
+            name: "claims",
+            apply: Migrations::claims,
+        },
+        Migration {
+            version: 13,
+            name: "group gifts",
+            apply: Migrations::group_gifts,
+        },
+    ];
+
+    fn latest_version() -> i64 {
+        Migrations::STEPS.last().map_or(0, |m| m.version)
+    }
+
+    fn current_version(db: &Connection) -> rusqlite::Result<i64> {
+        db.query_row(
+            format!(
+                "SELECT COALESCE(MAX(version), 0) FROM {}",
+                Migrations::VERSION_TABLE
+            )
+            .as_str(),
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    pub async fn run_at_ignite(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+        let conn = DbConn::get_one(&rocket).await.expect("database mounted");
+        match conn.run(Migrations::migrate).await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                println!("Couldn't migrate the database: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+
+    pub fn migrate(db: &mut Connection) -> Result<(), String> {
+        Migrations::apply_steps(db, Migrations::latest_version())?;
+        let violations: i64 = db
+            .query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| {
+                row.get(0)
+            })
+            .map_err(|e| e.to_string())?;
+        if violations > 0 {
+            return Err(format!("{} rows break foreign key constraints", violations));
+        }
+        Ok(())
+    }
+
+    // Brings the schema up to `until`, tests stop short of the latest to look at old steps
+    fn apply_steps(db: &mut Connection, until: i64) -> Result<(), String> {
+        // Tables get rebuilt underneath their references, so checks wait until the end
+        db.execute_batch("PRAGMA foreign_keys = OFF")
+            .map_err(|e| e.to_string())?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    version     INTEGER PRIMARY KEY,
+                    name        TEXT NOT NULL,
+                    applied     INTEGER NOT NULL
+                    )",
+                Migrations::VERSION_TABLE
+            )
+            .as_str(),
+            [],
+        )
+        .map_err(|e| e.to_string())?;
+
+        let current = Migrations::current_version(db).map_err(|e| e.to_string())?;
+        if current > Migrations::latest_version() {
+            return Err(format!(
+                "the database is at schema version {} but this build only knows up to {}, refusing to start",
+                current,
+                Migrations::latest_version()
+            ));
+        }
+
+        for step in Migrations::STEPS
+            .iter()
+            .filter(|m| m.version > current && m.version <= until)
+        {
+            // Each step lands together with its version row or not at all
+            let applied = db.transaction().and_then(|tx| {
+                (step.apply)(&tx)?;
+                tx.execute(
+                    format!(
+                        "INSERT INTO {} (version, name, applied) VALUES (?1, ?2, ?3)",
+                        Migrations::VERSION_TABLE
+                    )
+                    .as_str(),
+                    params![step.version, step.name, Migrations::now()],
+                )?;
+                tx.commit()
+            });
+            if let Err(e) = applied {
+                return Err(format!(
+                    "migration {} ({}) failed: {}",
+                    step.version, step.name, e
+                ));
+            }
+            println!(
+                "Applied database migration {} ({})",
+                step.version, step.name
+            );
+        }
+        Ok(())
+    }
+
+    // A backup can be swapped in if it's intact and from a schema this build knows, older ones
+    // get migrated forward afterwards
+    pub fn check_backup(db: &Connection) -> Result<i64, String> {
+        let integrity: String = db
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .map_err(|e| format!("couldn't read the backup ({})", e))?;
+        if integrity != "ok" {
+            return Err(format!("the backup is damaged ({})", integrity));
+        }
+        let version = Migrations::current_version(db).unwrap_or(0);
+        if version == 0 {
+            return Err(
+                "the backup has no schema version, so it isn't a gift database".to_string(),
+            );
+        }
+        if version > Migrations::latest_version() {
+            return Err(format!(
+                "the backup is at schema version {} but this build only knows up to {}",
+                version,
+                Migrations::latest_version()
+            ));
+        }
+        Ok(version)
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+        db.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+        )
+    }
+
+    // Databases from before migrations existed already have some of these, hence IF NOT EXISTS
+    fn initial_schema(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    user_uuid   TEXT PRIMARY KEY,
+                    auth_token  TEXT NOT NULL,
+                    email       TEXT NOT NULL,
+                    name        TEXT NOT NULL,
+                    can_create  BOOL NOT NULL
+                    )",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        if Migrations::has_column(db, DbConn::USER_TABLE, "auth_token")? {
+            db.execute(
+                format!(
+                    "CREATE INDEX IF NOT EXISTS tokens ON {}(auth_token)",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS emails ON {}(email)",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    list_uuid   TEXT PRIMARY KEY,
+                    name        TEXT NOT NULL,
+                    owner       TEXT NOT NULL
+                    )",
+                DbConn::LIST_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    list_uuid   TEXT,
+                    user_uuid   TEXT
+                    )",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS list_map_list ON {}(list_uuid)",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS list_map_user ON {}(user_uuid)",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    gift_uuid       TEXT PRIMARY KEY,
+                    user_uuid       TEXT NOT NULL,
+                    url             TEXT NOT NULL,
+                    comment         TEXT NOT NULL,
+                    claimed         BOOL NOT NULL,
+                    claimed_by      TEXT NOT NULL,
+                    alternate_to    TEXT
+                    )",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    list_uuid   TEXT,
+                    user_uuid   TEXT,
+                    gift_uuid   TEXT
+                    )",
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS item_map_list ON {}(list_uuid)",
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Login tokens used to live forever in USERS.auth_token
+    fn login_tokens_and_sessions(db: &Connection) -> rusqlite::Result<()> {
+        if Migrations::has_column(db, DbConn::USER_TABLE, "auth_token")? {
+            db.execute("DROP INDEX IF EXISTS tokens", [])?;
+            db.execute(
+                format!("ALTER TABLE {} DROP COLUMN auth_token", DbConn::USER_TABLE).as_str(),
+                [],
+            )?;
+        }
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    token       TEXT PRIMARY KEY,
+                    user_uuid   TEXT NOT NULL,
+                    expires     INTEGER NOT NULL
+                    )",
+                DbConn::LOGIN_TOKEN_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS login_token_user ON {}(user_uuid)",
+                DbConn::LOGIN_TOKEN_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    session_token   TEXT PRIMARY KEY,
+                    user_uuid       TEXT NOT NULL,
+                    created         INTEGER NOT NULL
+                    )",
+                DbConn::SESSION_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS session_user ON {}(user_uuid)",
+                DbConn::SESSION_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn admin_users(db: &Connection) -> rusqlite::Result<()> {
+        if !Migrations::has_column(db, DbConn::USER_TABLE, "is_admin")? {
+            db.execute(
+                format!(
+                    "ALTER TABLE {} ADD COLUMN is_admin BOOL NOT NULL DEFAULT 0",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    // List owners used to be the only members with any say
+    fn list_roles(db: &Connection) -> rusqlite::Result<()> {
+        if Migrations::has_column(db, DbConn::LIST_TO_USER_TABLE, "role")? {
+            return Ok(());
+        }
+        db.execute(
+            format!(
+                "ALTER TABLE {} ADD COLUMN role TEXT NOT NULL DEFAULT 'member'",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "UPDATE {} SET role = ?1 WHERE EXISTS (\
+                SELECT 1 FROM {} l WHERE l.list_uuid = {}.list_uuid AND l.owner = {}.user_uuid)",
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::LIST_TABLE,
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            params![ListRole::CoOwner.as_str()],
+        )?;
+        Ok(())
+    }
+
+    // Swaps a table for one with the given definition, keeping every column they share
+    fn rebuild_table(
+        db: &Connection,
+        table: &str,
+        definition: &str,
+        columns: &str,
+    ) -> rusqlite::Result<()> {
+        Migrations::rebuild_table_from(db, table, definition, columns, columns)
+    }
+
+    // Same as rebuild_table, with the old rows read through the given expressions
+    fn rebuild_table_from(
+        db: &Connection,
+        table: &str,
+        definition: &str,
+        columns: &str,
+        select: &str,
+    ) -> rusqlite::Result<()> {
+        db.execute(
+            format!("CREATE TABLE {}_new ({})", table, definition).as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "INSERT INTO {}_new ({}) SELECT {} FROM {}",
+                table, columns, select, table
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(format!("DROP TABLE {}", table).as_str(), [])?;
+        db.execute(
+            format!("ALTER TABLE {}_new RENAME TO {}", table, table).as_str(),
+            [],
+        )?;
+        // Checks are off while tables get swapped, so anything repair_orphans missed stops here
+        let violations: i64 = db.query_row(
+            "SELECT COUNT(*) FROM pragma_foreign_key_check(?1)",
+            params![table],
+            |row| row.get(0),
+        )?;
+        if violations > 0 {
+            return Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+                Some(format!(
+                    "{} rows in {} break foreign key constraints",
+                    violations, table
+                )),
+            ));
+        }
+        Ok(())
+    }
+
+    // Gifts live and die with their place on a list
+    fn create_list_item_trigger(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            format!(
+                "CREATE TRIGGER IF NOT EXISTS list_item_removed AFTER DELETE ON {} \
+                BEGIN DELETE FROM {} WHERE gift_uuid = OLD.gift_uuid; END",
+                DbConn::LIST_TO_GIFT_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Deleting lists used to fail halfway and leave their gifts behind
+    fn repair_orphans(db: &Connection) -> rusqlite::Result<usize> {
+        let mut repaired = 0;
+        // Nothing kept people from being added to a list twice, or a gift filed twice
+        for (table, key) in [
+            (DbConn::LIST_TO_USER_TABLE, "list_uuid, user_uuid"),
+            (DbConn::LIST_TO_GIFT_TABLE, "gift_uuid"),
+        ] {
+            repaired += db.execute(
+                format!(
+                    "DELETE FROM {} WHERE rowid NOT IN (SELECT MIN(rowid) FROM {} GROUP BY {})",
+                    table, table, key
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        repaired += db.execute(
+            format!(
+                "DELETE FROM {} WHERE NOT EXISTS (\
+                SELECT 1 FROM {} u WHERE u.user_uuid = {}.owner)",
+                DbConn::LIST_TABLE,
+                DbConn::USER_TABLE,
+                DbConn::LIST_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "DELETE FROM {} WHERE NOT EXISTS (\
+                SELECT 1 FROM {} l WHERE l.list_uuid = {}.list_uuid) OR NOT EXISTS (\
+                SELECT 1 FROM {} u WHERE u.user_uuid = {}.user_uuid)",
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::LIST_TABLE,
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::USER_TABLE,
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "DELETE FROM {} WHERE NOT EXISTS (\
+                SELECT 1 FROM {} u WHERE u.user_uuid = {}.user_uuid)",
+                DbConn::GIFT_TABLE,
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "DELETE FROM {} WHERE NOT EXISTS (\
+                SELECT 1 FROM {} m WHERE m.list_uuid = {}.list_uuid AND m.user_uuid = {}.user_uuid) \
+                OR NOT EXISTS (SELECT 1 FROM {} g WHERE g.gift_uuid = {}.gift_uuid)",
+                DbConn::LIST_TO_GIFT_TABLE,
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "DELETE FROM {} WHERE NOT EXISTS (\
+                SELECT 1 FROM {} l WHERE l.gift_uuid = {}.gift_uuid)",
+                DbConn::GIFT_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "UPDATE {} SET claimed = 0, claimed_by = 'None' \
+                WHERE claimed_by != 'None' AND NOT EXISTS (\
+                SELECT 1 FROM {} u WHERE u.user_uuid = {}.claimed_by)",
+                DbConn::GIFT_TABLE,
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        repaired += db.execute(
+            format!(
+                "UPDATE {} SET alternate_to = NULL \
+                WHERE alternate_to IS NOT NULL AND NOT EXISTS (\
+                SELECT 1 FROM {} g WHERE g.gift_uuid = {}.alternate_to)",
+                DbConn::GIFT_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        for table in [DbConn::LOGIN_TOKEN_TABLE, DbConn::SESSION_TABLE] {
+            repaired += db.execute(
+                format!(
+                    "DELETE FROM {} WHERE NOT EXISTS (\
+                    SELECT 1 FROM {} u WHERE u.user_uuid = {}.user_uuid)",
+                    table,
+                    DbConn::USER_TABLE,
+                    table
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(repaired)
+    }
+
+    fn foreign_keys(db: &Connection) -> rusqlite::Result<()> {
+        let repaired = Migrations::repair_orphans(db)?;
+        if repaired > 0 {
+            println!("Repaired {} orphaned rows", repaired);
+        }
+
+        Migrations::rebuild_table(
+            db,
+            DbConn::LIST_TABLE,
+            format!(
+                "list_uuid   TEXT PRIMARY KEY,
+                name        TEXT NOT NULL,
+                owner       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            "list_uuid, name, owner",
+        )?;
+        Migrations::rebuild_table(
+            db,
+            DbConn::LIST_TO_USER_TABLE,
+            format!(
+                "list_uuid   TEXT NOT NULL REFERENCES {}(list_uuid) ON DELETE CASCADE,
+                user_uuid   TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                role        TEXT NOT NULL DEFAULT 'member',
+                PRIMARY KEY (list_uuid, user_uuid)",
+                DbConn::LIST_TABLE,
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            "list_uuid, user_uuid, role",
+        )?;
+        Migrations::rebuild_table(
+            db,
+            DbConn::GIFT_TABLE,
+            format!(
+                "gift_uuid       TEXT PRIMARY KEY,
+                user_uuid       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                url             TEXT NOT NULL,
+                comment         TEXT NOT NULL,
+                claimed         BOOL NOT NULL,
+                claimed_by      TEXT NOT NULL,
+                alternate_to    TEXT REFERENCES {}(gift_uuid) ON DELETE SET NULL",
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            "gift_uuid, user_uuid, url, comment, claimed, claimed_by, alternate_to",
+        )?;
+        // Leaving a list takes your wishlist on it with you
+        Migrations::rebuild_table(
+            db,
+            DbConn::LIST_TO_GIFT_TABLE,
+            format!(
+                "list_uuid   TEXT NOT NULL,
+                user_uuid   TEXT NOT NULL,
+                gift_uuid   TEXT PRIMARY KEY REFERENCES {}(gift_uuid) ON DELETE CASCADE,
+                FOREIGN KEY (list_uuid, user_uuid) REFERENCES {}(list_uuid, user_uuid) ON DELETE CASCADE",
+                DbConn::GIFT_TABLE,
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            "list_uuid, user_uuid, gift_uuid",
+        )?;
+        Migrations::rebuild_table(
+            db,
+            DbConn::LOGIN_TOKEN_TABLE,
+            format!(
+                "token       TEXT PRIMARY KEY,
+                user_uuid   TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                expires     INTEGER NOT NULL",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            "token, user_uuid, expires",
+        )?;
+        Migrations::rebuild_table(
+            db,
+            DbConn::SESSION_TABLE,
+            format!(
+                "session_token   TEXT PRIMARY KEY,
+                user_uuid       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                created         INTEGER NOT NULL",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            "session_token, user_uuid, created",
+        )?;
+
+        Migrations::create_list_item_trigger(db)?;
+
+        // Dropping the old tables took their indexes with them
+        for (index, table, columns) in [
+            ("list_owner", DbConn::LIST_TABLE, "owner"),
+            ("list_map_user", DbConn::LIST_TO_USER_TABLE, "user_uuid"),
+            ("item_owner", DbConn::GIFT_TABLE, "user_uuid"),
+            ("item_alternate", DbConn::GIFT_TABLE, "alternate_to"),
+            (
+                "item_map_list",
+                DbConn::LIST_TO_GIFT_TABLE,
+                "list_uuid, user_uuid",
+            ),
+            ("login_token_user", DbConn::LOGIN_TOKEN_TABLE, "user_uuid"),
+            ("session_user", DbConn::SESSION_TABLE, "user_uuid"),
+        ] {
+            db.execute(
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {}({})",
+                    index, table, columns
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Every save of a wishlist bumps its version and stamps the gifts it wrote with it
+    fn edit_versions(db: &Connection) -> rusqlite::Result<()> {
+        for table in [DbConn::LIST_TO_USER_TABLE, DbConn::GIFT_TABLE] {
+            if !Migrations::has_column(db, table, "version")? {
+                db.execute(
+                    format!(
+                        "ALTER TABLE {} ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+                        table
+                    )
+                    .as_str(),
+                    [],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Existing gifts keep the order they were added in
+    fn gift_positions(db: &Connection) -> rusqlite::Result<()> {
+        if Migrations::has_column(db, DbConn::GIFT_TABLE, "position")? {
+            return Ok(());
+        }
+        db.execute(
+            format!(
+                "ALTER TABLE {} ADD COLUMN position INTEGER NOT NULL DEFAULT 0",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!("UPDATE {} SET position = rowid", DbConn::GIFT_TABLE).as_str(),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Unclaimed gifts used to hold the string 'None', now a claim is just a claimer or NULL
+    fn nullable_claims(db: &Connection) -> rusqlite::Result<()> {
+        if !Migrations::has_column(db, DbConn::GIFT_TABLE, "claimed")? {
+            return Ok(());
+        }
+        // The trigger would point at a missing table while ITEMS is swapped out
+        db.execute("DROP TRIGGER IF EXISTS list_item_removed", [])?;
+        Migrations::rebuild_table_from(
+            db,
+            DbConn::GIFT_TABLE,
+            format!(
+                "gift_uuid       TEXT PRIMARY KEY,
+                user_uuid       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                url             TEXT NOT NULL,
+                comment         TEXT NOT NULL,
+                claimed_by      TEXT REFERENCES {}(user_uuid) ON DELETE SET NULL,
+                alternate_to    TEXT REFERENCES {}(gift_uuid) ON DELETE SET NULL,
+                version         INTEGER NOT NULL DEFAULT 0,
+                position        INTEGER NOT NULL DEFAULT 0",
+                DbConn::USER_TABLE,
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            "gift_uuid, user_uuid, url, comment, claimed_by, alternate_to, version, position",
+            // The 'None' sentinel and claimers who are gone both become NULL
+            format!(
+                "gift_uuid, user_uuid, url, comment, \
+                CASE WHEN claimed AND EXISTS (\
+                    SELECT 1 FROM {} u WHERE u.user_uuid = {}.claimed_by) \
+                THEN claimed_by END, \
+                alternate_to, version, position",
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+        )?;
+        Migrations::create_list_item_trigger(db)?;
+        for (index, columns) in [
+            ("item_owner", "user_uuid"),
+            ("item_alternate", "alternate_to"),
+            ("item_claimer", "claimed_by"),
+        ] {
+            db.execute(
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {}({})",
+                    index,
+                    DbConn::GIFT_TABLE,
+                    columns
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Deleting used to be for good, now it stamps the row and the purge job finishes it later
+    fn trash(db: &Connection) -> rusqlite::Result<()> {
+        for (table, index) in [
+            (DbConn::LIST_TABLE, "list_deleted"),
+            (DbConn::GIFT_TABLE, "item_deleted"),
+        ] {
+            if !Migrations::has_column(db, table, "deleted")? {
+                db.execute(
+                    format!("ALTER TABLE {} ADD COLUMN deleted INTEGER", table).as_str(),
+                    [],
+                )?;
+            }
+            db.execute(
+                format!("CREATE INDEX IF NOT EXISTS {} ON {}(deleted)", index, table).as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Gifts used to be edited in place, so what they are now is the only revision there is. Claims
+    // already made count as made on it.
+    fn gift_revisions(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {}(gift_uuid) ON DELETE CASCADE,
+                    version     INTEGER NOT NULL,
+                    url         TEXT NOT NULL,
+                    comment     TEXT NOT NULL,
+                    author      TEXT REFERENCES {}(user_uuid) ON DELETE SET NULL,
+                    created     INTEGER NOT NULL,
+                    PRIMARY KEY (gift_uuid, version)
+                    )",
+                DbConn::GIFT_REVISION_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS item_revision_author ON {}(author)",
+                DbConn::GIFT_REVISION_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "INSERT OR IGNORE INTO {} (gift_uuid, version, url, comment, author, created) \
+                SELECT gift_uuid, version, url, comment, user_uuid, ?1 FROM {}",
+                DbConn::GIFT_REVISION_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            params![Migrations::now()],
+        )?;
+        if !Migrations::has_column(db, DbConn::GIFT_TABLE, "claimed_version")? {
+            db.execute(
+                format!(
+                    "ALTER TABLE {} ADD COLUMN claimed_version INTEGER",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        db.execute(
+            format!(
+                "UPDATE {} SET claimed_version = version \
+                WHERE claimed_by IS NOT NULL AND claimed_version IS NULL",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Everything went in the url and comment before, so existing gifts start out with the defaults
+    fn gift_details(db: &Connection) -> rusqlite::Result<()> {
+        for table in [DbConn::GIFT_TABLE, DbConn::GIFT_REVISION_TABLE] {
+            for (column, definition) in [
+                ("title", "TEXT NOT NULL DEFAULT ''"),
+                ("price", "INTEGER"),
+                ("currency", "TEXT NOT NULL DEFAULT ''"),
+                ("quantity", "INTEGER NOT NULL DEFAULT 1"),
+                ("priority", "TEXT NOT NULL DEFAULT 'medium'"),
+                ("notes", "TEXT NOT NULL DEFAULT ''"),
+            ] {
+                if !Migrations::has_column(db, table, column)? {
+                    db.execute(
+                        format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition)
+                            .as_str(),
+                        [],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A gift used to have one claimer who took all of it, now each giver claims a share. Claims
+    // already made take the whole gift.
+    fn claims(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {}(gift_uuid) ON DELETE CASCADE,
+                    user_uuid   TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                    quantity    INTEGER NOT NULL,
+                    version     INTEGER NOT NULL,
+                    created     INTEGER NOT NULL,
+                    PRIMARY KEY (gift_uuid, user_uuid)
+                    )",
+                DbConn::CLAIM_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE INDEX IF NOT EXISTS item_claim_user ON {}(user_uuid)",
+                DbConn::CLAIM_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        if !Migrations::has_column(db, DbConn::GIFT_TABLE, "claimed_by")? {
+            return Ok(());
+        }
+        db.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, quantity, version, created) \
+                SELECT gift_uuid, claimed_by, quantity, COALESCE(claimed_version, version), ?1 \
+                FROM {} WHERE claimed_by IS NOT NULL",
+                DbConn::CLAIM_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            params![Migrations::now()],
+        )?;
+        // The trigger would point at a missing table while ITEMS is swapped out
+        db.execute("DROP TRIGGER IF EXISTS list_item_removed", [])?;
+        Migrations::rebuild_table(
+            db,
+            DbConn::GIFT_TABLE,
+            format!(
+                "gift_uuid       TEXT PRIMARY KEY,
+                user_uuid       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                url             TEXT NOT NULL,
+                comment         TEXT NOT NULL,
+                alternate_to    TEXT REFERENCES {}(gift_uuid) ON DELETE SET NULL,
+                version         INTEGER NOT NULL DEFAULT 0,
+                position        INTEGER NOT NULL DEFAULT 0,
+                deleted         INTEGER,
+                title           TEXT NOT NULL DEFAULT '',
+                price           INTEGER,
+                currency        TEXT NOT NULL DEFAULT '',
+                quantity        INTEGER NOT NULL DEFAULT 1,
+                priority        TEXT NOT NULL DEFAULT 'medium',
+                notes           TEXT NOT NULL DEFAULT ''",
+                DbConn::USER_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            "gift_uuid, user_uuid, url, comment, alternate_to, version, position, deleted, \
+            title, price, currency, quantity, priority, notes",
+        )?;
+        Migrations::create_list_item_trigger(db)?;
+        for (index, columns) in [
+            ("item_owner", "user_uuid"),
+            ("item_alternate", "alternate_to"),
+            ("item_deleted", "deleted"),
+        ] {
+            db.execute(
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {}({})",
+                    index,
+                    DbConn::GIFT_TABLE,
+                    columns
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn group_gifts(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    gift_uuid   TEXT PRIMARY KEY REFERENCES {}(gift_uuid) ON DELETE CASCADE,
+                    organizer   TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                    purchased   INTEGER,
+                    created     INTEGER NOT NULL
+                    )",
+                DbConn::GROUP_GIFT_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        db.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {}(gift_uuid) ON DELETE CASCADE,
+                    user_uuid   TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE,
+                    amount      INTEGER NOT NULL,
+                    created     INTEGER NOT NULL,
+                    PRIMARY KEY (gift_uuid, user_uuid)
+                    )",
+                DbConn::PLEDGE_TABLE,
+                DbConn::GROUP_GIFT_TABLE,
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            [],
+        )?;
+        for (index, table, column) in [
+            (
+                "group_gift_organizer",
+                DbConn::GROUP_GIFT_TABLE,
+                "organizer",
+            ),
+            ("group_pledge_user", DbConn::PLEDGE_TABLE, "user_uuid"),
+        ] {
+            db.execute(
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {}({})",
+                    index, table, column
+                )
+                .as_str(),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket_sync_db_pools::rusqlite;
+    use rusqlite::{params, Connection};
+
+    use super::Migrations;
+    use crate::db::DbConn;
+
+    // What a database looked like before migrations existed
+    fn baseline() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        Migrations::initial_schema(&db).unwrap();
+        db.execute_batch(
+            "INSERT INTO USERS VALUES ('ada', 'token-a', 'ada@example.com', 'Ada', 1);
+            INSERT INTO USERS VALUES ('grace', 'token-g', 'grace@example.com', 'Grace', 0);
+            INSERT INTO LISTS VALUES ('birthdays', 'Birthdays', 'ada');
+            INSERT INTO LIST_USERS VALUES ('birthdays', 'ada');
+            INSERT INTO LIST_USERS VALUES ('birthdays', 'grace');",
+        )
+        .unwrap();
+        db
+    }
+
+    fn role(db: &Connection, user_uuid: &str) -> String {
+        db.query_row(
+            format!(
+                "SELECT role FROM {} WHERE user_uuid = ?1",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            params![user_uuid],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn baseline_databases_upgrade_to_the_latest_version() {
+        let mut db = baseline();
+        Migrations::migrate(&mut db).unwrap();
+        assert_eq!(
+            Migrations::current_version(&db).unwrap(),
+            Migrations::latest_version()
+        );
+        let users: i64 = db
+            .query_row(
+                format!("SELECT COUNT(*) FROM {}", DbConn::USER_TABLE).as_str(),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(users, 2);
+        assert_eq!(role(&db, "ada"), "co_owner");
+        assert_eq!(role(&db, "grace"), "member");
+
+        // A second start has nothing left to do
+        Migrations::migrate(&mut db).unwrap();
+        let steps: i64 = db
+            .query_row(
+                format!("SELECT COUNT(*) FROM {}", Migrations::VERSION_TABLE).as_str(),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(steps, Migrations::STEPS.len() as i64);
+    }
+
+    #[test]
+    fn databases_from_a_newer_build_are_refused() {
+        let mut db = baseline();
+        Migrations::migrate(&mut db).unwrap();
+        db.execute(
+            format!(
+                "INSERT INTO {} (version, name, applied) VALUES (?1, 'from the future', 0)",
+                Migrations::VERSION_TABLE
+            )
+            .as_str(),
+            params![Migrations::latest_version() + 1],
+        )
+        .unwrap();
+        let error = Migrations::migrate(&mut db).unwrap_err();
+        assert!(error.contains("refusing to start"), "{}", error);
+    }
+
+    fn count(db: &Connection, table: &str) -> i64 {
+        db.query_row(
+            format!("SELECT COUNT(*) FROM {}", table).as_str(),
+            [],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn orphans_are_repaired_before_foreign_keys_go_on() {
+        let mut db = baseline();
+        db.execute_batch(
+            "INSERT INTO LISTS VALUES ('lost', 'Lost', 'nobody');
+            INSERT INTO LIST_USERS VALUES ('gone', 'ada');
+            INSERT INTO LIST_USERS VALUES ('birthdays', 'grace');
+            INSERT INTO ITEMS VALUES ('bike', 'ada', 'bike', '', 1, 'nobody', 'missing');
+            INSERT INTO ITEMS VALUES ('ghost', 'nobody', 'ghost', '', 0, 'None', NULL);
+            INSERT INTO ITEMS VALUES ('stray', 'ada', 'stray', '', 0, 'None', NULL);
+            INSERT INTO LIST_ITEMS VALUES ('birthdays', 'ada', 'bike');
+            INSERT INTO LIST_ITEMS VALUES ('birthdays', 'ada', 'bike');
+            INSERT INTO LIST_ITEMS VALUES ('birthdays', 'ada', 'missing');
+            INSERT INTO LIST_ITEMS VALUES ('lost', 'ada', 'ghost');",
+        )
+        .unwrap();
+        Migrations::migrate(&mut db).unwrap();
+
+        assert_eq!(count(&db, DbConn::LIST_TABLE), 1);
+        assert_eq!(count(&db, DbConn::LIST_TO_USER_TABLE), 2);
+        assert_eq!(count(&db, DbConn::LIST_TO_GIFT_TABLE), 1);
+        assert_eq!(count(&db, DbConn::GIFT_TABLE), 1);
+        // The gift that's left lost its claim by a missing user and its missing main gift
+        let alternate_to: Option<String> = db
+            .query_row(
+                "SELECT alternate_to FROM ITEMS WHERE gift_uuid = 'bike'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(alternate_to, None);
+        assert_eq!(count(&db, DbConn::CLAIM_TABLE), 0);
+    }
+
+    #[test]
+    fn rebuilds_stop_at_rows_that_break_foreign_keys() {
+        let db = baseline();
+        db.execute("INSERT INTO LISTS VALUES ('lost', 'Lost', 'nobody')", [])
+            .unwrap();
+        // Skipping the repair leaves the orphan for the rebuild to trip over
+        let rebuilt = Migrations::rebuild_table(
+            &db,
+            DbConn::LIST_TABLE,
+            format!(
+                "list_uuid   TEXT PRIMARY KEY,
+                name        TEXT NOT NULL,
+                owner       TEXT NOT NULL REFERENCES {}(user_uuid) ON DELETE CASCADE",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+            "list_uuid, name, owner",
+        );
+        assert!(rebuilt.is_err());
+    }
+
+    #[test]
+    fn unclaimed_gifts_lose_their_none_claimer() {
+        let mut db = baseline();
+        Migrations::apply_steps(&mut db, 7).unwrap();
+        db.execute_batch(
+            "INSERT INTO ITEMS (gift_uuid, user_uuid, url, comment, claimed, claimed_by) \
+                VALUES ('bike', 'ada', '', 'bike', 1, 'grace');
+            INSERT INTO ITEMS (gift_uuid, user_uuid, url, comment, claimed, claimed_by) \
+                VALUES ('kite', 'ada', '', 'kite', 0, 'None');
+            INSERT INTO ITEMS (gift_uuid, user_uuid, url, comment, claimed, claimed_by) \
+                VALUES ('sled', 'ada', '', 'sled', 0, 'grace');",
+        )
+        .unwrap();
+        Migrations::migrate(&mut db).unwrap();
+
+        let claims = db
+            .prepare(
+                format!(
+                    "SELECT gift_uuid, user_uuid FROM {} ORDER BY gift_uuid",
+                    DbConn::CLAIM_TABLE
+                )
+                .as_str(),
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, String)>, _>>()
+            .unwrap();
+        assert_eq!(claims, [("bike".to_string(), "grace".to_string())]);
+    }
+
+    #[test]
+    fn claimers_move_to_their_own_table() {
+        let mut db = baseline();
+        Migrations::apply_steps(&mut db, 11).unwrap();
+        db.execute_batch(
+            "INSERT INTO ITEMS (gift_uuid, user_uuid, url, comment, claimed_by, quantity) \
+                VALUES ('bike', 'ada', '', 'bike', 'grace', 2);
+            INSERT INTO ITEMS (gift_uuid, user_uuid, url, comment, claimed_by) \
+                VALUES ('kite', 'ada', '', 'kite', NULL);",
+        )
+        .unwrap();
+        Migrations::migrate(&mut db).unwrap();
+        assert!(!Migrations::has_column(&db, DbConn::GIFT_TABLE, "claimed_by").unwrap());
+
+        // A claim made before shares took the whole gift
+        let claims = db
+            .prepare(
+                format!(
+                    "SELECT gift_uuid, user_uuid, quantity FROM {} ORDER BY gift_uuid",
+                    DbConn::CLAIM_TABLE
+                )
+                .as_str(),
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, String, i64)>, _>>()
+            .unwrap();
+        assert_eq!(claims, [("bike".to_string(), "grace".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,1663 @@
+use rocket_sync_db_pools::database;
+use rocket_sync_db_pools::postgres::types::ToSql;
+use rocket_sync_db_pools::postgres::{self, Row, Transaction};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use crate::data::{
+    AdminList, DeletedGift, DeletedList, Gift, GiftClaim, GiftDetails, GiftPriority, GiftRevision,
+    GroupGift, List, ListMember, ListRole, Pledge, User,
+};
+use crate::db::DbConn;
+use crate::error::AppError;
+use crate::repository::Repository;
+
+// Same tables as SQLite, see DbConn for their names
+#[database("postgres")]
+pub struct PgConn(postgres::Client);
+
+impl PgConn {
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn user_from_row(row: &Row, current_user_uuid: &str) -> Result<User, postgres::Error> {
+        let user_uuid: String = row.try_get(0)?;
+        Ok(User {
+            is_me: current_user_uuid == user_uuid,
+            uuid: user_uuid,
+            email: row.try_get(1)?,
+            name: row.try_get(2)?,
+            can_create: row.try_get(3)?,
+            is_admin: row.try_get(4)?,
+        })
+    }
+
+    fn details_from_row(row: &Row, first: usize) -> Result<GiftDetails, postgres::Error> {
+        Ok(GiftDetails {
+            title: row.try_get(first)?,
+            url: row.try_get(first + 1)?,
+            comment: row.try_get(first + 2)?,
+            price: row.try_get(first + 3)?,
+            currency: row.try_get(first + 4)?,
+            quantity: row.try_get(first + 5)?,
+            priority: GiftPriority::from_str(row.try_get(first + 6)?),
+            notes: row.try_get(first + 7)?,
+        })
+    }
+
+    // Expects the columns in gift_columns order
+    fn gift_from_row(row: &Row) -> Result<Gift, postgres::Error> {
+        Ok(Gift {
+            uuid: row.try_get(0)?,
+            owner: row.try_get(1)?,
+            details: PgConn::details_from_row(row, 4)?,
+            alternate_to_uuid: row.try_get(2)?,
+            claims: Vec::new(),
+            version: row.try_get(3)?,
+        })
+    }
+
+    // The gift is r
+    fn gift_columns() -> String {
+        format!(
+            "r.gift_uuid, r.user_uuid, r.alternate_to, r.version, {}",
+            DbConn::detail_columns("r")
+        )
+    }
+
+    // Claims on the gifts the condition on c picks out, oldest first, keyed by gift
+    fn claims_where(
+        db: &mut postgres::Client,
+        condition: &str,
+        params: &[&(dyn ToSql + Sync)],
+        current_user_uuid: &str,
+    ) -> Result<HashMap<String, Vec<GiftClaim>>, postgres::Error> {
+        let mut claims: HashMap<String, Vec<GiftClaim>> = HashMap::new();
+        for row in db.query(
+            format!(
+                "SELECT c.gift_uuid, c.user_uuid, u.name, c.quantity FROM {} c \
+            INNER JOIN {} u ON u.user_uuid = c.user_uuid \
+            WHERE {} ORDER BY c.created, c.user_uuid",
+                DbConn::CLAIM_TABLE,
+                DbConn::USER_TABLE,
+                condition
+            )
+            .as_str(),
+            params,
+        )? {
+            let user_uuid: String = row.try_get(1)?;
+            claims.entry(row.try_get(0)?).or_default().push(GiftClaim {
+                is_me: user_uuid == current_user_uuid,
+                user_uuid,
+                name: row.try_get(2)?,
+                quantity: row.try_get(3)?,
+            });
+        }
+        Ok(claims)
+    }
+
+    // Group gifts whose uuid is among those the query picks out, with their pledges oldest first
+    fn group_gifts_where(
+        db: &mut postgres::Client,
+        gift_uuids: &str,
+        params: &[&(dyn ToSql + Sync)],
+        current_user_uuid: &str,
+    ) -> Result<Vec<GroupGift>, postgres::Error> {
+        let mut groups = Vec::new();
+        for row in db.query(
+            format!(
+                "SELECT g.gift_uuid, g.organizer, u.name, g.purchased FROM {} g \
+            INNER JOIN {} u ON u.user_uuid = g.organizer \
+            WHERE g.gift_uuid IN ({}) ORDER BY g.created, g.gift_uuid",
+                DbConn::GROUP_GIFT_TABLE,
+                DbConn::USER_TABLE,
+                gift_uuids
+            )
+            .as_str(),
+            params,
+        )? {
+            let organizer_uuid: String = row.try_get(1)?;
+            groups.push(GroupGift {
+                gift_uuid: row.try_get(0)?,
+                organized_by_me: organizer_uuid == current_user_uuid,
+                organizer_uuid,
+                organizer_name: row.try_get(2)?,
+                purchased: row.try_get::<usize, Option<i64>>(3)?.is_some(),
+                pledges: Vec::new(),
+            });
+        }
+        for row in db.query(
+            format!(
+                "SELECT p.gift_uuid, p.user_uuid, u.name, p.amount FROM {} p \
+            INNER JOIN {} u ON u.user_uuid = p.user_uuid \
+            WHERE p.gift_uuid IN ({}) ORDER BY p.created, p.user_uuid",
+                DbConn::PLEDGE_TABLE,
+                DbConn::USER_TABLE,
+                gift_uuids
+            )
+            .as_str(),
+            params,
+        )? {
+            let gift_uuid: String = row.try_get(0)?;
+            let user_uuid: String = row.try_get(1)?;
+            if let Some(group) = groups.iter_mut().find(|g| g.gift_uuid == gift_uuid) {
+                group.pledges.push(Pledge {
+                    is_me: user_uuid == current_user_uuid,
+                    user_uuid,
+                    name: row.try_get(2)?,
+                    amount: row.try_get(3)?,
+                });
+            }
+        }
+        Ok(groups)
+    }
+
+    fn bump_wishlist_version(
+        tx: &mut Transaction,
+        list_uuid: &str,
+        user_uuid: &str,
+    ) -> Result<i64, AppError> {
+        tx.query_opt(
+            format!(
+                "UPDATE {} SET version = version + 1 \
+            WHERE list_uuid = $1 AND user_uuid = $2 RETURNING version",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+            &[&list_uuid, &user_uuid],
+        )?
+        .map(|row| row.get(0))
+        .ok_or(AppError::NotFound)
+    }
+
+    fn insert_gift_row(
+        tx: &mut Transaction,
+        gift: &Gift,
+        list_uuid: &str,
+        version: i64,
+    ) -> Result<(), postgres::Error> {
+        tx.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, title, url, comment, price, currency, \
+            quantity, priority, notes, alternate_to, version, position) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, \
+            (SELECT COALESCE(MAX(i.position), 0) + 1 FROM {} i \
+            INNER JOIN {} l ON l.gift_uuid = i.gift_uuid \
+            WHERE l.list_uuid = $13 AND l.user_uuid = $2))",
+                DbConn::GIFT_TABLE,
+                DbConn::GIFT_TABLE,
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            &[
+                &gift.uuid,
+                &gift.owner,
+                &gift.details.title,
+                &gift.details.url,
+                &gift.details.comment,
+                &gift.details.price,
+                &gift.details.currency,
+                &gift.details.quantity,
+                &gift.details.priority.as_str(),
+                &gift.details.notes,
+                &gift.alternate_to_uuid,
+                &version,
+                &list_uuid,
+            ],
+        )?;
+        tx.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, list_uuid) VALUES ($1, $2, $3)",
+                DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+            &[&gift.uuid, &gift.owner, &list_uuid],
+        )?;
+        PgConn::record_revision(tx, gift, version)
+    }
+
+    // Saves that leave the details as they were, like an import, add nothing
+    fn record_revision(
+        tx: &mut Transaction,
+        gift: &Gift,
+        version: i64,
+    ) -> Result<(), postgres::Error> {
+        let latest = tx
+            .query_opt(
+                format!(
+                    "SELECT {} FROM {} r WHERE r.gift_uuid = $1 \
+                ORDER BY r.version DESC LIMIT 1",
+                    DbConn::detail_columns("r"),
+                    DbConn::GIFT_REVISION_TABLE
+                )
+                .as_str(),
+                &[&gift.uuid],
+            )?
+            .map(|row| PgConn::details_from_row(&row, 0))
+            .transpose()?;
+        if latest.as_ref() == Some(&gift.details) {
+            return Ok(());
+        }
+        let details = &gift.details;
+        tx.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, version, title, url, comment, price, currency, \
+            quantity, priority, notes, author, created) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+                DbConn::GIFT_REVISION_TABLE
+            )
+            .as_str(),
+            &[
+                &gift.uuid,
+                &version,
+                &details.title,
+                &details.url,
+                &details.comment,
+                &details.price,
+                &details.currency,
+                &details.quantity,
+                &details.priority.as_str(),
+                &details.notes,
+                &gift.owner,
+                &PgConn::now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn revision_from_row(row: &Row) -> Result<GiftRevision, postgres::Error> {
+        Ok(GiftRevision {
+            gift_uuid: row.try_get(0)?,
+            version: row.try_get(1)?,
+            details: PgConn::details_from_row(row, 2)?,
+            author_name: row.try_get(10)?,
+            created: row.try_get(11)?,
+        })
+    }
+
+    // Alternates go in the trash with their gift, stamped the same so they come back with it
+    fn trash_gift_row(
+        tx: &mut Transaction,
+        gift_uuid: &str,
+        deleted: i64,
+    ) -> Result<(), postgres::Error> {
+        tx.execute(
+            format!(
+                "UPDATE {} SET deleted = $2 \
+            WHERE (gift_uuid = $1 OR alternate_to = $1) AND deleted IS NULL",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            &[&gift_uuid, &deleted],
+        )?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for PgConn {
+    async fn consume_login_token(&self, login_token: String) -> Result<String, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "DELETE FROM {} WHERE token = $1 AND expires > $2 RETURNING user_uuid",
+                    DbConn::LOGIN_TOKEN_TABLE
+                )
+                .as_str(),
+                &[&login_token, &PgConn::now()],
+            )?
+            .map(|row| row.get(0))
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn create_login_token(
+        &self,
+        user_uuid: String,
+        login_token: String,
+        lifetime_secs: i64,
+    ) -> Result<(), AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            // Issuing a new link invalidates any previous ones for this user
+            tx.execute(
+                format!(
+                    "DELETE FROM {} WHERE user_uuid = $1 OR expires <= $2",
+                    DbConn::LOGIN_TOKEN_TABLE
+                )
+                .as_str(),
+                &[&user_uuid, &PgConn::now()],
+            )?;
+            tx.execute(
+                format!(
+                    "INSERT INTO {} (token, user_uuid, expires) VALUES ($1, $2, $3)",
+                    DbConn::LOGIN_TOKEN_TABLE
+                )
+                .as_str(),
+                &[&login_token, &user_uuid, &(PgConn::now() + lifetime_secs)],
+            )?;
+            tx.commit()
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn create_session(
+        &self,
+        user_uuid: String,
+        session_token: String,
+    ) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "INSERT INTO {} (session_token, user_uuid, created) VALUES ($1, $2, $3)",
+                    DbConn::SESSION_TABLE
+                )
+                .as_str(),
+                &[&session_token, &user_uuid, &PgConn::now()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn user_uuid_from_session(&self, session_token: String) -> Result<String, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT user_uuid FROM {} WHERE session_token = $1",
+                    DbConn::SESSION_TABLE
+                )
+                .as_str(),
+                &[&session_token],
+            )?
+            .map(|row| row.get(0))
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_token: String) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "DELETE FROM {} WHERE session_token = $1",
+                    DbConn::SESSION_TABLE
+                )
+                .as_str(),
+                &[&session_token],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sessions_of_user(&self, user_uuid: String) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!("DELETE FROM {} WHERE user_uuid = $1", DbConn::SESSION_TABLE).as_str(),
+                &[&user_uuid],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn user_count(&self) -> Result<i64, AppError> {
+        self.run(move |db| {
+            db.query_one(
+                format!("SELECT COUNT(*) FROM {}", DbConn::USER_TABLE).as_str(),
+                &[],
+            )
+            .map(|row| row.get(0))
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn all_users(&self, current_user_uuid: String) -> Result<Vec<User>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT user_uuid, email, name, can_create, is_admin FROM {} ORDER BY name",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[],
+            )?
+            .iter()
+            .map(|row| PgConn::user_from_row(row, &current_user_uuid))
+            .collect::<Result<Vec<User>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn user_from_email(&self, email: String) -> Result<User, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT user_uuid, email, name, can_create, is_admin FROM {} WHERE email = $1",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[&email],
+            )?
+            .map(|row| PgConn::user_from_row(&row, ""))
+            .transpose()?
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn user_from_uuid(
+        &self,
+        user_uuid: String,
+        current_uuid: String,
+    ) -> Result<User, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT user_uuid, email, name, can_create, is_admin FROM {} WHERE user_uuid = $1",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[&user_uuid],
+            )?
+            .map(|row| PgConn::user_from_row(&row, &current_uuid))
+            .transpose()?
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn create_user(&self, user: User) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "INSERT INTO {} (user_uuid, email, name, can_create, is_admin) \
+                VALUES ($1, $2, $3, $4, $5)",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[
+                    &user.uuid,
+                    &user.email,
+                    &user.name,
+                    &user.can_create,
+                    &user.is_admin,
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn modify_user(&self, user: User) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET name = $1, email = $2, can_create = $3, is_admin = $4 \
+                WHERE user_uuid = $5",
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[
+                    &user.name,
+                    &user.email,
+                    &user.can_create,
+                    &user.is_admin,
+                    &user.uuid,
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_uuid: String) -> Result<(), AppError> {
+        let blocked = self
+            .run(move |db| -> Result<_, postgres::Error> {
+                let mut tx = db.transaction()?;
+                // Another co-owner takes over each list they created
+                tx.execute(
+                    format!(
+                        "UPDATE {lists} l SET owner = m.user_uuid FROM (\
+                        SELECT DISTINCT ON (list_uuid) list_uuid, user_uuid FROM {members} \
+                        WHERE user_uuid != $1 AND role = $2 ORDER BY list_uuid, user_uuid) m \
+                    WHERE l.list_uuid = m.list_uuid AND l.owner = $1",
+                        lists = DbConn::LIST_TABLE,
+                        members = DbConn::LIST_TO_USER_TABLE
+                    )
+                    .as_str(),
+                    &[&user_uuid, &ListRole::CoOwner.as_str()],
+                )?;
+                let blocked = tx
+                    .query(
+                        format!(
+                            "SELECT l.name FROM {} l \
+                        WHERE l.owner = $1 AND l.deleted IS NULL AND EXISTS (\
+                            SELECT 1 FROM {} m \
+                            WHERE m.list_uuid = l.list_uuid AND m.user_uuid != $1) \
+                        ORDER BY l.name",
+                            DbConn::LIST_TABLE,
+                            DbConn::LIST_TO_USER_TABLE
+                        )
+                        .as_str(),
+                        &[&user_uuid],
+                    )?
+                    .iter()
+                    .map(|row| row.try_get(0))
+                    .collect::<Result<Vec<String>, _>>()?;
+                if !blocked.is_empty() {
+                    return Ok(blocked);
+                }
+                // Their remaining lists, memberships, gifts, claims, pledges, group gifts, tokens
+                // and sessions cascade
+                tx.execute(
+                    format!("DELETE FROM {} WHERE user_uuid = $1", DbConn::USER_TABLE).as_str(),
+                    &[&user_uuid],
+                )?;
+                tx.commit()?;
+                Ok(blocked)
+            })
+            .await?;
+        if !blocked.is_empty() {
+            return Err(AppError::only_co_owner(&blocked));
+        }
+        Ok(())
+    }
+
+    async fn lists_of_user(&self, user_uuid: String) -> Result<Vec<List>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT r.list_uuid, r.name, r.owner, l.role \
+                FROM {} l \
+                INNER JOIN {} r ON r.list_uuid = l.list_uuid \
+                WHERE l.user_uuid = $1 AND r.deleted IS NULL",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::LIST_TABLE,
+                )
+                .as_str(),
+                &[&user_uuid],
+            )?
+            .iter()
+            .map(|row| {
+                let role = ListRole::from_str(row.try_get(3)?);
+                Ok(List {
+                    uuid: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    owner: row.try_get(2)?,
+                    my_role: Some(role),
+                    can_manage: role.can_manage(),
+                })
+            })
+            .collect::<Result<Vec<List>, postgres::Error>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn all_lists(&self) -> Result<Vec<AdminList>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT l.list_uuid, l.name, u.name, \
+                (SELECT COUNT(*) FROM {} m WHERE m.list_uuid = l.list_uuid) \
+                FROM {} l \
+                LEFT JOIN {} u ON u.user_uuid = l.owner \
+                WHERE l.deleted IS NULL \
+                ORDER BY l.name",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::LIST_TABLE,
+                    DbConn::USER_TABLE,
+                )
+                .as_str(),
+                &[],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(AdminList {
+                    uuid: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    owner_name: row.try_get::<usize, Option<String>>(2)?.unwrap_or_default(),
+                    member_count: row.try_get(3)?,
+                })
+            })
+            .collect::<Result<Vec<AdminList>, postgres::Error>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn list_from_uuid(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<List, AppError> {
+        self.run(move |db| {
+            let row = db
+                .query_opt(
+                    format!(
+                        "SELECT l.list_uuid, l.name, l.owner, m.role \
+                    FROM {} l \
+                    LEFT JOIN {} m ON m.list_uuid = l.list_uuid AND m.user_uuid = $2 \
+                    WHERE l.list_uuid = $1 AND l.deleted IS NULL",
+                        DbConn::LIST_TABLE,
+                        DbConn::LIST_TO_USER_TABLE,
+                    )
+                    .as_str(),
+                    &[&list_uuid, &current_user_uuid],
+                )?
+                .ok_or(AppError::NotFound)?;
+            let role = row
+                .try_get::<usize, Option<&str>>(3)?
+                .map(ListRole::from_str);
+            Ok(List {
+                uuid: row.try_get(0)?,
+                name: row.try_get(1)?,
+                owner: row.try_get(2)?,
+                my_role: role,
+                can_manage: role.is_some_and(|r| r.can_manage()),
+            })
+        })
+        .await
+    }
+
+    async fn create_list(&self, list: List) -> Result<(), AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            tx.execute(
+                format!(
+                    "INSERT INTO {} (list_uuid, name, owner) VALUES ($1, $2, $3)",
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                &[&list.uuid, &list.name, &list.owner],
+            )?;
+            tx.execute(
+                format!(
+                    "INSERT INTO {} (list_uuid, user_uuid, role) VALUES ($1, $2, $3)",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&list.uuid, &list.owner, &ListRole::CoOwner.as_str()],
+            )?;
+            tx.commit()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn modify_list(&self, list: List) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET name = $1 WHERE list_uuid = $2",
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                &[&list.name, &list.uuid],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_list(&self, list_uuid: String) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET deleted = $2 WHERE list_uuid = $1 AND deleted IS NULL",
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &PgConn::now()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn restore_list(&self, list_uuid: String) -> Result<bool, AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET deleted = NULL WHERE list_uuid = $1 AND deleted IS NOT NULL",
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                &[&list_uuid],
+            )
+        })
+        .await
+        .map(|changed| changed > 0)
+        .map_err(AppError::from)
+    }
+
+    async fn users_of_list(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<ListMember>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT r.user_uuid, r.email, r.name, r.can_create, r.is_admin, l.role \
+                FROM {} l \
+                INNER JOIN {} r ON r.user_uuid = l.user_uuid \
+                WHERE l.list_uuid = $1",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::USER_TABLE,
+                )
+                .as_str(),
+                &[&list_uuid],
+            )?
+            .iter()
+            .map(|row| {
+                let role = ListRole::from_str(row.try_get(5)?);
+                Ok(ListMember {
+                    user: PgConn::user_from_row(row, &current_user_uuid)?,
+                    role,
+                    has_wishlist: role.has_wishlist(),
+                })
+            })
+            .collect::<Result<Vec<ListMember>, postgres::Error>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn role_of_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<ListRole, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT m.role FROM {} m \
+                INNER JOIN {} l ON l.list_uuid = m.list_uuid \
+                WHERE m.list_uuid = $1 AND m.user_uuid = $2 AND l.deleted IS NULL",
+                    DbConn::LIST_TO_USER_TABLE,
+                    DbConn::LIST_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid],
+            )?
+            .map(|row| ListRole::from_str(row.get(0)))
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn add_user_to_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "INSERT INTO {} (list_uuid, user_uuid, role) VALUES ($1, $2, $3)",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid, &role.as_str()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_list_role(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET role = $1 WHERE list_uuid = $2 AND user_uuid = $3",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&role.as_str(), &list_uuid, &user_uuid],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_user_from_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+    ) -> Result<(), AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            // Their claims and pledges on this list are released, the group gifts they organize
+            // there are called off and their wishlist goes with them
+            for (table, column) in [
+                (DbConn::CLAIM_TABLE, "user_uuid"),
+                (DbConn::PLEDGE_TABLE, "user_uuid"),
+                (DbConn::GROUP_GIFT_TABLE, "organizer"),
+            ] {
+                tx.execute(
+                    format!(
+                        "DELETE FROM {} WHERE {} = $1 AND gift_uuid IN (\
+                    SELECT gift_uuid FROM {} WHERE list_uuid = $2)",
+                        table,
+                        column,
+                        DbConn::LIST_TO_GIFT_TABLE
+                    )
+                    .as_str(),
+                    &[&user_uuid, &list_uuid],
+                )?;
+            }
+            tx.execute(
+                format!(
+                    "DELETE FROM {} WHERE user_uuid = $1 AND list_uuid = $2",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&user_uuid, &list_uuid],
+            )?;
+            tx.commit()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn wishlist_version(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<i64, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT version FROM {} WHERE list_uuid = $1 AND user_uuid = $2",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid],
+            )?
+            .map(|row| row.get(0))
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn list_uuid_of_gift(&self, gift_uuid: String) -> Result<String, AppError> {
+        self.run(move |db| {
+            db.query_opt(
+                format!(
+                    "SELECT list_uuid FROM {} WHERE gift_uuid = $1",
+                    DbConn::LIST_TO_GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )?
+            .map(|row| row.get(0))
+            .ok_or(AppError::NotFound)
+        })
+        .await
+    }
+
+    async fn gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<Gift>, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut gifts = db
+                .query(
+                    format!(
+                        "SELECT {} \
+                    FROM {} l \
+                    INNER JOIN {} r ON r.gift_uuid = l.gift_uuid \
+                    WHERE l.list_uuid = $1 AND l.user_uuid = $2 AND r.deleted IS NULL \
+                    ORDER BY r.alternate_to IS NOT NULL, r.position",
+                        PgConn::gift_columns(),
+                        DbConn::LIST_TO_GIFT_TABLE,
+                        DbConn::GIFT_TABLE,
+                    )
+                    .as_str(),
+                    &[&list_uuid, &user_uuid],
+                )?
+                .iter()
+                .map(PgConn::gift_from_row)
+                .collect::<Result<Vec<Gift>, _>>()?;
+            let mut claims = PgConn::claims_where(
+                db,
+                format!(
+                    "c.gift_uuid IN (SELECT gift_uuid FROM {} WHERE list_uuid = $1 AND user_uuid = $2)",
+                    DbConn::LIST_TO_GIFT_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid],
+                &current_user_uuid,
+            )?;
+            for gift in gifts.iter_mut() {
+                gift.claims = claims.remove(&gift.uuid).unwrap_or_default();
+            }
+            Ok(gifts)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn gift_from_uuid(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Gift, AppError> {
+        self.run(move |db| {
+            let mut gift = db
+                .query_opt(
+                    format!(
+                        "SELECT {} \
+                    FROM {} r \
+                    INNER JOIN {} m ON m.gift_uuid = r.gift_uuid \
+                    INNER JOIN {} s ON s.list_uuid = m.list_uuid \
+                    WHERE r.gift_uuid = $1 AND r.deleted IS NULL AND s.deleted IS NULL",
+                        PgConn::gift_columns(),
+                        DbConn::GIFT_TABLE,
+                        DbConn::LIST_TO_GIFT_TABLE,
+                        DbConn::LIST_TABLE,
+                    )
+                    .as_str(),
+                    &[&gift_uuid],
+                )?
+                .map(|row| PgConn::gift_from_row(&row))
+                .transpose()?
+                .ok_or(AppError::NotFound)?;
+            gift.claims =
+                PgConn::claims_where(db, "c.gift_uuid = $1", &[&gift_uuid], &current_user_uuid)?
+                    .remove(&gift_uuid)
+                    .unwrap_or_default();
+            Ok(gift)
+        })
+        .await
+    }
+
+    async fn save_gift_list(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        removed: Vec<String>,
+        added: Vec<Gift>,
+        modified: Vec<Gift>,
+    ) -> Result<bool, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            let version = base_version + 1;
+            let bumped = tx.execute(
+                format!(
+                    "UPDATE {} SET version = $4 \
+                WHERE list_uuid = $1 AND user_uuid = $2 AND version = $3",
+                    DbConn::LIST_TO_USER_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid, &base_version, &version],
+            )?;
+            if bumped == 0 {
+                return Ok(false);
+            }
+            let deleted = PgConn::now();
+            for gift_uuid in &removed {
+                PgConn::trash_gift_row(&mut tx, gift_uuid, deleted)?;
+            }
+            for gift in &added {
+                PgConn::insert_gift_row(&mut tx, gift, &list_uuid, version)?;
+            }
+            // Claims are left alone so an owner's save can't undo one made in the meantime
+            for gift in &modified {
+                tx.execute(
+                    format!(
+                        "UPDATE {} SET title = $1, url = $2, comment = $3, price = $4, \
+                    currency = $5, quantity = $6, priority = $7, notes = $8, version = $9 \
+                    WHERE gift_uuid = $10",
+                        DbConn::GIFT_TABLE
+                    )
+                    .as_str(),
+                    &[
+                        &gift.details.title,
+                        &gift.details.url,
+                        &gift.details.comment,
+                        &gift.details.price,
+                        &gift.details.currency,
+                        &gift.details.quantity,
+                        &gift.details.priority.as_str(),
+                        &gift.details.notes,
+                        &version,
+                        &gift.uuid,
+                    ],
+                )?;
+                PgConn::record_revision(&mut tx, gift, version)?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn add_gift(&self, list_uuid: String, gift: Gift) -> Result<i64, AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            let version = PgConn::bump_wishlist_version(&mut tx, &list_uuid, &gift.owner)?;
+            PgConn::insert_gift_row(&mut tx, &gift, &list_uuid, version)?;
+            tx.commit()?;
+            Ok(version)
+        })
+        .await
+    }
+
+    async fn update_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            let version = PgConn::bump_wishlist_version(&mut tx, &list_uuid, &gift.owner)?;
+            let changed = tx.execute(
+                format!(
+                    "UPDATE {} SET title = $1, url = $2, comment = $3, price = $4, \
+                currency = $5, quantity = $6, priority = $7, notes = $8, version = $9 \
+                WHERE gift_uuid = $10 AND version = $11 AND deleted IS NULL",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[
+                    &gift.details.title,
+                    &gift.details.url,
+                    &gift.details.comment,
+                    &gift.details.price,
+                    &gift.details.currency,
+                    &gift.details.quantity,
+                    &gift.details.priority.as_str(),
+                    &gift.details.notes,
+                    &version,
+                    &gift.uuid,
+                    &base_version,
+                ],
+            )?;
+            if changed == 0 {
+                return Ok(None);
+            }
+            PgConn::record_revision(&mut tx, &gift, version)?;
+            tx.commit()?;
+            Ok(Some(version))
+        })
+        .await
+    }
+
+    async fn delete_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            let version = PgConn::bump_wishlist_version(&mut tx, &list_uuid, &gift.owner)?;
+            let deleted = PgConn::now();
+            let changed = tx.execute(
+                format!(
+                    "UPDATE {} SET deleted = $3 \
+                WHERE gift_uuid = $1 AND version = $2 AND deleted IS NULL",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift.uuid, &base_version, &deleted],
+            )?;
+            if changed == 0 {
+                return Ok(None);
+            }
+            PgConn::trash_gift_row(&mut tx, &gift.uuid, deleted)?;
+            tx.commit()?;
+            Ok(Some(version))
+        })
+        .await
+    }
+
+    async fn reorder_gifts(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        order: Vec<String>,
+    ) -> Result<Option<i64>, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            let version = tx
+                .query_opt(
+                    format!(
+                        "UPDATE {} SET version = version + 1 \
+                    WHERE list_uuid = $1 AND user_uuid = $2 AND version = $3 RETURNING version",
+                        DbConn::LIST_TO_USER_TABLE
+                    )
+                    .as_str(),
+                    &[&list_uuid, &user_uuid, &base_version],
+                )?
+                .map(|row| row.get(0));
+            if version.is_none() {
+                return Ok(None);
+            }
+            for (position, gift_uuid) in order.iter().enumerate() {
+                tx.execute(
+                    format!(
+                        "UPDATE {} SET position = $1 WHERE gift_uuid = $2",
+                        DbConn::GIFT_TABLE
+                    )
+                    .as_str(),
+                    &[&(position as i64 + 1), gift_uuid],
+                )?;
+            }
+            tx.commit()?;
+            Ok(version)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn restore_gift(
+        &self,
+        list_uuid: String,
+        gift_uuid: String,
+        user_uuid: String,
+    ) -> Result<Option<i64>, AppError> {
+        self.run(move |db| {
+            let mut tx = db.transaction()?;
+            let deleted: i64 = match tx.query_opt(
+                format!(
+                    "SELECT deleted FROM {} \
+                WHERE gift_uuid = $1 AND user_uuid = $2 AND deleted IS NOT NULL",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &user_uuid],
+            )? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+            let version = PgConn::bump_wishlist_version(&mut tx, &list_uuid, &user_uuid)?;
+            // Stamped with the new version so open pages see it as added
+            tx.execute(
+                format!(
+                    "UPDATE {} SET deleted = NULL, version = $3 \
+                WHERE (gift_uuid = $1 OR alternate_to = $1) AND deleted = $2",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &deleted, &version],
+            )?;
+            tx.commit()?;
+            Ok(Some(version))
+        })
+        .await
+    }
+
+    async fn deleted_lists_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedList>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT l.list_uuid, l.name, l.deleted \
+                FROM {} l \
+                INNER JOIN {} m ON m.list_uuid = l.list_uuid \
+                WHERE m.user_uuid = $1 AND m.role = $2 AND l.deleted > $3 \
+                ORDER BY l.deleted DESC",
+                    DbConn::LIST_TABLE,
+                    DbConn::LIST_TO_USER_TABLE,
+                )
+                .as_str(),
+                &[&user_uuid, &ListRole::CoOwner.as_str(), &since],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(DeletedList {
+                    uuid: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    deleted: row.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<DeletedList>, postgres::Error>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn deleted_gifts_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedGift>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT i.gift_uuid, m.list_uuid, l.name, i.title, i.url, i.comment, i.deleted \
+                FROM {} i \
+                INNER JOIN {} m ON m.gift_uuid = i.gift_uuid \
+                INNER JOIN {} l ON l.list_uuid = m.list_uuid \
+                LEFT JOIN {} a ON a.gift_uuid = i.alternate_to \
+                WHERE i.user_uuid = $1 AND i.deleted > $2 \
+                AND l.deleted IS NULL AND a.deleted IS NULL \
+                ORDER BY i.deleted DESC",
+                    DbConn::GIFT_TABLE,
+                    DbConn::LIST_TO_GIFT_TABLE,
+                    DbConn::LIST_TABLE,
+                    DbConn::GIFT_TABLE,
+                )
+                .as_str(),
+                &[&user_uuid, &since],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(DeletedGift {
+                    uuid: row.try_get(0)?,
+                    list_uuid: row.try_get(1)?,
+                    list_name: row.try_get(2)?,
+                    title: row.try_get(3)?,
+                    url: row.try_get(4)?,
+                    comment: row.try_get(5)?,
+                    deleted: row.try_get(6)?,
+                })
+            })
+            .collect::<Result<Vec<DeletedGift>, postgres::Error>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn purge_trash(&self, before: i64) -> Result<usize, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            // Gifts on a purged list go with it through the foreign keys
+            let mut purged = 0;
+            for table in [DbConn::LIST_TABLE, DbConn::GIFT_TABLE] {
+                purged += tx.execute(
+                    format!("DELETE FROM {} WHERE deleted < $1", table).as_str(),
+                    &[&before],
+                )?;
+            }
+            tx.commit()?;
+            Ok(purged as usize)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn gift_revisions(&self, gift_uuid: String) -> Result<Vec<GiftRevision>, AppError> {
+        self.run(move |db| {
+            db.query(
+                format!(
+                    "SELECT r.gift_uuid, r.version, {}, u.name, r.created \
+                FROM {} r \
+                LEFT JOIN {} u ON u.user_uuid = r.author \
+                WHERE r.gift_uuid = $1 \
+                ORDER BY r.version DESC",
+                    DbConn::detail_columns("r"),
+                    DbConn::GIFT_REVISION_TABLE,
+                    DbConn::USER_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )?
+            .iter()
+            .map(PgConn::revision_from_row)
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn claimed_revisions(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        claimer_uuid: String,
+    ) -> Result<Vec<GiftRevision>, AppError> {
+        self.run(move |db| {
+            // Coming back from the trash stamps a gift without a new revision, so the claim was
+            // made on the latest one at or before the version it was claimed at
+            db.query(
+                format!(
+                    "SELECT r.gift_uuid, r.version, {}, u.name, r.created \
+                FROM {} l \
+                INNER JOIN {} i ON i.gift_uuid = l.gift_uuid \
+                INNER JOIN {} c ON c.gift_uuid = i.gift_uuid \
+                INNER JOIN {} r ON r.gift_uuid = i.gift_uuid \
+                LEFT JOIN {} u ON u.user_uuid = r.author \
+                WHERE l.list_uuid = $1 AND l.user_uuid = $2 AND c.user_uuid = $3 \
+                AND i.deleted IS NULL \
+                AND r.version = (SELECT MAX(version) FROM {} \
+                    WHERE gift_uuid = i.gift_uuid AND version <= c.version)",
+                    DbConn::detail_columns("r"),
+                    DbConn::LIST_TO_GIFT_TABLE,
+                    DbConn::GIFT_TABLE,
+                    DbConn::CLAIM_TABLE,
+                    DbConn::GIFT_REVISION_TABLE,
+                    DbConn::USER_TABLE,
+                    DbConn::GIFT_REVISION_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid, &claimer_uuid],
+            )?
+            .iter()
+            .map(PgConn::revision_from_row)
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn claim_gift(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        quantity: i64,
+    ) -> Result<Option<i64>, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            // Locking the gift holds off other claims on it, and trashing it, until this one is in
+            let left: i64 = match tx.query_opt(
+                format!(
+                    "SELECT i.quantity FROM {} i \
+                WHERE i.gift_uuid = $1 AND i.deleted IS NULL FOR UPDATE",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )? {
+                Some(row) => row.try_get(0)?,
+                None => return Ok(Some(0)),
+            };
+            let group = tx.query_opt(
+                format!(
+                    "SELECT 1 FROM {} WHERE gift_uuid = $1",
+                    DbConn::GROUP_GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )?;
+            if group.is_some() {
+                return Ok(Some(0));
+            }
+            let others: i64 = tx
+                .query_one(
+                    format!(
+                        "SELECT COALESCE(SUM(quantity), 0)::BIGINT FROM {} \
+                    WHERE gift_uuid = $1 AND user_uuid != $2",
+                        DbConn::CLAIM_TABLE
+                    )
+                    .as_str(),
+                    &[&gift_uuid, &user_uuid],
+                )?
+                .try_get(0)?;
+            if quantity > left - others {
+                return Ok(Some((left - others).max(0)));
+            }
+            tx.execute(
+                format!(
+                    "INSERT INTO {} (gift_uuid, user_uuid, quantity, version, created) \
+                SELECT gift_uuid, $2, $3, version, $4 FROM {} \
+                WHERE gift_uuid = $1 AND deleted IS NULL \
+                ON CONFLICT (gift_uuid, user_uuid) \
+                DO UPDATE SET quantity = excluded.quantity, version = excluded.version",
+                    DbConn::CLAIM_TABLE,
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &user_uuid, &quantity, &PgConn::now()],
+            )?;
+            tx.commit()?;
+            Ok(None)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn unclaim_gift(&self, gift_uuid: String, user_uuid: String) -> Result<bool, AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "DELETE FROM {} WHERE gift_uuid = $1 AND user_uuid = $2",
+                    DbConn::CLAIM_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &user_uuid],
+            )
+        })
+        .await
+        .map(|changed| changed > 0)
+        .map_err(AppError::from)
+    }
+
+    async fn group_gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<GroupGift>, AppError> {
+        self.run(move |db| {
+            PgConn::group_gifts_where(
+                db,
+                format!(
+                    "SELECT gift_uuid FROM {} WHERE list_uuid = $1 AND user_uuid = $2",
+                    DbConn::LIST_TO_GIFT_TABLE
+                )
+                .as_str(),
+                &[&list_uuid, &user_uuid],
+                &current_user_uuid,
+            )
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn group_gift(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Option<GroupGift>, AppError> {
+        self.run(move |db| PgConn::group_gifts_where(db, "$1", &[&gift_uuid], &current_user_uuid))
+            .await
+            .map(|groups| groups.into_iter().next())
+            .map_err(AppError::from)
+    }
+
+    async fn start_group_gift(
+        &self,
+        gift_uuid: String,
+        organizer_uuid: String,
+    ) -> Result<bool, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            // Takes the same lock as a claim, so one can't slip in first
+            tx.query_opt(
+                format!(
+                    "SELECT 1 FROM {} WHERE gift_uuid = $1 FOR UPDATE",
+                    DbConn::GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )?;
+            let changed = tx.execute(
+                format!(
+                    "INSERT INTO {} (gift_uuid, organizer, created) \
+                SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT 1 FROM {} WHERE gift_uuid = $1) \
+                ON CONFLICT (gift_uuid) DO NOTHING",
+                    DbConn::GROUP_GIFT_TABLE,
+                    DbConn::CLAIM_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &organizer_uuid, &PgConn::now()],
+            )?;
+            tx.commit()?;
+            Ok(changed > 0)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn cancel_group_gift(&self, gift_uuid: String) -> Result<(), AppError> {
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "DELETE FROM {} WHERE gift_uuid = $1",
+                    DbConn::GROUP_GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn pledge(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        amount: i64,
+    ) -> Result<bool, AppError> {
+        self.run(move |db| -> Result<_, postgres::Error> {
+            let mut tx = db.transaction()?;
+            // Locked so it can't be marked purchased while the pledge goes in
+            let open = tx
+                .query_opt(
+                    format!(
+                        "SELECT 1 FROM {} WHERE gift_uuid = $1 AND purchased IS NULL FOR UPDATE",
+                        DbConn::GROUP_GIFT_TABLE
+                    )
+                    .as_str(),
+                    &[&gift_uuid],
+                )?
+                .is_some();
+            if !open {
+                return Ok(false);
+            }
+            if amount == 0 {
+                tx.execute(
+                    format!(
+                        "DELETE FROM {} WHERE gift_uuid = $1 AND user_uuid = $2",
+                        DbConn::PLEDGE_TABLE
+                    )
+                    .as_str(),
+                    &[&gift_uuid, &user_uuid],
+                )?;
+            } else {
+                tx.execute(
+                    format!(
+                        "INSERT INTO {} (gift_uuid, user_uuid, amount, created) \
+                    VALUES ($1, $2, $3, $4) \
+                    ON CONFLICT (gift_uuid, user_uuid) DO UPDATE SET amount = excluded.amount",
+                        DbConn::PLEDGE_TABLE
+                    )
+                    .as_str(),
+                    &[&gift_uuid, &user_uuid, &amount, &PgConn::now()],
+                )?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+        .map_err(AppError::from)
+    }
+
+    async fn set_group_gift_purchased(
+        &self,
+        gift_uuid: String,
+        purchased: bool,
+    ) -> Result<(), AppError> {
+        let purchased = if purchased { Some(PgConn::now()) } else { None };
+        self.run(move |db| {
+            db.execute(
+                format!(
+                    "UPDATE {} SET purchased = $2 WHERE gift_uuid = $1",
+                    DbConn::GROUP_GIFT_TABLE
+                )
+                .as_str(),
+                &[&gift_uuid, &purchased],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Postgres has its own tools for this
+    async fn backup(&self, _path: PathBuf) -> Result<(), AppError> {
+        Err(AppError::Validation(
+            "Back up Postgres storage with pg_dump".to_string(),
+        ))
+    }
+}
+
+// Needs a Postgres to talk to: GIFT_TEST_POSTGRES_URL, or a gifts_test database on localhost
+// (see the postgres feature in Cargo.toml).
+// Every test makes its own users and lists, so they can share one database and run in parallel.
+#[cfg(test)]
+mod tests {
+    use rocket::{Ignite, Rocket};
+
+    use super::PgConn;
+    use crate::repository::Storage;
+    use crate::repository_tests::repository_cases;
+
+    async fn storage() -> (Rocket<Ignite>, Storage) {
+        let url = std::env::var("GIFT_TEST_POSTGRES_URL")
+            .unwrap_or_else(|_| "postgres://postgres@localhost/gifts_test".to_string());
+        let figment = rocket::Config::figment()
+            .merge(("storage", "postgres"))
+            .merge(("databases.postgres.url", url))
+            .merge(("databases.postgres.pool_size", 2));
+        let rocket = Storage::attach(rocket::custom(figment))
+            .ignite()
+            .await
+            .expect("test database reachable and migrated");
+        let conn = Storage::get_one(&rocket).await.expect("storage mounted");
+        (rocket, conn)
+    }
+
+    repository_cases!(storage());
+
+    #[rocket::async_test]
+    async fn migrations_run_once() {
+        let (rocket, _) = storage().await;
+        // A second start finds nothing left to do
+        let (_, _) = storage().await;
+        let versions: i64 = PgConn::get_one(&rocket)
+            .await
+            .unwrap()
+            .run(|db| {
+                db.query_one(
+                    "SELECT COUNT(*) - COUNT(DISTINCT version) FROM SCHEMA_VERSION",
+                    &[],
+                )
+                .map(|row| row.get(0))
+            })
+            .await
+            .unwrap();
+        assert_eq!(versions, 0);
+    }
+}
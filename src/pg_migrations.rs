@@ -0,0 +1,515 @@
+use rocket::{Build, Rocket};
+use rocket_sync_db_pools::postgres::{self, Client, Transaction};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::ListRole;
+use crate::db::DbConn;
+use crate::pg::PgConn;
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    apply: fn(&mut Transaction) -> Result<(), postgres::Error>,
+}
+
+// The same steps as Migrations, written for Postgres, so both databases agree on what a
+// schema version means
+pub struct PgMigrations;
+
+impl PgMigrations {
+    const VERSION_TABLE: &'static str = "SCHEMA_VERSION";
+
+    // Keeps two servers starting at once from migrating over each other
+    const LOCK_ID: i64 = 0x6769_6674;
+
+    // Append new steps to the end, never edit or reorder ones that have shipped
+    const STEPS: &'static [Migration] = &[
+        Migration {
+            version: 1,
+            name: "initial schema",
+            apply: PgMigrations::initial_schema,
+        },
+        Migration {
+            version: 2,
+            name: "login tokens and sessions",
+            apply: PgMigrations::login_tokens_and_sessions,
+        },
+        Migration {
+            version: 3,
+            name: "admin users",
+            apply: PgMigrations::admin_users,
+        },
+        Migration {
+            version: 4,
+            name: "list roles",
+            apply: PgMigrations::list_roles,
+        },
+        Migration {
+            version: 5,
+            name: "foreign keys",
+            apply: PgMigrations::foreign_keys,
+        },
+        Migration {
+            version: 6,
+            name: "edit versions",
+            apply: PgMigrations::edit_versions,
+        },
+        Migration {
+            version: 7,
+            name: "gift positions",
+            apply: PgMigrations::gift_positions,
+        },
+        Migration {
+            version: 8,
+            name: "nullable claims",
+            apply: PgMigrations::nullable_claims,
+        },
+        Migration {
+            version: 9,
+            name: "trash",
+            apply: PgMigrations::trash,
+        },
+        Migration {
+            version: 10,
+            name: "gift revisions",
+            apply: PgMigrations::gift_revisions,
+        },
+        Migration {
+            version: 11,
+            name: "gift details",
+            apply: PgMigrations::gift_details,
+        },
+        Migration {
+            version: 12,
+            name: "claims",
+            apply: PgMigrations::claims,
+        },
+        Migration {
+            version: 13,
+            name: "group gifts",
+            apply: PgMigrations::group_gifts,
+        },
+    ];
+
+    fn latest_version() -> i64 {
+        PgMigrations::STEPS.last().map_or(0, |m| m.version)
+    }
+
+    pub async fn run_at_ignite(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+        let conn = PgConn::get_one(&rocket).await.expect("database mounted");
+        match conn.run(PgMigrations::migrate).await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                println!("Couldn't migrate the database: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+
+    fn migrate(db: &mut Client) -> Result<(), String> {
+        db.execute("SELECT pg_advisory_lock($1)", &[&PgMigrations::LOCK_ID])
+            .map_err(|e| e.to_string())?;
+        let migrated = PgMigrations::apply_steps(db);
+        db.execute("SELECT pg_advisory_unlock($1)", &[&PgMigrations::LOCK_ID])
+            .map_err(|e| e.to_string())?;
+        migrated
+    }
+
+    fn apply_steps(db: &mut Client) -> Result<(), String> {
+        db.batch_execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    version     BIGINT PRIMARY KEY,
+                    name        TEXT NOT NULL,
+                    applied     BIGINT NOT NULL
+                    )",
+                PgMigrations::VERSION_TABLE
+            )
+            .as_str(),
+        )
+        .map_err(|e| e.to_string())?;
+
+        let current: i64 = db
+            .query_one(
+                format!(
+                    "SELECT COALESCE(MAX(version), 0) FROM {}",
+                    PgMigrations::VERSION_TABLE
+                )
+                .as_str(),
+                &[],
+            )
+            .map(|row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if current > PgMigrations::latest_version() {
+            return Err(format!(
+                "the database is at schema version {} but this build only knows up to {}, refusing to start",
+                current,
+                PgMigrations::latest_version()
+            ));
+        }
+
+        for step in PgMigrations::STEPS.iter().filter(|m| m.version > current) {
+            // Postgres can roll back schema changes too, so each step lands with its version row or not at all
+            let applied = db.transaction().and_then(|mut tx| {
+                (step.apply)(&mut tx)?;
+                tx.execute(
+                    format!(
+                        "INSERT INTO {} (version, name, applied) VALUES ($1, $2, $3)",
+                        PgMigrations::VERSION_TABLE
+                    )
+                    .as_str(),
+                    &[&step.version, &step.name, &PgMigrations::now()],
+                )?;
+                tx.commit()
+            });
+            if let Err(e) = applied {
+                return Err(format!(
+                    "migration {} ({}) failed: {}",
+                    step.version, step.name, e
+                ));
+            }
+            println!(
+                "Applied database migration {} ({})",
+                step.version, step.name
+            );
+        }
+        Ok(())
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn initial_schema(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "CREATE TABLE {users} (
+                    user_uuid   TEXT PRIMARY KEY,
+                    auth_token  TEXT NOT NULL,
+                    email       TEXT NOT NULL,
+                    name        TEXT NOT NULL,
+                    can_create  BOOLEAN NOT NULL
+                    );
+                CREATE INDEX tokens ON {users}(auth_token);
+                CREATE INDEX emails ON {users}(email);
+                CREATE TABLE {lists} (
+                    list_uuid   TEXT PRIMARY KEY,
+                    name        TEXT NOT NULL,
+                    owner       TEXT NOT NULL
+                    );
+                CREATE TABLE {list_users} (
+                    list_uuid   TEXT,
+                    user_uuid   TEXT
+                    );
+                CREATE INDEX list_map_list ON {list_users}(list_uuid);
+                CREATE INDEX list_map_user ON {list_users}(user_uuid);
+                CREATE TABLE {items} (
+                    gift_uuid       TEXT PRIMARY KEY,
+                    user_uuid       TEXT NOT NULL,
+                    url             TEXT NOT NULL,
+                    comment         TEXT NOT NULL,
+                    claimed         BOOLEAN NOT NULL,
+                    claimed_by      TEXT NOT NULL,
+                    alternate_to    TEXT
+                    );
+                CREATE TABLE {list_items} (
+                    list_uuid   TEXT,
+                    user_uuid   TEXT,
+                    gift_uuid   TEXT
+                    );
+                CREATE INDEX item_map_list ON {list_items}(list_uuid);",
+                users = DbConn::USER_TABLE,
+                lists = DbConn::LIST_TABLE,
+                list_users = DbConn::LIST_TO_USER_TABLE,
+                items = DbConn::GIFT_TABLE,
+                list_items = DbConn::LIST_TO_GIFT_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn login_tokens_and_sessions(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "DROP INDEX tokens;
+                ALTER TABLE {users} DROP COLUMN auth_token;
+                CREATE TABLE {login_tokens} (
+                    token       TEXT PRIMARY KEY,
+                    user_uuid   TEXT NOT NULL,
+                    expires     BIGINT NOT NULL
+                    );
+                CREATE INDEX login_token_user ON {login_tokens}(user_uuid);
+                CREATE TABLE {sessions} (
+                    session_token   TEXT PRIMARY KEY,
+                    user_uuid       TEXT NOT NULL,
+                    created         BIGINT NOT NULL
+                    );
+                CREATE INDEX \"session_user\" ON {sessions}(user_uuid);",
+                users = DbConn::USER_TABLE,
+                login_tokens = DbConn::LOGIN_TOKEN_TABLE,
+                sessions = DbConn::SESSION_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn admin_users(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {} ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE",
+                DbConn::USER_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn list_roles(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {} ADD COLUMN role TEXT NOT NULL DEFAULT 'member'",
+                DbConn::LIST_TO_USER_TABLE
+            )
+            .as_str(),
+        )?;
+        tx.execute(
+            format!(
+                "UPDATE {} m SET role = $1 FROM {} l \
+                WHERE l.list_uuid = m.list_uuid AND l.owner = m.user_uuid",
+                DbConn::LIST_TO_USER_TABLE,
+                DbConn::LIST_TABLE
+            )
+            .as_str(),
+            &[&ListRole::CoOwner.as_str()],
+        )?;
+        Ok(())
+    }
+
+    // Postgres can add constraints in place, so nothing needs rebuilding like on SQLite
+    fn foreign_keys(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {lists}
+                    ADD FOREIGN KEY (owner) REFERENCES {users}(user_uuid) ON DELETE CASCADE;
+                DROP INDEX list_map_list;
+                ALTER TABLE {list_users}
+                    ALTER COLUMN list_uuid SET NOT NULL,
+                    ALTER COLUMN user_uuid SET NOT NULL,
+                    ADD PRIMARY KEY (list_uuid, user_uuid),
+                    ADD FOREIGN KEY (list_uuid) REFERENCES {lists}(list_uuid) ON DELETE CASCADE,
+                    ADD FOREIGN KEY (user_uuid) REFERENCES {users}(user_uuid) ON DELETE CASCADE;
+                ALTER TABLE {items}
+                    ADD FOREIGN KEY (user_uuid) REFERENCES {users}(user_uuid) ON DELETE CASCADE,
+                    ADD FOREIGN KEY (alternate_to) REFERENCES {items}(gift_uuid) ON DELETE SET NULL;
+                DROP INDEX item_map_list;
+                ALTER TABLE {list_items}
+                    ALTER COLUMN list_uuid SET NOT NULL,
+                    ALTER COLUMN user_uuid SET NOT NULL,
+                    ADD PRIMARY KEY (gift_uuid),
+                    ADD FOREIGN KEY (gift_uuid) REFERENCES {items}(gift_uuid) ON DELETE CASCADE,
+                    ADD FOREIGN KEY (list_uuid, user_uuid)
+                        REFERENCES {list_users}(list_uuid, user_uuid) ON DELETE CASCADE;
+                ALTER TABLE {login_tokens}
+                    ADD FOREIGN KEY (user_uuid) REFERENCES {users}(user_uuid) ON DELETE CASCADE;
+                ALTER TABLE {sessions}
+                    ADD FOREIGN KEY (user_uuid) REFERENCES {users}(user_uuid) ON DELETE CASCADE;
+                CREATE INDEX list_owner ON {lists}(owner);
+                CREATE INDEX item_owner ON {items}(user_uuid);
+                CREATE INDEX item_alternate ON {items}(alternate_to);
+                CREATE INDEX item_map_list ON {list_items}(list_uuid, user_uuid);
+                CREATE FUNCTION list_item_removed() RETURNS trigger AS $$
+                    BEGIN DELETE FROM {items} WHERE gift_uuid = OLD.gift_uuid; RETURN NULL; END
+                    $$ LANGUAGE plpgsql;
+                CREATE TRIGGER list_item_removed AFTER DELETE ON {list_items}
+                    FOR EACH ROW EXECUTE FUNCTION list_item_removed();",
+                users = DbConn::USER_TABLE,
+                lists = DbConn::LIST_TABLE,
+                list_users = DbConn::LIST_TO_USER_TABLE,
+                items = DbConn::GIFT_TABLE,
+                list_items = DbConn::LIST_TO_GIFT_TABLE,
+                login_tokens = DbConn::LOGIN_TOKEN_TABLE,
+                sessions = DbConn::SESSION_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn edit_versions(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        for table in [DbConn::LIST_TO_USER_TABLE, DbConn::GIFT_TABLE] {
+            tx.batch_execute(
+                format!(
+                    "ALTER TABLE {} ADD COLUMN version BIGINT NOT NULL DEFAULT 0",
+                    table
+                )
+                .as_str(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Existing gifts keep the order they were stored in, there's no rowid to number them by
+    fn gift_positions(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {items} ADD COLUMN position BIGINT NOT NULL DEFAULT 0;
+                UPDATE {items} i SET position = n.position FROM (
+                    SELECT gift_uuid, ROW_NUMBER() OVER (PARTITION BY user_uuid ORDER BY ctid) AS position
+                    FROM {items}) n
+                WHERE n.gift_uuid = i.gift_uuid;",
+                items = DbConn::GIFT_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn nullable_claims(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {items} ALTER COLUMN claimed_by DROP NOT NULL;
+                UPDATE {items} SET claimed_by = NULL WHERE NOT claimed OR NOT EXISTS (
+                    SELECT 1 FROM {users} u WHERE u.user_uuid = {items}.claimed_by);
+                ALTER TABLE {items}
+                    DROP COLUMN claimed,
+                    ADD FOREIGN KEY (claimed_by) REFERENCES {users}(user_uuid) ON DELETE SET NULL;
+                CREATE INDEX item_claimer ON {items}(claimed_by);",
+                users = DbConn::USER_TABLE,
+                items = DbConn::GIFT_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn trash(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {lists} ADD COLUMN deleted BIGINT;
+                ALTER TABLE {items} ADD COLUMN deleted BIGINT;
+                CREATE INDEX list_deleted ON {lists}(deleted);
+                CREATE INDEX item_deleted ON {items}(deleted);",
+                lists = DbConn::LIST_TABLE,
+                items = DbConn::GIFT_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn gift_revisions(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "CREATE TABLE {revisions} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {items}(gift_uuid) ON DELETE CASCADE,
+                    version     BIGINT NOT NULL,
+                    url         TEXT NOT NULL,
+                    comment     TEXT NOT NULL,
+                    author      TEXT REFERENCES {users}(user_uuid) ON DELETE SET NULL,
+                    created     BIGINT NOT NULL,
+                    PRIMARY KEY (gift_uuid, version)
+                    );
+                CREATE INDEX item_revision_author ON {revisions}(author);
+                ALTER TABLE {items} ADD COLUMN claimed_version BIGINT;
+                UPDATE {items} SET claimed_version = version WHERE claimed_by IS NOT NULL;",
+                revisions = DbConn::GIFT_REVISION_TABLE,
+                items = DbConn::GIFT_TABLE,
+                users = DbConn::USER_TABLE
+            )
+            .as_str(),
+        )?;
+        tx.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, version, url, comment, author, created) \
+                SELECT gift_uuid, version, url, comment, user_uuid, $1 FROM {}",
+                DbConn::GIFT_REVISION_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            &[&PgMigrations::now()],
+        )?;
+        Ok(())
+    }
+
+    fn gift_details(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        for table in [DbConn::GIFT_TABLE, DbConn::GIFT_REVISION_TABLE] {
+            tx.batch_execute(
+                format!(
+                    "ALTER TABLE {}
+                        ADD COLUMN title TEXT NOT NULL DEFAULT '',
+                        ADD COLUMN price BIGINT,
+                        ADD COLUMN currency TEXT NOT NULL DEFAULT '',
+                        ADD COLUMN quantity BIGINT NOT NULL DEFAULT 1,
+                        ADD COLUMN priority TEXT NOT NULL DEFAULT 'medium',
+                        ADD COLUMN notes TEXT NOT NULL DEFAULT ''",
+                    table
+                )
+                .as_str(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn claims(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "CREATE TABLE {claims} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {items}(gift_uuid) ON DELETE CASCADE,
+                    user_uuid   TEXT NOT NULL REFERENCES {users}(user_uuid) ON DELETE CASCADE,
+                    quantity    BIGINT NOT NULL,
+                    version     BIGINT NOT NULL,
+                    created     BIGINT NOT NULL,
+                    PRIMARY KEY (gift_uuid, user_uuid)
+                    );
+                CREATE INDEX item_claim_user ON {claims}(user_uuid);",
+                claims = DbConn::CLAIM_TABLE,
+                items = DbConn::GIFT_TABLE,
+                users = DbConn::USER_TABLE
+            )
+            .as_str(),
+        )?;
+        tx.execute(
+            format!(
+                "INSERT INTO {} (gift_uuid, user_uuid, quantity, version, created) \
+                SELECT gift_uuid, claimed_by, quantity, COALESCE(claimed_version, version), $1 \
+                FROM {} WHERE claimed_by IS NOT NULL",
+                DbConn::CLAIM_TABLE,
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+            &[&PgMigrations::now()],
+        )?;
+        tx.batch_execute(
+            format!(
+                "ALTER TABLE {} DROP COLUMN claimed_by, DROP COLUMN claimed_version",
+                DbConn::GIFT_TABLE
+            )
+            .as_str(),
+        )
+    }
+
+    fn group_gifts(tx: &mut Transaction) -> Result<(), postgres::Error> {
+        tx.batch_execute(
+            format!(
+                "CREATE TABLE {groups} (
+                    gift_uuid   TEXT PRIMARY KEY REFERENCES {items}(gift_uuid) ON DELETE CASCADE,
+                    organizer   TEXT NOT NULL REFERENCES {users}(user_uuid) ON DELETE CASCADE,
+                    purchased   BIGINT,
+                    created     BIGINT NOT NULL
+                    );
+                CREATE TABLE {pledges} (
+                    gift_uuid   TEXT NOT NULL REFERENCES {groups}(gift_uuid) ON DELETE CASCADE,
+                    user_uuid   TEXT NOT NULL REFERENCES {users}(user_uuid) ON DELETE CASCADE,
+                    amount      BIGINT NOT NULL,
+                    created     BIGINT NOT NULL,
+                    PRIMARY KEY (gift_uuid, user_uuid)
+                    );
+                CREATE INDEX group_gift_organizer ON {groups}(organizer);
+                CREATE INDEX group_pledge_user ON {pledges}(user_uuid);",
+                groups = DbConn::GROUP_GIFT_TABLE,
+                pledges = DbConn::PLEDGE_TABLE,
+                items = DbConn::GIFT_TABLE,
+                users = DbConn::USER_TABLE
+            )
+            .as_str(),
+        )
+    }
+}
@@ -0,0 +1,339 @@
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Build, Phase, Rocket};
+use std::ops::Deref;
+use std::path::PathBuf;
+
+use crate::data::{
+    AdminList, DeletedGift, DeletedList, Gift, GiftRevision, GroupGift, List, ListMember, ListRole,
+    User,
+};
+use crate::db::DbConn;
+use crate::error::AppError;
+use crate::migrations::Migrations;
+#[cfg(feature = "postgres")]
+use crate::pg::PgConn;
+#[cfg(feature = "postgres")]
+use crate::pg_migrations::PgMigrations;
+
+// Everything the app keeps: users and their logins, lists and memberships, gifts and claims
+#[rocket::async_trait]
+pub trait Repository: Send + Sync {
+    async fn consume_login_token(&self, login_token: String) -> Result<String, AppError>;
+    async fn create_login_token(
+        &self,
+        user_uuid: String,
+        login_token: String,
+        lifetime_secs: i64,
+    ) -> Result<(), AppError>;
+    async fn create_session(
+        &self,
+        user_uuid: String,
+        session_token: String,
+    ) -> Result<(), AppError>;
+    async fn user_uuid_from_session(&self, session_token: String) -> Result<String, AppError>;
+    async fn delete_session(&self, session_token: String) -> Result<(), AppError>;
+    async fn delete_sessions_of_user(&self, user_uuid: String) -> Result<(), AppError>;
+
+    async fn user_count(&self) -> Result<i64, AppError>;
+    async fn all_users(&self, current_user_uuid: String) -> Result<Vec<User>, AppError>;
+    async fn user_from_email(&self, email: String) -> Result<User, AppError>;
+    async fn user_from_uuid(
+        &self,
+        user_uuid: String,
+        current_uuid: String,
+    ) -> Result<User, AppError>;
+    async fn create_user(&self, user: User) -> Result<(), AppError>;
+    async fn modify_user(&self, user: User) -> Result<(), AppError>;
+    // A co-owner takes over each list they created, and a list nobody else could look after
+    // stops the delete. Their other lists, memberships, wishlists and sessions go with them and
+    // their claims are released
+    async fn delete_user(&self, user_uuid: String) -> Result<(), AppError>;
+
+    async fn lists_of_user(&self, user_uuid: String) -> Result<Vec<List>, AppError>;
+    async fn all_lists(&self) -> Result<Vec<AdminList>, AppError>;
+    async fn list_from_uuid(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<List, AppError>;
+    // The creator joins as co-owner
+    async fn create_list(&self, list: List) -> Result<(), AppError>;
+    async fn modify_list(&self, list: List) -> Result<(), AppError>;
+    // Moves the list to the trash, which hides it and everything on it until it's restored
+    async fn delete_list(&self, list_uuid: String) -> Result<(), AppError>;
+    // False if the list isn't in the trash
+    async fn restore_list(&self, list_uuid: String) -> Result<bool, AppError>;
+
+    async fn users_of_list(
+        &self,
+        list_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<ListMember>, AppError>;
+    async fn role_of_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+    ) -> Result<ListRole, AppError>;
+    async fn add_user_to_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError>;
+    async fn set_list_role(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+        role: ListRole,
+    ) -> Result<(), AppError>;
+    // Their claims and pledges on the list are released, group gifts they organize there are
+    // called off and their wishlist on it goes with them
+    async fn remove_user_from_list(
+        &self,
+        user_uuid: String,
+        list_uuid: String,
+    ) -> Result<(), AppError>;
+
+    async fn wishlist_version(&self, list_uuid: String, user_uuid: String)
+        -> Result<i64, AppError>;
+    async fn list_uuid_of_gift(&self, gift_uuid: String) -> Result<String, AppError>;
+    // Gifts with no main gift come first, each group in its saved order
+    async fn gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<Gift>, AppError>;
+    async fn gift_from_uuid(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Gift, AppError>;
+    // Removals, additions and edits to one wishlist land together or not at all, and only on
+    // top of the version the edit started from; false means someone got there first. Removed
+    // gifts go to the trash.
+    async fn save_gift_list(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        removed: Vec<String>,
+        added: Vec<Gift>,
+        modified: Vec<Gift>,
+    ) -> Result<bool, AppError>;
+    // Gives the new wishlist version
+    async fn add_gift(&self, list_uuid: String, gift: Gift) -> Result<i64, AppError>;
+    // Gives the new wishlist version, or None if the gift changed since base_version
+    async fn update_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError>;
+    // Moves the gift and its alternates to the trash; None if the gift changed since base_version
+    async fn delete_gift(
+        &self,
+        list_uuid: String,
+        gift: Gift,
+        base_version: i64,
+    ) -> Result<Option<i64>, AppError>;
+    // Gives the new wishlist version, or None if the wishlist changed since base_version
+    async fn reorder_gifts(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        base_version: i64,
+        order: Vec<String>,
+    ) -> Result<Option<i64>, AppError>;
+
+    // Brings back a gift from the trash along with the alternates that went with it. Gives the
+    // new wishlist version, or None if the user has no such gift in the trash.
+    async fn restore_gift(
+        &self,
+        list_uuid: String,
+        gift_uuid: String,
+        user_uuid: String,
+    ) -> Result<Option<i64>, AppError>;
+
+    // Lists the user co-owns that were deleted after since, newest first
+    async fn deleted_lists_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedList>, AppError>;
+    // The user's gifts deleted after since, newest first. Gifts on deleted lists come back with
+    // their list and alternates with their main gift, so neither is listed.
+    async fn deleted_gifts_of_user(
+        &self,
+        user_uuid: String,
+        since: i64,
+    ) -> Result<Vec<DeletedGift>, AppError>;
+    // Removes for good whatever was deleted before the cutoff, giving how many lists and gifts went
+    async fn purge_trash(&self, before: i64) -> Result<usize, AppError>;
+
+    // Every saved url and comment of the gift, newest first, so the first is what it is now
+    async fn gift_revisions(&self, gift_uuid: String) -> Result<Vec<GiftRevision>, AppError>;
+    // For each gift on the wishlist the claimer has claimed, the revision it was at when they
+    // claimed it
+    async fn claimed_revisions(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        claimer_uuid: String,
+    ) -> Result<Vec<GiftRevision>, AppError>;
+
+    // Sets the user's claim on the gift to quantity if the others have left that many; otherwise
+    // hands back how many they have left
+    async fn claim_gift(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        quantity: i64,
+    ) -> Result<Option<i64>, AppError>;
+    // Drops the user's claim on the gift, false if they had none
+    async fn unclaim_gift(&self, gift_uuid: String, user_uuid: String) -> Result<bool, AppError>;
+
+    async fn group_gifts_of_list_user(
+        &self,
+        list_uuid: String,
+        user_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Vec<GroupGift>, AppError>;
+    async fn group_gift(
+        &self,
+        gift_uuid: String,
+        current_user_uuid: String,
+    ) -> Result<Option<GroupGift>, AppError>;
+    // False if the gift is a group gift already or someone has claimed some of it
+    async fn start_group_gift(
+        &self,
+        gift_uuid: String,
+        organizer_uuid: String,
+    ) -> Result<bool, AppError>;
+    // Pledges go with it
+    async fn cancel_group_gift(&self, gift_uuid: String) -> Result<(), AppError>;
+    // Replaces the user's pledge and an amount of 0 takes it back, false once it's been purchased
+    async fn pledge(
+        &self,
+        gift_uuid: String,
+        user_uuid: String,
+        amount: i64,
+    ) -> Result<bool, AppError>;
+    async fn set_group_gift_purchased(
+        &self,
+        gift_uuid: String,
+        purchased: bool,
+    ) -> Result<(), AppError>;
+
+    // Copies everything into a new database file at path while requests carry on
+    async fn backup(&self, path: PathBuf) -> Result<(), AppError>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Sqlite,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+impl StorageBackend {
+    // Picked with `storage` in Rocket.toml, SQLite unless told otherwise
+    pub fn from_figment(figment: &Figment) -> Result<StorageBackend, String> {
+        let name = figment
+            .extract_inner::<String>("storage")
+            .unwrap_or_else(|_| "sqlite".to_string());
+        match name.as_str() {
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            #[cfg(feature = "postgres")]
+            "postgres" => Ok(StorageBackend::Postgres),
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => {
+                Err("storage 'postgres' needs a build with the postgres feature".to_string())
+            }
+            other => Err(format!(
+                "unknown storage '{}', expected sqlite or postgres",
+                other
+            )),
+        }
+    }
+}
+
+// The configured backend, handed to a request or to startup code
+pub struct Storage(Box<dyn Repository>);
+
+impl Storage {
+    pub fn attach(rocket: Rocket<Build>) -> Rocket<Build> {
+        let backend = match StorageBackend::from_figment(rocket.figment()) {
+            Ok(backend) => backend,
+            Err(e) => {
+                return rocket.attach(AdHoc::try_on_ignite("Storage", |rocket| async move {
+                    println!("Couldn't set up storage: {}", e);
+                    Err(rocket)
+                }))
+            }
+        };
+        let rocket = rocket.manage(backend);
+        match backend {
+            StorageBackend::Sqlite => {
+                rocket
+                    .attach(DbConn::fairing())
+                    .attach(AdHoc::try_on_ignite(
+                        "Database Migrations",
+                        Migrations::run_at_ignite,
+                    ))
+            }
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres => {
+                rocket
+                    .attach(PgConn::fairing())
+                    .attach(AdHoc::try_on_ignite(
+                        "Database Migrations",
+                        PgMigrations::run_at_ignite,
+                    ))
+            }
+        }
+    }
+
+    pub async fn get_one<P: Phase>(rocket: &Rocket<P>) -> Option<Storage> {
+        match rocket.state::<StorageBackend>()? {
+            StorageBackend::Sqlite => DbConn::get_one(rocket)
+                .await
+                .map(|conn| Storage(Box::new(conn))),
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres => PgConn::get_one(rocket)
+                .await
+                .map(|conn| Storage(Box::new(conn))),
+        }
+    }
+}
+
+impl Deref for Storage {
+    type Target = dyn Repository;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Storage {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Storage, Self::Error> {
+        match req.rocket().state::<StorageBackend>() {
+            Some(StorageBackend::Sqlite) => req
+                .guard::<DbConn>()
+                .await
+                .map(|conn| Storage(Box::new(conn))),
+            #[cfg(feature = "postgres")]
+            Some(StorageBackend::Postgres) => req
+                .guard::<PgConn>()
+                .await
+                .map(|conn| Storage(Box::new(conn))),
+            None => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
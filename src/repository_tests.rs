@@ -0,0 +1,770 @@
+// Cases every storage backend has to pass, run against each one with `repository_cases!`
+use uuid::Uuid;
+
+use crate::data::{Gift, GiftDetails, GiftPriority, List, ListRole, User};
+use crate::error::AppError;
+use crate::repository::Repository;
+
+// Takes a future of (anything to keep alive, something that derefs to a Repository) and adds a test per case
+macro_rules! repository_cases {
+    ($storage:expr) => {
+        crate::repository_tests::repository_cases!(
+            $storage,
+            login_tokens_work_once_and_expire,
+            sessions_end_on_logout,
+            sessions_end_with_their_user,
+            list_roles,
+            co_owners_keep_the_list_when_its_creator_goes,
+            leaving_releases_claims,
+            only_the_first_claim_wins,
+            claims_share_the_quantity,
+            trashed_gifts_cannot_be_claimed,
+            gifts_keep_their_order_and_versions,
+            stale_saves_change_nothing,
+            trashed_gifts_come_back_until_purged,
+            claimers_see_the_revision_they_claimed,
+            gift_details_round_trip,
+            group_gifts_collect_pledges
+        );
+    };
+    ($storage:expr, $($case:ident),*) => {
+        $(
+            #[rocket::async_test]
+            async fn $case() {
+                let (_keep, conn) = $storage.await;
+                crate::repository_tests::$case(&*conn).await;
+            }
+        )*
+    };
+}
+pub(crate) use repository_cases;
+
+pub async fn new_user(conn: &dyn Repository, name: &str) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    conn.create_user(User {
+        uuid: uuid.to_owned(),
+        email: format!("{}@example.com", uuid),
+        name: name.to_string(),
+        can_create: true,
+        is_admin: false,
+        is_me: false,
+    })
+    .await
+    .unwrap();
+    uuid
+}
+
+pub async fn new_list(conn: &dyn Repository, owner: &str) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    conn.create_list(List {
+        uuid: uuid.to_owned(),
+        name: "Birthdays".to_string(),
+        owner: owner.to_string(),
+        my_role: None,
+        can_manage: false,
+    })
+    .await
+    .unwrap();
+    uuid
+}
+
+// Saved on top of whatever version the wishlist is at
+pub async fn new_gift(conn: &dyn Repository, owner: &str, list_uuid: &str, url: &str) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    let version = conn
+        .wishlist_version(list_uuid.to_string(), owner.to_string())
+        .await
+        .unwrap();
+    let saved = conn
+        .save_gift_list(
+            list_uuid.to_string(),
+            owner.to_string(),
+            version,
+            Vec::new(),
+            vec![gift(&uuid, owner, url)],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+    assert!(saved);
+    uuid
+}
+
+pub fn gift(uuid: &str, owner: &str, url: &str) -> Gift {
+    Gift {
+        uuid: uuid.to_string(),
+        owner: owner.to_string(),
+        details: GiftDetails {
+            title: "".to_string(),
+            url: url.to_string(),
+            comment: "".to_string(),
+            price: None,
+            currency: "".to_string(),
+            quantity: 1,
+            priority: GiftPriority::Medium,
+            notes: "".to_string(),
+        },
+        claims: Vec::new(),
+        alternate_to_uuid: None,
+        version: 0,
+    }
+}
+
+pub async fn login_tokens_work_once_and_expire(conn: &dyn Repository) {
+    let user = new_user(conn, "Ada").await;
+    let token = Uuid::new_v4().to_string();
+    conn.create_login_token(user.to_owned(), token.to_owned(), 60)
+        .await
+        .unwrap();
+    assert_eq!(
+        conn.consume_login_token(token.to_owned()).await.unwrap(),
+        user
+    );
+    assert!(conn.consume_login_token(token).await.is_err());
+
+    // A new link replaces the old one
+    let first = Uuid::new_v4().to_string();
+    let second = Uuid::new_v4().to_string();
+    conn.create_login_token(user.to_owned(), first.to_owned(), 60)
+        .await
+        .unwrap();
+    conn.create_login_token(user.to_owned(), second.to_owned(), 60)
+        .await
+        .unwrap();
+    assert!(conn.consume_login_token(first).await.is_err());
+
+    let expired = Uuid::new_v4().to_string();
+    conn.create_login_token(user, expired.to_owned(), -1)
+        .await
+        .unwrap();
+    assert!(conn.consume_login_token(expired).await.is_err());
+}
+
+pub async fn sessions_end_on_logout(conn: &dyn Repository) {
+    let user = new_user(conn, "Ada").await;
+    let sessions: Vec<String> = (0..3).map(|_| Uuid::new_v4().to_string()).collect();
+    for session in &sessions {
+        conn.create_session(user.to_owned(), session.to_owned())
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        conn.user_uuid_from_session(sessions[0].to_owned())
+            .await
+            .unwrap(),
+        user
+    );
+
+    conn.delete_session(sessions[0].to_owned()).await.unwrap();
+    assert!(conn
+        .user_uuid_from_session(sessions[0].to_owned())
+        .await
+        .is_err());
+    assert!(conn
+        .user_uuid_from_session(sessions[1].to_owned())
+        .await
+        .is_ok());
+
+    // Logging out everywhere ends the rest
+    conn.delete_sessions_of_user(user).await.unwrap();
+    assert!(conn
+        .user_uuid_from_session(sessions[2].to_owned())
+        .await
+        .is_err());
+}
+
+pub async fn sessions_end_with_their_user(conn: &dyn Repository) {
+    let user = new_user(conn, "Ada").await;
+    let session = Uuid::new_v4().to_string();
+    conn.create_session(user.to_owned(), session.to_owned())
+        .await
+        .unwrap();
+    conn.delete_user(user).await.unwrap();
+    assert!(matches!(
+        conn.user_uuid_from_session(session).await,
+        Err(AppError::NotFound)
+    ));
+}
+
+pub async fn list_roles(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let giver = new_user(conn, "Grace").await;
+    let outsider = new_user(conn, "Linus").await;
+    let list_uuid = Uuid::new_v4().to_string();
+    conn.create_list(List {
+        uuid: list_uuid.to_owned(),
+        name: "Birthdays".to_string(),
+        owner: owner.to_owned(),
+        my_role: None,
+        can_manage: false,
+    })
+    .await
+    .unwrap();
+    conn.add_user_to_list(giver.to_owned(), list_uuid.to_owned(), ListRole::Giver)
+        .await
+        .unwrap();
+
+    let seen = conn
+        .list_from_uuid(list_uuid.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(seen.my_role, Some(ListRole::CoOwner));
+    assert!(seen.can_manage);
+    let seen = conn
+        .list_from_uuid(list_uuid.to_owned(), outsider)
+        .await
+        .unwrap();
+    assert_eq!(seen.my_role, None);
+    assert!(!seen.can_manage);
+
+    let members = conn.users_of_list(list_uuid, owner).await.unwrap();
+    assert_eq!(members.len(), 2);
+    assert!(members
+        .iter()
+        .any(|m| m.user.uuid == giver && m.role == ListRole::Giver && !m.has_wishlist));
+}
+
+pub async fn co_owners_keep_the_list_when_its_creator_goes(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let co_owner = new_user(conn, "Grace").await;
+    let member = new_user(conn, "Linus").await;
+    let list = new_list(conn, &owner).await;
+    conn.add_user_to_list(member.to_owned(), list.to_owned(), ListRole::Member)
+        .await
+        .unwrap();
+    let gift = new_gift(conn, &member, &list, "kite").await;
+
+    // Nobody else could look after the list, so nothing goes
+    assert!(matches!(
+        conn.delete_user(owner.to_owned()).await,
+        Err(AppError::Validation(_))
+    ));
+    conn.user_from_uuid(owner.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+
+    conn.add_user_to_list(co_owner.to_owned(), list.to_owned(), ListRole::CoOwner)
+        .await
+        .unwrap();
+    conn.delete_user(owner).await.unwrap();
+    let kept = conn
+        .list_from_uuid(list, co_owner.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(kept.owner, co_owner);
+    assert!(kept.can_manage);
+    conn.gift_from_uuid(gift, co_owner).await.unwrap();
+}
+
+pub async fn leaving_releases_claims(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let giver = new_user(conn, "Grace").await;
+    let list = new_list(conn, &owner).await;
+    conn.add_user_to_list(giver.to_owned(), list.to_owned(), ListRole::Member)
+        .await
+        .unwrap();
+    let owners_gift = new_gift(conn, &owner, &list, "book").await;
+    let givers_gift = new_gift(conn, &giver, &list, "scarf").await;
+    conn.claim_gift(owners_gift.to_owned(), giver.to_owned(), 1)
+        .await
+        .unwrap();
+
+    conn.remove_user_from_list(giver.to_owned(), list.to_owned())
+        .await
+        .unwrap();
+    let gift = conn
+        .gift_from_uuid(owners_gift.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    assert!(gift.claims.is_empty());
+    assert!(matches!(
+        conn.gift_from_uuid(givers_gift, owner.to_owned()).await,
+        Err(AppError::NotFound)
+    ));
+
+    // Deleting the owner takes the list and everything on it
+    conn.delete_user(owner).await.unwrap();
+    assert!(matches!(
+        conn.list_from_uuid(list, giver.to_owned()).await,
+        Err(AppError::NotFound)
+    ));
+    assert!(matches!(
+        conn.gift_from_uuid(owners_gift, giver).await,
+        Err(AppError::NotFound)
+    ));
+}
+
+pub async fn only_the_first_claim_wins(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let first = new_user(conn, "Grace").await;
+    let second = new_user(conn, "Linus").await;
+    let list = new_list(conn, &owner).await;
+    let gift = new_gift(conn, &owner, &list, "book").await;
+
+    assert_eq!(
+        conn.claim_gift(gift.to_owned(), first.to_owned(), 1)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        conn.claim_gift(gift.to_owned(), second.to_owned(), 1)
+            .await
+            .unwrap(),
+        Some(0)
+    );
+    assert!(!conn
+        .unclaim_gift(gift.to_owned(), second.to_owned())
+        .await
+        .unwrap());
+
+    let claimed = conn
+        .gift_from_uuid(gift.to_owned(), first.to_owned())
+        .await
+        .unwrap();
+    assert!(claimed.claims[0].is_me);
+    assert_eq!(claimed.remaining(), 0);
+
+    assert!(conn
+        .unclaim_gift(gift.to_owned(), first.to_owned())
+        .await
+        .unwrap());
+    let unclaimed = conn.gift_from_uuid(gift, first).await.unwrap();
+    assert!(unclaimed.claims.is_empty());
+}
+
+pub async fn claims_share_the_quantity(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let first = new_user(conn, "Grace").await;
+    let second = new_user(conn, "Linus").await;
+    let list = new_list(conn, &owner).await;
+    let mut gift = gift(&Uuid::new_v4().to_string(), &owner, "wine glasses");
+    gift.details.quantity = 6;
+    conn.add_gift(list, gift.to_owned()).await.unwrap();
+
+    let claim =
+        |user: &str, quantity| conn.claim_gift(gift.uuid.to_owned(), user.to_string(), quantity);
+    assert_eq!(claim(&first, 4).await.unwrap(), None);
+    assert_eq!(claim(&second, 3).await.unwrap(), Some(2));
+    assert_eq!(claim(&second, 2).await.unwrap(), None);
+    // Changing a claim only counts everyone else's
+    assert_eq!(claim(&first, 5).await.unwrap(), Some(4));
+    assert_eq!(claim(&first, 1).await.unwrap(), None);
+
+    let claimed = conn
+        .gift_from_uuid(gift.uuid.to_owned(), second.to_owned())
+        .await
+        .unwrap();
+    // Both came in the same second, so their order is down to chance
+    let mut claims = claimed
+        .claims
+        .iter()
+        .map(|c| (c.name.as_str(), c.quantity, c.is_me))
+        .collect::<Vec<_>>();
+    claims.sort();
+    assert_eq!(claims, [("Grace", 1, false), ("Linus", 2, true)]);
+    assert_eq!(claimed.remaining(), 3);
+    assert_eq!(claimed.left_for(&second), 5);
+
+    assert!(conn
+        .unclaim_gift(gift.uuid.to_owned(), first.to_owned())
+        .await
+        .unwrap());
+    let unclaimed = conn
+        .gift_from_uuid(gift.uuid.to_owned(), owner)
+        .await
+        .unwrap();
+    assert_eq!(unclaimed.remaining(), 4);
+}
+
+pub async fn trashed_gifts_cannot_be_claimed(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let giver = new_user(conn, "Grace").await;
+    let list = new_list(conn, &owner).await;
+    let gift = gift(&Uuid::new_v4().to_string(), &owner, "book");
+    conn.add_gift(list.to_owned(), gift.to_owned())
+        .await
+        .unwrap();
+    // Looked up before the owner trashed it
+    conn.delete_gift(list.to_owned(), gift.to_owned(), 1)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        conn.claim_gift(gift.uuid.to_owned(), giver.to_owned(), 1)
+            .await
+            .unwrap(),
+        Some(0)
+    );
+    conn.restore_gift(list, gift.uuid.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    let restored = conn.gift_from_uuid(gift.uuid, owner).await.unwrap();
+    assert!(restored.claims.is_empty());
+}
+
+pub async fn urls(conn: &dyn Repository, list_uuid: &str, owner: &str) -> Vec<String> {
+    conn.gifts_of_list_user(list_uuid.to_string(), owner.to_string(), owner.to_string())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|g| g.details.url)
+        .collect()
+}
+
+pub async fn stale_saves_change_nothing(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let list = new_list(conn, &owner).await;
+    let kept = new_gift(conn, &owner, &list, "kept").await;
+    assert_eq!(
+        conn.wishlist_version(list.to_owned(), owner.to_owned())
+            .await
+            .unwrap(),
+        1
+    );
+
+    let saved = conn
+        .save_gift_list(
+            list.to_owned(),
+            owner.to_owned(),
+            0,
+            vec![kept.to_owned()],
+            vec![gift(&Uuid::new_v4().to_string(), &owner, "lost")],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+    assert!(!saved);
+    assert_eq!(urls(conn, &list, &owner).await, ["kept"]);
+
+    let mut edited = conn
+        .gift_from_uuid(kept.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    edited.details.url = "kept, edited".to_string();
+    let saved = conn
+        .save_gift_list(
+            list.to_owned(),
+            owner.to_owned(),
+            1,
+            Vec::new(),
+            vec![gift(&Uuid::new_v4().to_string(), &owner, "added")],
+            vec![edited],
+        )
+        .await
+        .unwrap();
+    assert!(saved);
+    let mut urls = urls(conn, &list, &owner).await;
+    urls.sort();
+    assert_eq!(urls, ["added", "kept, edited"]);
+    // Every gift the save touched carries the version it was saved at
+    let edited = conn.gift_from_uuid(kept, owner).await.unwrap();
+    assert_eq!(edited.version, 2);
+}
+
+pub async fn gifts_keep_their_order_and_versions(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let list = new_list(conn, &owner).await;
+    let first = gift(&Uuid::new_v4().to_string(), &owner, "first");
+    let second = gift(&Uuid::new_v4().to_string(), &owner, "second");
+    let mut alternate = gift(&Uuid::new_v4().to_string(), &owner, "alternate");
+    alternate.alternate_to_uuid = Some(first.uuid.to_owned());
+    for gift in [&first, &alternate, &second] {
+        conn.add_gift(list.to_owned(), gift.to_owned())
+            .await
+            .unwrap();
+    }
+    // Alternates come after every main gift
+    assert_eq!(
+        urls(conn, &list, &owner).await,
+        ["first", "second", "alternate"]
+    );
+
+    let version = conn
+        .wishlist_version(list.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(version, 3);
+    let order = vec![second.uuid.to_owned(), first.uuid.to_owned()];
+    assert_eq!(
+        conn.reorder_gifts(
+            list.to_owned(),
+            owner.to_owned(),
+            version - 1,
+            order.to_owned()
+        )
+        .await
+        .unwrap(),
+        None
+    );
+    assert_eq!(
+        conn.reorder_gifts(list.to_owned(), owner.to_owned(), version, order)
+            .await
+            .unwrap(),
+        Some(4)
+    );
+    assert_eq!(
+        urls(conn, &list, &owner).await,
+        ["second", "first", "alternate"]
+    );
+
+    // The gift was last written at version 1, so an edit from before the reorder still applies
+    let mut edited = first.to_owned();
+    edited.details.url = "first, edited".to_string();
+    assert_eq!(
+        conn.update_gift(list.to_owned(), edited.to_owned(), 0)
+            .await
+            .unwrap(),
+        None
+    );
+    let version = conn
+        .update_gift(list.to_owned(), edited, 1)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(version, 5);
+
+    // The alternate goes with its main gift
+    assert_eq!(
+        conn.delete_gift(list.to_owned(), first, version)
+            .await
+            .unwrap(),
+        Some(6)
+    );
+    assert_eq!(urls(conn, &list, &owner).await, ["second"]);
+}
+
+pub async fn trashed_gifts_come_back_until_purged(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let list = new_list(conn, &owner).await;
+    let main = gift(&Uuid::new_v4().to_string(), &owner, "main");
+    let mut alternate = gift(&Uuid::new_v4().to_string(), &owner, "alternate");
+    alternate.alternate_to_uuid = Some(main.uuid.to_owned());
+    let other = gift(&Uuid::new_v4().to_string(), &owner, "other");
+    for gift in [&main, &alternate, &other] {
+        conn.add_gift(list.to_owned(), gift.to_owned())
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        conn.delete_gift(list.to_owned(), main.to_owned(), 1)
+            .await
+            .unwrap(),
+        Some(4)
+    );
+    assert_eq!(urls(conn, &list, &owner).await, ["other"]);
+    assert!(matches!(
+        conn.gift_from_uuid(main.uuid.to_owned(), owner.to_owned())
+            .await,
+        Err(AppError::NotFound)
+    ));
+    // The alternate comes back with its main gift, so only the main gift is listed
+    let trashed = conn
+        .deleted_gifts_of_user(owner.to_owned(), 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        trashed
+            .iter()
+            .map(|g| g.uuid.to_owned())
+            .collect::<Vec<_>>(),
+        [main.uuid.to_owned()]
+    );
+    assert_eq!(
+        conn.restore_gift(list.to_owned(), main.uuid.to_owned(), owner.to_owned())
+            .await
+            .unwrap(),
+        Some(5)
+    );
+    assert_eq!(
+        urls(conn, &list, &owner).await,
+        ["main", "other", "alternate"]
+    );
+
+    conn.delete_list(list.to_owned()).await.unwrap();
+    assert!(matches!(
+        conn.role_of_user(list.to_owned(), owner.to_owned()).await,
+        Err(AppError::NotFound)
+    ));
+    assert_eq!(
+        conn.deleted_lists_of_user(owner.to_owned(), 0)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(conn.restore_list(list.to_owned()).await.unwrap());
+    assert!(!conn.restore_list(list.to_owned()).await.unwrap());
+
+    conn.delete_gift(list.to_owned(), other.to_owned(), 3)
+        .await
+        .unwrap();
+    assert!(conn.purge_trash(i64::MAX).await.unwrap() >= 1);
+    assert_eq!(
+        conn.restore_gift(list.to_owned(), other.uuid.to_owned(), owner.to_owned())
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(urls(conn, &list, &owner).await, ["main", "alternate"]);
+}
+
+pub async fn claimers_see_the_revision_they_claimed(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let giver = new_user(conn, "Grace").await;
+    let list = new_list(conn, &owner).await;
+    let mut gift = gift(&Uuid::new_v4().to_string(), &owner, "book");
+    conn.add_gift(list.to_owned(), gift.to_owned())
+        .await
+        .unwrap();
+    conn.claim_gift(gift.uuid.to_owned(), giver.to_owned(), 1)
+        .await
+        .unwrap();
+
+    gift.details.url = "other book".to_string();
+    assert_eq!(
+        conn.update_gift(list.to_owned(), gift.to_owned(), 1)
+            .await
+            .unwrap(),
+        Some(2)
+    );
+    // Saving it again as it is adds no revision
+    assert!(conn
+        .save_gift_list(
+            list.to_owned(),
+            owner.to_owned(),
+            2,
+            vec![],
+            vec![],
+            vec![gift.to_owned()]
+        )
+        .await
+        .unwrap());
+
+    let revisions = conn.gift_revisions(gift.uuid.to_owned()).await.unwrap();
+    assert_eq!(
+        revisions
+            .iter()
+            .map(|r| (r.version, r.details.url.as_str()))
+            .collect::<Vec<_>>(),
+        [(2, "other book"), (1, "book")]
+    );
+    assert_eq!(revisions[0].author_name.as_deref(), Some("Ada"));
+
+    let claimed = conn
+        .claimed_revisions(list.to_owned(), owner.to_owned(), giver.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].details.url, "book");
+    assert!(conn
+        .claimed_revisions(list.to_owned(), owner.to_owned(), owner.to_owned())
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+pub async fn gift_details_round_trip(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let list = new_list(conn, &owner).await;
+    let mut gift = gift(
+        &Uuid::new_v4().to_string(),
+        &owner,
+        "https://example.com/socks",
+    );
+    gift.details.title = "Wool socks".to_string();
+    gift.details.price = Some(1250);
+    gift.details.currency = "EUR".to_string();
+    gift.details.quantity = 3;
+    gift.details.priority = GiftPriority::High;
+    gift.details.notes = "Size 42, not green".to_string();
+    conn.add_gift(list.to_owned(), gift.to_owned())
+        .await
+        .unwrap();
+
+    let saved = conn
+        .gift_from_uuid(gift.uuid.to_owned(), owner.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(saved.details, gift.details);
+
+    // A change to any of them is a new revision
+    gift.details.price = None;
+    gift.details.priority = GiftPriority::Low;
+    conn.update_gift(list.to_owned(), gift.to_owned(), 1)
+        .await
+        .unwrap();
+    let revisions = conn.gift_revisions(gift.uuid.to_owned()).await.unwrap();
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0].details, gift.details);
+    assert_eq!(revisions[1].details.price, Some(1250));
+}
+
+pub async fn group_gifts_collect_pledges(conn: &dyn Repository) {
+    let owner = new_user(conn, "Ada").await;
+    let organizer = new_user(conn, "Grace").await;
+    let giver = new_user(conn, "Linus").await;
+    let list = new_list(conn, &owner).await;
+    let bike = new_gift(conn, &owner, &list, "bike").await;
+    let book = new_gift(conn, &owner, &list, "book").await;
+
+    // Claimed gifts can't become group gifts and group gifts can't be claimed
+    conn.claim_gift(book.to_owned(), giver.to_owned(), 1)
+        .await
+        .unwrap();
+    assert!(!conn
+        .start_group_gift(book, organizer.to_owned())
+        .await
+        .unwrap());
+    assert!(conn
+        .start_group_gift(bike.to_owned(), organizer.to_owned())
+        .await
+        .unwrap());
+    assert!(!conn
+        .start_group_gift(bike.to_owned(), giver.to_owned())
+        .await
+        .unwrap());
+    assert_eq!(
+        conn.claim_gift(bike.to_owned(), giver.to_owned(), 1)
+            .await
+            .unwrap(),
+        Some(0)
+    );
+
+    let pledge = |user: &str, amount| conn.pledge(bike.to_owned(), user.to_string(), amount);
+    assert!(pledge(&organizer, 10000).await.unwrap());
+    assert!(pledge(&giver, 5000).await.unwrap());
+    assert!(pledge(&giver, 7500).await.unwrap());
+    let group = conn
+        .group_gift(bike.to_owned(), giver.to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(group.organizer_name, "Grace");
+    assert!(!group.organized_by_me);
+    assert_eq!(group.pledges.iter().map(|p| p.amount).sum::<i64>(), 17500);
+    let mine = group.pledges.iter().find(|p| p.is_me).unwrap();
+    assert_eq!(mine.amount, 7500);
+
+    // Once it's bought the pledges stay as they are
+    conn.set_group_gift_purchased(bike.to_owned(), true)
+        .await
+        .unwrap();
+    assert!(!pledge(&giver, 0).await.unwrap());
+    let groups = conn
+        .group_gifts_of_list_user(list.to_owned(), owner.to_owned(), organizer.to_owned())
+        .await
+        .unwrap();
+    assert_eq!(groups.len(), 1);
+    assert!(groups[0].purchased);
+    assert!(groups[0].organized_by_me);
+    assert_eq!(groups[0].pledges.len(), 2);
+
+    // The organizer leaving calls it off
+    conn.add_user_to_list(organizer.to_owned(), list.to_owned(), ListRole::Member)
+        .await
+        .unwrap();
+    conn.remove_user_from_list(organizer, list).await.unwrap();
+    assert!(conn.group_gift(bike, giver).await.unwrap().is_none());
+}
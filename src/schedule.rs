@@ -0,0 +1,147 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::futures::future::BoxFuture;
+use rocket::{Data, Orbit, Request, Rocket};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::AppConfig;
+use crate::repository::{Storage, StorageBackend};
+
+// A job that runs every so often on a connection of its own. The pool only hands connections to
+// a request or to the Rocket itself, so the first request after a run falls due starts it
+pub struct Scheduled {
+    name: &'static str,
+    // Seconds between runs with this config and backend, None to never run
+    every: fn(&AppConfig, StorageBackend) -> Option<i64>,
+    // Runs on the first request rather than one interval after starting
+    at_start: bool,
+    job: fn(Storage, AppConfig) -> BoxFuture<'static, ()>,
+    next_run: AtomicI64,
+}
+
+impl Scheduled {
+    pub fn new(
+        name: &'static str,
+        every: fn(&AppConfig, StorageBackend) -> Option<i64>,
+        at_start: bool,
+        job: fn(Storage, AppConfig) -> BoxFuture<'static, ()>,
+    ) -> Scheduled {
+        Scheduled {
+            name,
+            every,
+            at_start,
+            job,
+            next_run: AtomicI64::new(i64::MAX),
+        }
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn settings<P: rocket::Phase>(rocket: &Rocket<P>) -> Option<(AppConfig, StorageBackend)> {
+        Some((
+            rocket.state::<AppConfig>()?.clone(),
+            *rocket.state::<StorageBackend>()?,
+        ))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Scheduled {
+    fn info(&self) -> Info {
+        Info {
+            name: self.name,
+            kind: Kind::Liftoff | Kind::Request,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let every = Scheduled::settings(rocket)
+            .and_then(|(config, backend)| (self.every)(&config, backend));
+        if let Some(every) = every {
+            let wait = if self.at_start { 0 } else { every };
+            self.next_run
+                .store(Scheduled::now() + wait, Ordering::Relaxed);
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let next_run = self.next_run.load(Ordering::Relaxed);
+        let now = Scheduled::now();
+        if now < next_run {
+            return;
+        }
+        let rocket = req.rocket();
+        let (config, backend) = match Scheduled::settings(rocket) {
+            Some(settings) => settings,
+            None => return,
+        };
+        let every = match (self.every)(&config, backend) {
+            Some(every) => every,
+            None => return,
+        };
+        // Only the request that moves the next run along starts this one
+        if self
+            .next_run
+            .compare_exchange(next_run, now + every, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        match Storage::get_one(rocket).await {
+            Some(conn) => {
+                rocket::tokio::spawn((self.job)(conn, config));
+            }
+            None => rocket::error!("{}: no connection to run on", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::fairing::AdHoc;
+    use rocket::local::asynchronous::Client;
+    use rocket::tokio::time::{sleep, Duration};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Scheduled;
+    use crate::data::AppConfig;
+    use crate::repository::Storage;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[rocket::async_test]
+    async fn jobs_run_once_per_interval() {
+        let figment = rocket::Config::figment()
+            .merge(("databases.sqlite_logs.url", ":memory:"))
+            .merge(("databases.sqlite_logs.pool_size", 1));
+        let rocket = Storage::attach(rocket::custom(figment))
+            .attach(AdHoc::config::<AppConfig>())
+            .attach(Scheduled::new(
+                "Counting",
+                |_, _| Some(60 * 60),
+                true,
+                |_, _| {
+                    Box::pin(async {
+                        RUNS.fetch_add(1, Ordering::SeqCst);
+                    })
+                },
+            ));
+        let client = Client::tracked(rocket).await.expect("test server");
+
+        for _ in 0..3 {
+            client.get("/").dispatch().await;
+        }
+        for _ in 0..100 {
+            if RUNS.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+}
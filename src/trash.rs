@@ -0,0 +1,42 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::AppConfig;
+use crate::schedule::Scheduled;
+
+pub struct Trash;
+
+impl Trash {
+    const PURGE_EVERY: i64 = 60 * 60;
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    // Anything deleted before this has been in the trash too long to come back
+    pub fn cutoff(config: &AppConfig) -> i64 {
+        Trash::now() - config.trash_days * 24 * 60 * 60
+    }
+
+    // Runs on the first request and then every hour, whichever storage is in use
+    pub fn schedule() -> Scheduled {
+        Scheduled::new(
+            "Trash Purge",
+            |_, _| Some(Trash::PURGE_EVERY),
+            true,
+            |conn, config| {
+                Box::pin(async move {
+                    match conn.purge_trash(Trash::cutoff(&config)).await {
+                        Ok(0) => {}
+                        Ok(purged) => {
+                            rocket::info!("Purged {} expired items from the trash", purged)
+                        }
+                        Err(e) => rocket::error!("Couldn't purge the trash: {}", e),
+                    }
+                })
+            },
+        )
+    }
+}
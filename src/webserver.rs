@@ -1,23 +1,34 @@
 use rand::{rngs::StdRng, RngCore, SeedableRng};
 use rocket::form::Form;
 use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::response::content::RawJson;
 use rocket::response::Redirect;
+use rocket::{Build, Rocket};
 use rocket_dyn_templates::Template;
-use serde_json;
+use serde::Serialize;
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::authorization::Authorization;
+use crate::backup::Backups;
 use crate::data::{
-    ClaimGift, CreateList, DeleteList, ExportGift, Gift, List, ListPage, ListUser, ListUserPage,
-    ModifyGiftList, ModifyList, ModifyListPage, User, UserPage,
+    AddGift, AdminModifyUser, AdminPage, AdminUserAction, AppConfig, CancelGroupGift, ClaimGift,
+    CreateList, DeleteGift, DeleteList, ExportGift, FormGift, FormGiftDetails, Gift, GiftDetails,
+    GiftHistoryPage, GroupGift, ImportGiftList, List, ListPage, ListRole, ListUser, ListUserPage,
+    MarkPurchased, ModifyList, ModifyListPage, PledgeGift, ReorderGifts, RequestLogin, RestoreGift,
+    RestoreList, RevertGift, SavedGift, StartGroupGift, TrashPage, UnclaimGift, UpdateGift, User,
+    UserPage,
 };
-use crate::db::DbConn;
 use crate::email::Email;
+use crate::error::AppError;
+use crate::repository::{Repository, Storage, StorageBackend};
+use crate::trash::Trash;
 
 pub struct WebServer;
 
 impl WebServer {
-    const USER_COOKIE: &'static str = "userToken";
+    pub(crate) const USER_COOKIE: &'static str = "userToken";
+    const MAX_QUANTITY: i64 = 99;
 
     pub async fn access_denied() -> Template {
         Template::render("access_denied", "")
@@ -35,141 +46,536 @@ impl WebServer {
         Template::render("index", "")
     }
 
-    pub async fn login(user_token: String, cookies: &CookieJar<'_>, conn: &DbConn) -> Redirect {
-        let user_uuid = conn.user_uuid_from_auth_token(user_token).await;
-        return match user_uuid {
+    pub async fn setup_first_admin(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+        let config: AppConfig = match rocket.figment().extract() {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Couldn't read the app config: {}", e);
+                return Err(rocket);
+            }
+        };
+        let conn = match Storage::get_one(&rocket).await {
+            Some(conn) => conn,
+            None => {
+                println!("Couldn't set up the first administrator: storage not mounted");
+                return Err(rocket);
+            }
+        };
+        match conn.user_count().await {
+            Ok(0) => {}
+            Ok(_) => return Ok(rocket),
+            Err(e) => {
+                println!("Couldn't count users: {}", e);
+                return Err(rocket);
+            }
+        }
+        let (name, email) = match (
+            config.setup_admin_name.to_owned(),
+            config.setup_admin_email.to_owned(),
+        ) {
+            (Some(name), Some(email)) => (name, email),
+            _ => {
+                println!("No users exist yet. Set setup_admin_name and setup_admin_email to create the first administrator.");
+                return Ok(rocket);
+            }
+        };
+        let admin = User {
+            uuid: Uuid::new_v4().to_string(),
+            email,
+            name,
+            can_create: true,
+            is_admin: true,
+            is_me: false,
+        };
+        if let Err(e) = conn.create_user(admin.to_owned()).await {
+            println!("Couldn't create the first administrator: {}", e);
+            return Err(rocket);
+        }
+        let login_token =
+            match WebServer::issue_login_token(admin.uuid.to_owned(), &config, &*conn).await {
+                Ok(token) => token,
+                Err(e) => {
+                    println!("Couldn't issue the first administrator a login link: {}", e);
+                    return Err(rocket);
+                }
+            };
+        // The link goes to stdout whatever happens to the email, it's the only way in
+        println!(
+            "Created first administrator {} <{}>. Login link: {}",
+            admin.name,
+            admin.email,
+            Email::site_login_link(login_token.to_owned())
+        );
+        let sent = Email::build().and_then(|email_client| {
+            email_client.send_login_email(
+                login_token,
+                config.login_link_hours,
+                admin.name,
+                admin.email,
+            )
+        });
+        if let Err(e) = sent {
+            println!("Couldn't email the administrator login link: {}", e);
+        }
+        Ok(rocket)
+    }
+
+    pub async fn login(
+        user_token: String,
+        cookies: &CookieJar<'_>,
+        conn: &dyn Repository,
+    ) -> Result<Redirect, AppError> {
+        let user_uuid = conn.consume_login_token(user_token).await;
+        match user_uuid {
             Ok(u) => {
-                WebServer::set_user_cookie(u, cookies);
-                Redirect::to(uri!("/user"))
+                let session_token = WebServer::generate_token();
+                conn.create_session(u, session_token.to_owned()).await?;
+                WebServer::set_user_cookie(session_token, cookies);
+                Ok(Redirect::to(uri!("/user")))
+            }
+            Err(AppError::NotFound) => Ok(Redirect::to(uri!("/"))),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn logout(
+        cookies: &CookieJar<'_>,
+        conn: &dyn Repository,
+    ) -> Result<Redirect, AppError> {
+        if let Ok(session_token) = WebServer::get_user_cookie(cookies) {
+            conn.delete_session(session_token).await?;
+        }
+        cookies.remove_private(Cookie::named(WebServer::USER_COOKIE));
+        Ok(Redirect::to(uri!("/")))
+    }
+
+    pub async fn logout_everywhere(
+        current_user: User,
+        cookies: &CookieJar<'_>,
+        conn: &dyn Repository,
+    ) -> Result<Redirect, AppError> {
+        conn.delete_sessions_of_user(current_user.uuid).await?;
+        cookies.remove_private(Cookie::named(WebServer::USER_COOKIE));
+        Ok(Redirect::to(uri!("/")))
+    }
+
+    pub async fn send_login_link(
+        request: Form<RequestLogin>,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        // Respond the same way whether or not the address exists
+        let user = match conn.user_from_email(request.email.trim().to_string()).await {
+            Ok(u) => u,
+            Err(AppError::NotFound) => return Ok(Template::render("login_sent", "")),
+            Err(e) => return Err(e),
+        };
+        let login_token = WebServer::issue_login_token(user.uuid.to_owned(), config, conn).await?;
+        let link_hours = config.login_link_hours;
+        let (user_name, user_email) = (user.name, user.email);
+        rocket::tokio::task::spawn_blocking(move || {
+            let sent = Email::build().and_then(|email_client| {
+                email_client.send_login_email(
+                    login_token,
+                    link_hours,
+                    user_name,
+                    user_email.to_owned(),
+                )
+            });
+            if let Err(e) = sent {
+                println!("Couldn't email a login link to {}: {}", user_email, e);
             }
-            Err(_) => Redirect::to(uri!("/")),
+        });
+        Ok(Template::render("login_sent", ""))
+    }
+
+    pub async fn user_page(
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        let lists = conn.lists_of_user(current_user.uuid.to_owned()).await?;
+        let context = UserPage {
+            current_user,
+            lists,
+            trash_days: config.trash_days,
         };
+        Ok(Template::render("user", &context))
     }
 
-    pub async fn user_page(cookies: &CookieJar<'_>, conn: &DbConn) -> Template {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
+    pub async fn trash_page(
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        let since = Trash::cutoff(config);
         let lists = conn
-            .lists_of_user(current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        let context = UserPage {
+            .deleted_lists_of_user(current_user.uuid.to_owned(), since)
+            .await?;
+        let gifts = conn
+            .deleted_gifts_of_user(current_user.uuid.to_owned(), since)
+            .await?;
+        let context = TrashPage {
             current_user,
             lists,
+            gifts,
+            trash_days: config.trash_days,
         };
-        Template::render("user", &context)
+        Ok(Template::render("trash", &context))
+    }
+
+    // Only what the trash page would offer can be restored
+    pub async fn restore_list(
+        list: Form<RestoreList>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let in_trash = conn
+            .deleted_lists_of_user(current_user.uuid, Trash::cutoff(config))
+            .await?
+            .iter()
+            .any(|l| l.uuid == list.uuid);
+        if !in_trash || !conn.restore_list(list.uuid.to_owned()).await? {
+            return Err(AppError::Validation(
+                "That list isn't in your trash".to_string(),
+            ));
+        }
+        Ok("Success!".to_string())
     }
 
-    pub async fn list_page(list_uuid: String, cookies: &CookieJar<'_>, conn: &DbConn) -> Template {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
+    pub async fn restore_gift(
+        gift: Form<RestoreGift>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let deleted = conn
+            .deleted_gifts_of_user(current_user.uuid.to_owned(), Trash::cutoff(config))
+            .await?
+            .into_iter()
+            .find(|g| g.uuid == gift.gift_uuid);
+        let list_uuid = match deleted {
+            Some(g) => g.list_uuid,
+            None => {
+                return Err(AppError::Validation(
+                    "That gift isn't in your trash".to_string(),
+                ))
+            }
+        };
+        Authorization::can_edit_wishlist(&current_user, list_uuid.to_owned(), conn).await?;
+        let restored = conn
+            .restore_gift(list_uuid, gift.gift_uuid.to_owned(), current_user.uuid)
+            .await?;
+        if restored.is_none() {
+            return Err(AppError::Validation(
+                "That gift isn't in your trash".to_string(),
+            ));
+        }
+        Ok("Success!".to_string())
+    }
+
+    pub async fn admin_page(
+        current_user: User,
+        config: &AppConfig,
+        backend: StorageBackend,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        let users = conn.all_users(current_user.uuid.to_owned()).await?;
+        let lists = conn.all_lists().await?;
+        let can_back_up = Backups::supported(backend).is_ok();
+        let mut backups = if can_back_up {
+            Backups::list(config)?
+        } else {
+            Vec::new()
+        };
+        backups.reverse();
+        let context = AdminPage {
+            current_user,
+            users,
+            lists,
+            can_back_up,
+            backups,
+        };
+        Ok(Template::render("admin", &context))
+    }
+
+    pub async fn admin_backup(
+        config: &AppConfig,
+        backend: StorageBackend,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        Backups::take(backend, conn, config).await?;
+        Ok("Success!".to_string())
+    }
+
+    pub async fn admin_modify_user(
+        user: Form<AdminModifyUser>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let mut user_data = conn
+            .user_from_uuid(user.uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        if user_data.is_admin
+            && !user.is_admin
+            && WebServer::is_last_admin(&user_data, conn).await?
+        {
+            return Err(AppError::Validation(
+                "There has to be at least one administrator".to_string(),
+            ));
+        }
+        if user_data.is_me && !user.is_admin {
+            return Err(AppError::Validation(
+                "You can't remove your own admin access".to_string(),
+            ));
+        }
+        let email = user.email.trim().to_string();
+        WebServer::validate_user(&user.name, &email)?;
+        if let Ok(other) = conn.user_from_email(email.to_owned()).await {
+            if other.uuid != user_data.uuid {
+                return Err(AppError::Validation(format!(
+                    "{} already uses that email",
+                    other.name
+                )));
+            }
+        }
+        user_data.name = user.name.to_owned();
+        user_data.email = email;
+        user_data.can_create = user.can_create;
+        user_data.is_admin = user.is_admin;
+        conn.modify_user(user_data).await?;
+        Ok("Success!".to_string())
+    }
+
+    pub async fn admin_reset_login(
+        user: Form<AdminUserAction>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let user_data = conn
+            .user_from_uuid(user.uuid.to_owned(), current_user.uuid)
+            .await?;
+        conn.delete_sessions_of_user(user_data.uuid.to_owned())
+            .await?;
+        let login_token =
+            WebServer::issue_login_token(user_data.uuid.to_owned(), config, conn).await?;
+        let sent = Email::build().and_then(|email_client| {
+            email_client.send_login_email(
+                login_token,
+                config.login_link_hours,
+                user_data.name,
+                user_data.email,
+            )
+        });
+        match sent {
+            Ok(_) => Ok("Success!".to_string()),
+            Err(e) => Ok(format!("Login reset, but the email failed to send: {}", e)),
+        }
+    }
+
+    pub async fn admin_delete_user(
+        user: Form<AdminUserAction>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let user_data = conn
+            .user_from_uuid(user.uuid.to_owned(), current_user.uuid)
+            .await?;
+        if user_data.is_admin && WebServer::is_last_admin(&user_data, conn).await? {
+            return Err(AppError::Validation(
+                "There has to be at least one administrator".to_string(),
+            ));
+        }
+        if user_data.is_me {
+            return Err(AppError::Validation(
+                "You can't delete your own account".to_string(),
+            ));
+        }
+        conn.delete_user(user.uuid.to_owned()).await?;
+        Ok("Success!".to_string())
+    }
+
+    pub async fn is_last_admin(user: &User, conn: &dyn Repository) -> Result<bool, AppError> {
+        let users = conn.all_users(user.uuid.to_owned()).await?;
+        Ok(!users.iter().any(|u| u.is_admin && !u.is_me))
+    }
+
+    pub async fn list_page(
+        list_uuid: String,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        Authorization::can_view_list(&current_user, list_uuid.to_owned(), conn).await?;
         let users = conn
             .users_of_list(list_uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         let mut list = conn
             .list_from_uuid(list_uuid, current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         let owner_name = conn
             .user_from_uuid(list.owner, current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         list.owner = owner_name.name;
         let context = ListPage {
             list,
             current_user,
             users,
         };
-        Template::render("list", &context)
+        Ok(Template::render("list", &context))
     }
 
     pub async fn list_user_page(
         list_uuid: String,
         user_uuid: String,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> Template {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        Authorization::can_view_wishlist(
+            &current_user,
+            list_uuid.to_owned(),
+            user_uuid.to_owned(),
+            conn,
+        )
+        .await?;
         let requested_user = conn
             .user_from_uuid(user_uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         let is_me = requested_user.is_me;
         let list = conn
             .list_from_uuid(list_uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        let gifts = conn
+            .await?;
+        let mut gifts = conn
             .gifts_of_list_user(
-                list_uuid,
+                list_uuid.to_owned(),
                 user_uuid.to_owned(),
                 current_user.uuid.to_owned(),
             )
-            .await
-            .unwrap();
+            .await?;
+        // The recipient never learns who claimed what, or that anything was claimed at all
+        if is_me {
+            for gift in gifts.iter_mut() {
+                gift.claims.clear();
+            }
+        }
+        let version = conn
+            .wishlist_version(list_uuid.to_owned(), user_uuid.to_owned())
+            .await?;
+        // Neither is ever shown to the recipient
+        let (claimed_revisions, groups) = if is_me {
+            (Vec::new(), Vec::new())
+        } else {
+            (
+                conn.claimed_revisions(
+                    list_uuid.to_owned(),
+                    user_uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                )
+                .await?,
+                conn.group_gifts_of_list_user(list_uuid, user_uuid, current_user.uuid.to_owned())
+                    .await?,
+            )
+        };
         let gifts_export = gifts
             .iter()
-            .map(|gift| ExportGift {
-                uuid: gift.uuid.to_owned(),
-                url: gift.url.to_owned(),
-                comment: gift.comment.to_owned(),
-                claimed: gift.claimed,
-                claimed_by_name: match &gift.claimed_by {
-                    Some(x) => x.name.to_owned(),
-                    None => "null".to_string(),
-                },
-                claimed_by_me: match &gift.claimed_by {
-                    Some(x) => x.is_me,
-                    None => false,
-                },
-                alternate_to_uuid: gift.alternate_to_uuid.to_owned(),
+            .map(|gift| {
+                let group = groups.iter().find(|g| g.gift_uuid == gift.uuid).cloned();
+                ExportGift {
+                    uuid: gift.uuid.to_owned(),
+                    details: gift.details.to_owned(),
+                    claimed: gift.remaining() == 0 || group.as_ref().is_some_and(|g| g.purchased),
+                    remaining: gift.remaining(),
+                    claims: gift.claims.to_owned(),
+                    my_claim: gift
+                        .claim_of(&current_user.uuid)
+                        .map_or(0, |claim| claim.quantity),
+                    group,
+                    alternate_to_uuid: gift.alternate_to_uuid.to_owned(),
+                    version: gift.version,
+                    claimed_as: claimed_revisions
+                        .iter()
+                        .find(|r| r.gift_uuid == gift.uuid)
+                        .filter(|r| r.details != gift.details)
+                        .cloned(),
+                }
             })
             .collect::<Vec<_>>();
-        let gifts_json = serde_json::to_string(&gifts_export).unwrap();
+        let gifts_json = WebServer::script_json(&gifts_export)?;
 
         let context = ListUserPage {
             user: requested_user,
             current_user,
             gifts_data: gifts_json,
             list,
+            version,
+            default_currency: config.default_currency.to_owned(),
         };
 
         if is_me {
-            Template::render("list_user_self", &context)
+            Ok(Template::render("list_user_self", &context))
         } else {
-            Template::render("list_user_other", &context)
+            Ok(Template::render("list_user_other", &context))
         }
     }
 
-    pub async fn create_list_page(cookies: &CookieJar<'_>, conn: &DbConn) -> Template {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        if !current_user.can_create {
-            return WebServer::not_found().await;
-        }
-        Template::render("create_list", "")
+    pub async fn gift_history_page(
+        gift_uuid: String,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        let gift = conn
+            .gift_from_uuid(gift_uuid, current_user.uuid.to_owned())
+            .await?;
+        let list_uuid = conn.list_uuid_of_gift(gift.uuid.to_owned()).await?;
+        Authorization::can_view_wishlist(
+            &current_user,
+            list_uuid.to_owned(),
+            gift.owner.to_owned(),
+            conn,
+        )
+        .await?;
+        let user = conn
+            .user_from_uuid(gift.owner.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        let list = conn
+            .list_from_uuid(list_uuid, current_user.uuid.to_owned())
+            .await?;
+        let revisions = conn.gift_revisions(gift.uuid.to_owned()).await?;
+        let context = GiftHistoryPage {
+            user,
+            current_user,
+            list,
+            gift,
+            revisions,
+        };
+        Ok(Template::render("gift_history", &context))
+    }
+
+    pub async fn create_list_page(current_user: User) -> Result<Template, AppError> {
+        Authorization::can_create_list(&current_user)?;
+        Ok(Template::render("create_list", ""))
     }
 
     pub async fn modify_list_page(
         list_uuid: String,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> Template {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<Template, AppError> {
+        Authorization::can_manage_list(&current_user, list_uuid.to_owned(), conn).await?;
         let list = conn
             .list_from_uuid(list_uuid, current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        if list.owner != current_user.uuid {
-            return WebServer::not_found().await;
-        }
+            .await?;
         let list_users = conn
             .users_of_list(list.uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         let users = list_users
             .iter()
             .map(|u| ListUser {
-                name: u.name.to_owned(),
-                email: u.email.to_owned(),
+                name: u.user.name.to_owned(),
+                email: u.user.email.to_owned(),
+                role: u.role,
             })
             .collect();
         let context = ModifyListPage {
@@ -177,77 +583,348 @@ impl WebServer {
             list,
             users,
         };
-        Template::render("modify_list", &context)
+        Ok(Template::render("modify_list", &context))
     }
 
     pub async fn claim_gift(
         claim: Form<ClaimGift>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> String {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        let mut gift = conn
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let gift = conn
             .gift_from_uuid(claim.gift_uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
+            .await?;
         if gift.owner == current_user.uuid {
-            return "You can't claim your own gifts :|".to_string();
+            return Err(AppError::Validation(
+                "You can't claim your own gifts :|".to_string(),
+            ));
+        }
+        Authorization::can_claim_gift(&current_user, &gift, conn).await?;
+        if conn
+            .group_gift(gift.uuid.to_owned(), current_user.uuid.to_owned())
+            .await?
+            .is_some()
+        {
+            return Err(AppError::Validation(
+                "This is a group gift, pledge towards it instead".to_string(),
+            ));
         }
-        if gift.claimed {
-            return format!("Item already claimed by {}", gift.claimed_by.unwrap().name);
+        if claim.quantity < 1 {
+            return Err(AppError::Validation(
+                "Claim at least one, or unclaim it instead".to_string(),
+            ));
+        }
+        WebServer::claim_loaded_gift(&gift, claim.quantity, &current_user, conn).await
+    }
+
+    // The gift is as it was looked up, and others may have claimed some of it since
+    async fn claim_loaded_gift(
+        gift: &Gift,
+        quantity: i64,
+        current_user: &User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let left = gift.left_for(&current_user.uuid);
+        if quantity > left {
+            return Err(WebServer::too_few_left(gift, left));
+        }
+        // The database has the final say, and then the gift is looked up again so the message
+        // names whoever got there first
+        if let Some(left) = conn
+            .claim_gift(gift.uuid.to_owned(), current_user.uuid.to_owned(), quantity)
+            .await?
+        {
+            let gift = conn
+                .gift_from_uuid(gift.uuid.to_owned(), current_user.uuid.to_owned())
+                .await?;
+            return Err(WebServer::too_few_left(&gift, left));
         }
-        gift.claimed = true;
-        gift.claimed_by = Some(current_user);
-        conn.modify_gift(gift).await;
 
-        "Claimed!".to_string()
+        Ok("Claimed!".to_string())
     }
 
     pub async fn unclaim_gift(
-        claim: Form<ClaimGift>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> String {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        let mut gift = conn
+        claim: Form<UnclaimGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let gift = conn
             .gift_from_uuid(claim.gift_uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        if gift.owner == current_user.uuid.to_owned() {
-            return "You can't unclaim your own gifts :|".to_string();
+            .await?;
+        if gift.owner == current_user.uuid {
+            return Err(AppError::Validation(
+                "You can't unclaim your own gifts :|".to_string(),
+            ));
         }
-        if !gift.claimed {
-            return "Item isn't claimed".to_string();
+        Authorization::can_claim_gift(&current_user, &gift, conn).await?;
+        if gift.claim_of(&current_user.uuid).is_none()
+            || !conn.unclaim_gift(gift.uuid, current_user.uuid).await?
+        {
+            return Err(AppError::Validation(
+                "You haven't claimed this item".to_string(),
+            ));
+        }
+
+        Ok("Unclaimed!".to_string())
+    }
+
+    pub async fn start_group_gift(
+        start: Form<StartGroupGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let (gift, group) =
+            WebServer::gift_for_group(start.gift_uuid.to_owned(), &current_user, conn).await?;
+        if group.is_some() {
+            return Err(AppError::Validation(
+                "This is a group gift already".to_string(),
+            ));
         }
-        if gift.claimed_by.as_ref().unwrap().uuid != current_user.uuid {
-            return format!("Item claimed by {}", gift.claimed_by.unwrap().name);
+        if !gift.claims.is_empty() || !conn.start_group_gift(gift.uuid, current_user.uuid).await? {
+            return Err(AppError::Validation(
+                "Someone has claimed this already, so it can't be a group gift".to_string(),
+            ));
         }
 
-        gift.claimed = false;
-        gift.claimed_by = None;
-        conn.modify_gift(gift).await;
+        Ok("Started!".to_string())
+    }
 
-        "Unclaimed!".to_string()
+    pub async fn cancel_group_gift(
+        cancel: Form<CancelGroupGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let (gift, group) =
+            WebServer::gift_for_group(cancel.gift_uuid.to_owned(), &current_user, conn).await?;
+        let group = WebServer::organized_by(group, &current_user)?;
+        if group.purchased {
+            return Err(AppError::Validation(
+                "It's been purchased, so it can't be called off".to_string(),
+            ));
+        }
+        conn.cancel_group_gift(gift.uuid).await?;
+
+        Ok("Called off!".to_string())
     }
 
-    pub async fn modify_gift_list(
-        gifts: Form<ModifyGiftList>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> String {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        let user_lists = conn
-            .lists_of_user(current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        if user_lists
-            .iter()
-            .filter(|list| list.uuid == gifts.list_uuid)
-            .collect::<Vec<_>>()
-            .is_empty()
-        {
-            "Unauthorized".to_string();
+    pub async fn pledge(
+        pledge: Form<PledgeGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let (gift, group) =
+            WebServer::gift_for_group(pledge.gift_uuid.to_owned(), &current_user, conn).await?;
+        let purchased = match group {
+            Some(group) => group.purchased,
+            None => {
+                return Err(AppError::Validation(
+                    "This isn't a group gift, claim it instead".to_string(),
+                ))
+            }
+        };
+        let amount = WebServer::parse_price(&pledge.amount)?.unwrap_or(0);
+        // The organizer could mark it purchased between the check and the write
+        if purchased || !conn.pledge(gift.uuid, current_user.uuid, amount).await? {
+            return Err(AppError::Validation(
+                "It's been purchased already".to_string(),
+            ));
+        }
+
+        Ok("Pledged!".to_string())
+    }
+
+    pub async fn mark_purchased(
+        mark: Form<MarkPurchased>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let (gift, group) =
+            WebServer::gift_for_group(mark.gift_uuid.to_owned(), &current_user, conn).await?;
+        WebServer::organized_by(group, &current_user)?;
+        conn.set_group_gift_purchased(gift.uuid, mark.purchased)
+            .await?;
+
+        Ok("Saved!".to_string())
+    }
+
+    // The same rules as claiming, since pledging is claiming a share of it
+    async fn gift_for_group(
+        gift_uuid: String,
+        current_user: &User,
+        conn: &dyn Repository,
+    ) -> Result<(Gift, Option<GroupGift>), AppError> {
+        let gift = conn
+            .gift_from_uuid(gift_uuid, current_user.uuid.to_owned())
+            .await?;
+        if gift.owner == current_user.uuid {
+            return Err(AppError::Validation(
+                "You can't chip in for your own gifts :|".to_string(),
+            ));
+        }
+        Authorization::can_claim_gift(current_user, &gift, conn).await?;
+        let group = conn
+            .group_gift(gift.uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        Ok((gift, group))
+    }
+
+    fn organized_by(group: Option<GroupGift>, current_user: &User) -> Result<GroupGift, AppError> {
+        match group {
+            Some(group) if group.organizer_uuid == current_user.uuid => Ok(group),
+            Some(_) => Err(AppError::Forbidden),
+            None => Err(AppError::Validation("This isn't a group gift".to_string())),
+        }
+    }
+
+    pub async fn add_gift(
+        gift: Form<AddGift>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<RawJson<String>, AppError> {
+        Authorization::can_edit_wishlist(&current_user, gift.list_uuid.to_owned(), conn).await?;
+        let details = WebServer::gift_details(&gift.details, config)?;
+        let alternate_to_uuid = if gift.alternate_to_uuid.is_empty() {
+            None
+        } else {
+            let main = conn
+                .gift_from_uuid(
+                    gift.alternate_to_uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                )
+                .await?;
+            Authorization::can_edit_gift(&current_user, &main)?;
+            let main_list = conn.list_uuid_of_gift(main.uuid.to_owned()).await?;
+            if main_list != gift.list_uuid || main.alternate_to_uuid.is_some() {
+                return Err(AppError::Validation(
+                    "An alternate points at a gift that isn't on the list".to_string(),
+                ));
+            }
+            Some(main.uuid)
+        };
+        let new_gift = Gift {
+            uuid: Uuid::new_v4().to_string(),
+            owner: current_user.uuid.to_owned(),
+            details,
+            claims: Vec::new(),
+            alternate_to_uuid,
+            version: 0,
+        };
+        let uuid = new_gift.uuid.to_owned();
+        let version = conn.add_gift(gift.list_uuid.to_owned(), new_gift).await?;
+        WebServer::saved_gift(uuid, version)
+    }
+
+    pub async fn update_gift(
+        gift: Form<UpdateGift>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<RawJson<String>, AppError> {
+        let mut gift_data = conn
+            .gift_from_uuid(gift.gift_uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        Authorization::can_edit_gift(&current_user, &gift_data)?;
+        let list_uuid = conn.list_uuid_of_gift(gift_data.uuid.to_owned()).await?;
+        Authorization::can_edit_wishlist(&current_user, list_uuid.to_owned(), conn).await?;
+        gift_data.details = WebServer::gift_details(&gift.details, config)?;
+        let uuid = gift_data.uuid.to_owned();
+        match conn.update_gift(list_uuid, gift_data, gift.version).await? {
+            Some(version) => WebServer::saved_gift(uuid, version),
+            None => Err(WebServer::gift_conflict()),
+        }
+    }
+
+    pub async fn delete_gift(
+        gift: Form<DeleteGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<RawJson<String>, AppError> {
+        let gift_data = conn
+            .gift_from_uuid(gift.gift_uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        Authorization::can_edit_gift(&current_user, &gift_data)?;
+        let list_uuid = conn.list_uuid_of_gift(gift_data.uuid.to_owned()).await?;
+        Authorization::can_edit_wishlist(&current_user, list_uuid.to_owned(), conn).await?;
+        let uuid = gift_data.uuid.to_owned();
+        match conn.delete_gift(list_uuid, gift_data, gift.version).await? {
+            Some(version) => WebServer::saved_gift(uuid, version),
+            None => Err(WebServer::gift_conflict()),
+        }
+    }
+
+    // Saved like any other edit, so the revert is a revision of its own
+    pub async fn revert_gift(
+        gift: Form<RevertGift>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<RawJson<String>, AppError> {
+        let mut gift_data = conn
+            .gift_from_uuid(gift.gift_uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        Authorization::can_edit_gift(&current_user, &gift_data)?;
+        let list_uuid = conn.list_uuid_of_gift(gift_data.uuid.to_owned()).await?;
+        Authorization::can_edit_wishlist(&current_user, list_uuid.to_owned(), conn).await?;
+        let revision = conn
+            .gift_revisions(gift_data.uuid.to_owned())
+            .await?
+            .into_iter()
+            .find(|r| r.version == gift.revision)
+            .ok_or_else(|| AppError::Validation("That revision doesn't exist".to_string()))?;
+        gift_data.details = revision.details;
+        let uuid = gift_data.uuid.to_owned();
+        match conn.update_gift(list_uuid, gift_data, gift.version).await? {
+            Some(version) => WebServer::saved_gift(uuid, version),
+            None => Err(WebServer::gift_conflict()),
         }
+    }
+
+    pub async fn reorder_gifts(
+        order: Form<ReorderGifts>,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<RawJson<String>, AppError> {
+        Authorization::can_edit_wishlist(&current_user, order.list_uuid.to_owned(), conn).await?;
+        let existing_gifts = conn
+            .gifts_of_list_user(
+                order.list_uuid.to_owned(),
+                current_user.uuid.to_owned(),
+                current_user.uuid.to_owned(),
+            )
+            .await?;
+        // Only gifts with no main gift have a place of their own, alternates follow theirs
+        for gift_uuid in &order.gifts {
+            let known = existing_gifts
+                .iter()
+                .any(|g| &g.uuid == gift_uuid && g.alternate_to_uuid.is_none());
+            if !known {
+                return Err(AppError::Validation(
+                    "Only gifts on your list can be reordered".to_string(),
+                ));
+            }
+        }
+        let version = conn
+            .reorder_gifts(
+                order.list_uuid.to_owned(),
+                current_user.uuid.to_owned(),
+                order.version,
+                order.gifts.to_owned(),
+            )
+            .await?;
+        match version {
+            Some(version) => Ok(RawJson(version.to_string())),
+            None => Err(WebServer::gift_conflict()),
+        }
+    }
+
+    // Replaces the whole wishlist, so the page only uses it for an explicit import
+    pub async fn import_gift_list(
+        gifts: Form<ImportGiftList>,
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        Authorization::can_edit_wishlist(&current_user, gifts.list_uuid.to_owned(), conn).await?;
 
         let mut new_uuid_map: HashMap<String, String> = HashMap::new();
         let existing_gifts = conn
@@ -256,8 +933,13 @@ impl WebServer {
                 current_user.uuid.to_owned(),
                 current_user.uuid.to_owned(),
             )
-            .await
-            .unwrap();
+            .await?;
+        let version = conn
+            .wishlist_version(gifts.list_uuid.to_owned(), current_user.uuid.to_owned())
+            .await?;
+        if version != gifts.version {
+            return Err(WebServer::gift_list_conflict(&gifts, &existing_gifts));
+        }
         let existing_gifts_uuids = existing_gifts
             .iter()
             .map(|gift| gift.uuid.to_owned())
@@ -275,211 +957,691 @@ impl WebServer {
             })
             .collect::<Vec<_>>();
 
-        for old_gift_uuid in existing_gifts_uuids {
-            if !new_gifts_uuid.contains(&old_gift_uuid) {
-                conn.delete_gift(old_gift_uuid).await;
+        // Check every existing gift before touching anything
+        let mut gifts_to_modify: HashMap<String, Gift> = HashMap::new();
+        for gift in &gifts.gifts {
+            if gift.uuid.is_empty() || gift.uuid.starts_with("newRow-") {
+                continue;
             }
+            let gift_data = conn
+                .gift_from_uuid(gift.uuid.to_owned(), current_user.uuid.to_owned())
+                .await?;
+            Authorization::can_edit_gift(&current_user, &gift_data)?;
+            gifts_to_modify.insert(gift_data.uuid.to_owned(), gift_data);
         }
+        WebServer::check_import_alternates(&gifts.gifts, &gifts_to_modify)?;
 
+        let removed = existing_gifts_uuids
+            .into_iter()
+            .filter(|uuid| !new_gifts_uuid.contains(uuid))
+            .collect::<Vec<_>>();
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
         for gift in &gifts.gifts {
             let alternate = gift.alternate_to_uuid.to_owned();
-            let opt_alt = if alternate.starts_with("newRow-") {
-                Option::Some(new_uuid_map.get(alternate.as_str()).unwrap().to_owned())
-            } else if alternate.is_empty() {
+            let opt_alt = if alternate.is_empty() {
                 Option::None
-            } else {
+            } else if let Some(uuid) = new_uuid_map.get(alternate.as_str()) {
+                Option::Some(uuid.to_owned())
+            } else if new_gifts_uuid.contains(&alternate) {
                 Option::Some(alternate)
+            } else {
+                return Err(AppError::Validation(
+                    "An alternate points at a gift that isn't on the list".to_string(),
+                ));
             };
-            if gift.uuid.is_empty() {
-                let gift_data = Gift {
-                    uuid: Uuid::new_v4().to_string(),
-                    owner: current_user.uuid.to_owned(),
-                    url: gift.url.to_owned(),
-                    comment: gift.comment.to_owned(),
-                    claimed: false,
-                    claimed_by: None,
-                    alternate_to_uuid: opt_alt,
+            let details = WebServer::gift_details(&gift.details, config)?;
+            if gift.uuid.is_empty() || gift.uuid.starts_with("newRow-") {
+                let uuid = match new_uuid_map.get(gift.uuid.as_str()) {
+                    Some(uuid) => uuid.to_owned(),
+                    None => Uuid::new_v4().to_string(),
                 };
-                conn.create_gift(gift_data, gifts.list_uuid.to_owned())
-                    .await;
-            } else if gift.uuid.starts_with("newRow-") {
-                let gift_data = Gift {
-                    uuid: new_uuid_map.get(gift.uuid.as_str()).unwrap().to_owned(),
+                added.push(Gift {
+                    uuid,
                     owner: current_user.uuid.to_owned(),
-                    url: gift.url.to_owned(),
-                    comment: gift.comment.to_owned(),
-                    claimed: false,
-                    claimed_by: None,
+                    details,
+                    claims: Vec::new(),
                     alternate_to_uuid: opt_alt,
-                };
-                conn.create_gift(gift_data, gifts.list_uuid.to_owned())
-                    .await;
-            } else {
-                let mut gift_data = conn
-                    .gift_from_uuid(gift.uuid.to_owned(), current_user.uuid.to_owned())
-                    .await
-                    .unwrap();
-                if gift_data.owner != current_user.uuid {
-                    return "Can't modify item you don't own".to_string();
-                }
+                    version: 0,
+                });
+            } else if let Some(mut gift_data) = gifts_to_modify.remove(&gift.uuid) {
+                gift_data.details = details;
+                modified.push(gift_data);
+            }
+        }
+        // Main gifts go in before the alternates that point at them
+        added.sort_by_key(|gift| gift.alternate_to_uuid.is_some());
+        let saved = conn
+            .save_gift_list(
+                gifts.list_uuid.to_owned(),
+                current_user.uuid.to_owned(),
+                gifts.version,
+                removed,
+                added,
+                modified,
+            )
+            .await?;
+        if !saved {
+            // Lost a race with another save after the check above, so look again
+            let existing_gifts = conn
+                .gifts_of_list_user(
+                    gifts.list_uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                )
+                .await?;
+            return Err(WebServer::gift_list_conflict(&gifts, &existing_gifts));
+        }
+        Ok("Success!".to_string())
+    }
 
-                gift_data.url = gift.url.to_owned();
-                gift_data.comment = gift.comment.to_owned();
-                conn.modify_gift(gift_data).await;
+    // Every alternate has to point at a main gift in the same import, which also rules out
+    // chains and cycles. Gifts already saved keep the alternate they have
+    fn check_import_alternates(
+        gifts: &[FormGift],
+        existing: &HashMap<String, Gift>,
+    ) -> Result<(), AppError> {
+        let alternate_of = |uuid: &str| -> Option<String> {
+            if let Some(gift) = existing.get(uuid) {
+                return Some(gift.alternate_to_uuid.to_owned().unwrap_or_default());
+            }
+            gifts
+                .iter()
+                .find(|g| !g.uuid.is_empty() && g.uuid == uuid)
+                .map(|g| g.alternate_to_uuid.to_owned())
+        };
+        for gift in gifts {
+            let alternate =
+                alternate_of(&gift.uuid).unwrap_or_else(|| gift.alternate_to_uuid.to_owned());
+            if alternate.is_empty() {
+                continue;
+            }
+            match alternate_of(&alternate) {
+                None => {
+                    return Err(AppError::Validation(
+                        "An alternate points at a gift that isn't on the list".to_string(),
+                    ))
+                }
+                Some(base_alternate) if !base_alternate.is_empty() => {
+                    return Err(AppError::Validation(
+                        "An alternate can only point at a main gift, not at another alternate"
+                            .to_string(),
+                    ))
+                }
+                Some(_) => {}
             }
         }
-        "Success!".to_string()
+        Ok(())
     }
 
     pub async fn create_list(
         list: Form<CreateList>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> String {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        if !current_user.can_create {
-            return "You don't have permission to create lists".to_string();
-        }
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        Authorization::can_create_list(&current_user)?;
+        WebServer::validate_list(&list.name, &list.users)?;
         let list_uuid = Uuid::new_v4().to_string();
         let list_data = List {
             uuid: list_uuid.to_owned(),
             name: list.name.to_owned(),
             owner: current_user.uuid.to_owned(),
-            im_owner: true,
+            my_role: Some(ListRole::CoOwner),
+            can_manage: true,
         };
-        conn.create_list(list_data).await;
-        for list_user in list.users.to_owned() {
-            WebServer::create_user_and_add_to_list(
-                list.name.to_owned(),
-                list_uuid.to_owned(),
-                current_user.uuid.to_owned(),
-                list_user,
-                conn,
-            )
-            .await;
+        conn.create_list(list_data).await?;
+        let mut invites = Vec::new();
+        for list_user in list.users.iter().cloned() {
+            invites.extend(
+                WebServer::create_user_and_add_to_list(
+                    list_uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                    list_user,
+                    config,
+                    conn,
+                )
+                .await?,
+            );
         }
-        "Success!".to_string()
+        Ok(WebServer::send_invites(&list.name, invites, config).await)
     }
 
     pub async fn modify_list(
         list: Form<ModifyList>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
-    ) -> String {
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
+        current_user: User,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        Authorization::can_manage_list(&current_user, list.uuid.to_owned(), conn).await?;
+        WebServer::validate_list(&list.name, &list.users)?;
         let mut list_data = conn
             .list_from_uuid(list.uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        if !list_data.im_owner {
-            return "Can't modify list you don't own".to_string();
-        }
+            .await?;
+        let list_owner = list_data.owner.to_owned();
         list_data.name = list.name.to_owned();
-        conn.modify_list(list_data).await;
+        conn.modify_list(list_data).await?;
         let users = conn
             .users_of_list(list.uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        let existing_user_emails: Vec<String> = users.iter().map(|u| u.email.to_owned()).collect();
-        for list_user in list.users.to_owned() {
-            if existing_user_emails.contains(&list_user.email) {
+            .await?;
+        let submitted_emails: Vec<String> = list.users.iter().map(|u| u.email.to_owned()).collect();
+        for member in &users {
+            // The list's creator always stays on as a co-owner
+            if member.user.uuid == list_owner || submitted_emails.contains(&member.user.email) {
                 continue;
             }
-            WebServer::create_user_and_add_to_list(
-                list.name.to_owned(),
-                list.uuid.to_owned(),
-                current_user.uuid.to_owned(),
-                list_user,
-                conn,
-            )
-            .await;
+            conn.remove_user_from_list(member.user.uuid.to_owned(), list.uuid.to_owned())
+                .await?;
+        }
+        let mut invites = Vec::new();
+        for list_user in list.users.iter().cloned() {
+            if let Some(member) = users.iter().find(|u| u.user.email == list_user.email) {
+                if member.user.uuid != list_owner && member.role != list_user.role {
+                    conn.set_list_role(
+                        member.user.uuid.to_owned(),
+                        list.uuid.to_owned(),
+                        list_user.role,
+                    )
+                    .await?;
+                }
+                continue;
+            }
+            invites.extend(
+                WebServer::create_user_and_add_to_list(
+                    list.uuid.to_owned(),
+                    current_user.uuid.to_owned(),
+                    list_user,
+                    config,
+                    conn,
+                )
+                .await?,
+            );
         }
-        // TODO: Remove deleted users
-        "Success!".to_string()
+        Ok(WebServer::send_invites(&list.name, invites, config).await)
     }
 
     pub async fn delete_list(
         list: Form<DeleteList>,
-        cookies: &CookieJar<'_>,
-        conn: &DbConn,
+        current_user: User,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        Authorization::can_manage_list(&current_user, list.uuid.to_owned(), conn).await?;
+        conn.delete_list(list.uuid.to_owned()).await?;
+        Ok("Success!".to_string())
+    }
+
+    // Everyone is on the list by now, so a failed email is reported rather than undoing anything
+    async fn send_invites(
+        list_name: &str,
+        invites: Vec<(User, String)>,
+        config: &AppConfig,
     ) -> String {
-        // Check to see if we should warn about deleting
-        let current_user = WebServer::get_current_user(cookies, conn).await.unwrap();
-        let list_data = conn
-            .list_from_uuid(list.uuid.to_owned(), current_user.uuid.to_owned())
-            .await
-            .unwrap();
-        if !list_data.im_owner {
-            return "Can't delete list you don't own".to_string();
+        let mut failed = Vec::new();
+        for (user, login_token) in invites {
+            let name = format!("{} <{}>", user.name, user.email);
+            if let Err(e) =
+                WebServer::send_email_for_list(list_name.to_owned(), user, login_token, config)
+                    .await
+            {
+                failed.push(format!("{} ({})", name, e));
+            }
+        }
+        if failed.is_empty() {
+            "Success!".to_string()
+        } else {
+            format!(
+                "The list was saved, but these invites couldn't be emailed: {}",
+                failed.join(", ")
+            )
         }
-        conn.delete_list(list.uuid.to_owned()).await;
-        "Success!".to_string()
     }
 
-    async fn send_email_for_list(list_name: String, user: User, auth_token: String) {
-        let email_client = Email::build();
-        email_client
-            .send_invite_email(list_name, auth_token, user.name, user.email)
-            .unwrap();
+    async fn send_email_for_list(
+        list_name: String,
+        user: User,
+        login_token: String,
+        config: &AppConfig,
+    ) -> Result<(), AppError> {
+        let email_client = Email::build()?;
+        email_client.send_invite_email(
+            list_name,
+            login_token,
+            config.login_link_hours,
+            user.name,
+            user.email,
+        )?;
+        Ok(())
+    }
+
+    fn saved_gift(uuid: String, version: i64) -> Result<RawJson<String>, AppError> {
+        serde_json::to_string(&SavedGift { uuid, version })
+            .map(RawJson)
+            .map_err(|e| AppError::Backend(e.to_string()))
+    }
+
+    fn gift_conflict() -> AppError {
+        AppError::Conflict(
+            "This was changed somewhere else since you opened the page, so nothing was saved. \
+            Reload the page to get the latest version."
+                .to_string(),
+        )
+    }
+
+    // Lists what changed since the page was loaded, judged by the versions gifts were saved at
+    fn gift_list_conflict(gifts: &ImportGiftList, current: &[Gift]) -> AppError {
+        let label = |title: &str, url: &str, comment: &str| {
+            [title, url, comment]
+                .iter()
+                .find(|text| !text.is_empty())
+                .copied()
+                .unwrap_or_default()
+                .to_owned()
+        };
+        let mut changes = Vec::new();
+        for gift in current.iter().filter(|g| g.version > gifts.version) {
+            let seen = gifts.gifts.iter().any(|g| g.uuid == gift.uuid);
+            let change = if seen { "Edited" } else { "Added" };
+            let details = &gift.details;
+            let label = label(&details.title, &details.url, &details.comment);
+            changes.push(format!("{}: {}", change, label));
+        }
+        for gift in &gifts.gifts {
+            let known = !gift.uuid.is_empty() && !gift.uuid.starts_with("newRow-");
+            if known && !current.iter().any(|g| g.uuid == gift.uuid) {
+                let details = &gift.details;
+                let label = label(&details.title, &details.url, &details.comment);
+                changes.push(format!("Removed: {}", label));
+            }
+        }
+        AppError::Conflict(format!(
+            "This list was saved somewhere else since you opened it, so nothing was saved.\n{}\nReload the page to get the latest version.",
+            changes.join("\n")
+        ))
+    }
+
+    fn validate_user(name: &str, email: &str) -> Result<(), AppError> {
+        if name.trim().is_empty() {
+            return Err(AppError::Validation("Every user needs a name".to_string()));
+        }
+        if !email.contains('@') {
+            return Err(AppError::Validation(format!(
+                "{} isn't a valid email address",
+                email
+            )));
+        }
+        Ok(())
+    }
+
+    fn too_few_left(gift: &Gift, left: i64) -> AppError {
+        if left > 0 {
+            return AppError::Validation(format!("Only {} left to claim", left));
+        }
+        let claimers = gift
+            .claims
+            .iter()
+            .filter(|c| !c.is_me)
+            .map(|c| c.name.to_owned())
+            .collect::<Vec<_>>();
+        if claimers.is_empty() {
+            AppError::Validation("Item already claimed".to_string())
+        } else {
+            AppError::Validation(format!("Item already claimed by {}", claimers.join(", ")))
+        }
+    }
+
+    // JSON to put inside a <script> block. A < only ever shows up inside a string, where \u003c
+    // means the same thing and can't close the block
+    fn script_json<T: Serialize>(value: &T) -> Result<String, AppError> {
+        let json = serde_json::to_string(value).map_err(|e| AppError::Backend(e.to_string()))?;
+        Ok(json.replace('<', "\\u003c"))
+    }
+
+    // Checks what was typed in and turns it into what gets stored
+    fn gift_details(form: &FormGiftDetails, config: &AppConfig) -> Result<GiftDetails, AppError> {
+        if form.title.trim().is_empty()
+            && form.url.trim().is_empty()
+            && form.comment.trim().is_empty()
+        {
+            return Err(AppError::Validation(
+                "A gift needs a title, a url or a comment".to_string(),
+            ));
+        }
+        let url = form.url.trim().to_lowercase();
+        if !url.is_empty() && !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(AppError::Validation(format!(
+                "{} isn't a web address, links have to start with http:// or https://",
+                form.url
+            )));
+        }
+        let price = WebServer::parse_price(&form.price)?;
+        let mut currency = form.currency.trim().to_uppercase();
+        if currency.is_empty() && price.is_some() {
+            currency = config.default_currency.to_owned();
+        }
+        if !currency.is_empty()
+            && (currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()))
+        {
+            return Err(AppError::Validation(format!(
+                "{} isn't a currency, use a three letter code like USD",
+                form.currency
+            )));
+        }
+        if !(1..=WebServer::MAX_QUANTITY).contains(&form.quantity) {
+            return Err(AppError::Validation(format!(
+                "The quantity has to be from 1 to {}",
+                WebServer::MAX_QUANTITY
+            )));
+        }
+        Ok(GiftDetails {
+            title: form.title.to_owned(),
+            url: form.url.to_owned(),
+            comment: form.comment.to_owned(),
+            price,
+            currency,
+            quantity: form.quantity,
+            priority: form.priority,
+            notes: form.notes.to_owned(),
+        })
+    }
+
+    // Whole units with up to two decimals, like 12 or 12.50, stored in hundredths
+    fn parse_price(price: &str) -> Result<Option<i64>, AppError> {
+        let price = price.trim();
+        if price.is_empty() {
+            return Ok(None);
+        }
+        let invalid = || AppError::Validation(format!("{} isn't a valid price", price));
+        let (whole, fraction) = price.split_once('.').unwrap_or((price, ""));
+        let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() || !digits(whole) || fraction.len() > 2 || !digits(fraction) {
+            return Err(invalid());
+        }
+        let whole = whole.parse::<i64>().map_err(|_| invalid())?;
+        let fraction = format!("{:0<2}", fraction)
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+        whole
+            .checked_mul(100)
+            .and_then(|hundredths| hundredths.checked_add(fraction))
+            .map(Some)
+            .ok_or_else(invalid)
+    }
+
+    fn validate_list(name: &str, users: &[ListUser]) -> Result<(), AppError> {
+        if name.trim().is_empty() {
+            return Err(AppError::Validation("The list needs a name".to_string()));
+        }
+        for user in users {
+            WebServer::validate_user(&user.name, &user.email)?;
+        }
+        Ok(())
     }
 
+    fn generate_token() -> String {
+        let mut rng = StdRng::from_entropy();
+        let mut new_token: [u8; 256] = [0; 256];
+        rng.fill_bytes(&mut new_token);
+        hex::encode(new_token)
+    }
+
+    pub async fn issue_login_token(
+        user_uuid: String,
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<String, AppError> {
+        let login_token = WebServer::generate_token();
+        conn.create_login_token(
+            user_uuid,
+            login_token.to_owned(),
+            config.login_link_hours * 60 * 60,
+        )
+        .await?;
+        Ok(login_token)
+    }
+
+    // Hands back the user and their login token to invite them with, unless they were on it already
     async fn create_user_and_add_to_list(
-        list_name: String,
         list_uuid: String,
         current_user_uuid: String,
         list_user: ListUser,
-        conn: &DbConn,
-    ) {
+        config: &AppConfig,
+        conn: &dyn Repository,
+    ) -> Result<Option<(User, String)>, AppError> {
         let user = match conn.user_from_email(list_user.email.to_owned()).await {
             Ok(u) => u,
-            Err(_) => {
-                let mut rng = StdRng::from_entropy();
-                let mut new_token: [u8; 256] = [0; 256];
-                rng.fill_bytes(&mut new_token);
+            Err(AppError::NotFound) => {
                 let new_user_uuid = Uuid::new_v4().to_string();
                 let new_user = User {
                     uuid: new_user_uuid.to_owned(),
                     email: list_user.email.to_owned(),
                     name: list_user.name.to_owned(),
                     can_create: false,
+                    is_admin: false,
                     is_me: false,
                 };
-                conn.create_user(new_user, hex::encode(new_token)).await;
+                conn.create_user(new_user).await?;
                 conn.user_from_uuid(new_user_uuid, current_user_uuid)
-                    .await
-                    .unwrap()
+                    .await?
             }
+            Err(e) => return Err(e),
         };
-        conn.add_user_to_list(user.uuid.to_owned(), list_uuid).await;
-        let auth_token = conn
-            .auth_token_from_user(user.uuid.to_owned())
+        if conn
+            .role_of_user(list_uuid.to_owned(), user.uuid.to_owned())
             .await
-            .unwrap();
-        WebServer::send_email_for_list(list_name, user, auth_token).await;
+            .is_ok()
+        {
+            return Ok(None);
+        }
+        conn.add_user_to_list(user.uuid.to_owned(), list_uuid, list_user.role)
+            .await?;
+        let login_token = WebServer::issue_login_token(user.uuid.to_owned(), config, conn).await?;
+        Ok(Some((user, login_token)))
     }
 
-    fn set_user_cookie(user_uuid: String, cookies: &CookieJar<'_>) {
-        let mut cookie = Cookie::new(WebServer::USER_COOKIE, user_uuid);
+    fn set_user_cookie(session_token: String, cookies: &CookieJar<'_>) {
+        let mut cookie = Cookie::new(WebServer::USER_COOKIE, session_token);
         cookie.set_same_site(SameSite::Lax);
         cookies.add_private(cookie);
     }
 
     pub fn get_user_cookie(cookies: &CookieJar<'_>) -> Result<String, String> {
         let cookie = cookies.get_private(WebServer::USER_COOKIE);
-        return match cookie {
+        match cookie {
             Some(c) => Ok(c.value().to_string()),
             None => Err("No cookie retrieved".to_string()),
-        };
+        }
     }
 
-    async fn get_current_user(cookies: &CookieJar<'_>, conn: &DbConn) -> Result<User, String> {
-        let current_user_uuid = WebServer::get_user_cookie(cookies);
-        return match current_user_uuid {
-            Ok(u) => match conn.user_from_uuid(u.to_owned(), u).await {
-                Ok(r) => Ok(r),
-                Err(e) => Err(e.to_string()),
-            },
-            Err(e) => Err(e),
+    pub async fn get_current_user(
+        cookies: &CookieJar<'_>,
+        conn: &dyn Repository,
+    ) -> Result<User, AppError> {
+        let session_token = WebServer::get_user_cookie(cookies).map_err(|_| AppError::Forbidden)?;
+        let current_user_uuid = conn.user_uuid_from_session(session_token).await?;
+        conn.user_from_uuid(current_user_uuid.to_owned(), current_user_uuid)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::Figment;
+    use rocket::form::Form;
+    use uuid::Uuid;
+
+    use super::WebServer;
+    use crate::data::{
+        AppConfig, FormGift, FormGiftDetails, GiftPriority, ImportGiftList, List, User,
+    };
+    use crate::db::tests::storage;
+    use crate::error::AppError;
+    use crate::memory::MemoryStore;
+    use crate::repository::Repository;
+    use crate::repository_tests::{gift, new_list, new_user};
+
+    fn config() -> AppConfig {
+        Figment::new().extract().unwrap()
+    }
+
+    fn details(url: &str, comment: &str) -> FormGiftDetails {
+        FormGiftDetails {
+            title: "".to_string(),
+            url: url.to_string(),
+            comment: comment.to_string(),
+            price: "".to_string(),
+            currency: "".to_string(),
+            quantity: 1,
+            priority: GiftPriority::Medium,
+            notes: "".to_string(),
+        }
+    }
+
+    fn import_row(uuid: &str, comment: &str, alternate_to: &str) -> FormGift {
+        FormGift {
+            uuid: uuid.to_string(),
+            details: details("", comment),
+            alternate_to_uuid: alternate_to.to_string(),
+        }
+    }
+
+    #[test]
+    fn only_web_links_are_kept() {
+        let config = config();
+        for url in ["javascript:alert(1)", "data:text/html,hi", "example.com"] {
+            assert!(matches!(
+                WebServer::gift_details(&details(url, ""), &config),
+                Err(AppError::Validation(_))
+            ));
+        }
+        for url in ["", "https://example.com/socks", "HTTP://example.com"] {
+            let saved = WebServer::gift_details(&details(url, "socks"), &config).unwrap();
+            assert_eq!(saved.url, url);
+        }
+    }
+
+    #[test]
+    fn script_json_cannot_close_its_script() {
+        let comment = "</script><script>alert(1)</script>";
+        let json = WebServer::script_json(&[comment]).unwrap();
+        assert!(!json.contains('<'));
+        assert_eq!(
+            serde_json::from_str::<Vec<String>>(&json).unwrap(),
+            [comment]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn imported_alternates_must_point_at_a_main_gift() {
+        let (_rocket, conn) = storage().await;
+        let owner = User {
+            uuid: Uuid::new_v4().to_string(),
+            email: "ada@example.com".to_string(),
+            name: "Ada".to_string(),
+            can_create: true,
+            is_admin: false,
+            is_me: false,
         };
+        conn.create_user(owner.to_owned()).await.unwrap();
+        let config = config();
+        let list_uuid = Uuid::new_v4().to_string();
+        conn.create_list(List {
+            uuid: list_uuid.to_owned(),
+            name: "Birthdays".to_string(),
+            owner: owner.uuid.to_owned(),
+            my_role: None,
+            can_manage: false,
+        })
+        .await
+        .unwrap();
+        let import = |gifts: Vec<FormGift>, version| {
+            WebServer::import_gift_list(
+                Form::from(ImportGiftList {
+                    list_uuid: list_uuid.to_owned(),
+                    version,
+                    gifts,
+                }),
+                owner.to_owned(),
+                &config,
+                &*conn,
+            )
+        };
+
+        for gifts in [
+            // A cycle
+            vec![
+                import_row("newRow-1", "bike", "newRow-2"),
+                import_row("newRow-2", "scooter", "newRow-1"),
+            ],
+            // A chain
+            vec![
+                import_row("newRow-1", "bike", ""),
+                import_row("newRow-2", "scooter", "newRow-1"),
+                import_row("newRow-3", "skates", "newRow-2"),
+            ],
+            // Nothing to point at
+            vec![import_row("newRow-1", "scooter", "newRow-9")],
+        ] {
+            assert!(matches!(
+                import(gifts, 0).await,
+                Err(AppError::Validation(_))
+            ));
+        }
+
+        // An alternate can come before its gift
+        import(
+            vec![
+                import_row("newRow-1", "scooter", "newRow-2"),
+                import_row("newRow-2", "bike", ""),
+            ],
+            0,
+        )
+        .await
+        .unwrap();
+        let gifts = conn
+            .gifts_of_list_user(
+                list_uuid.to_owned(),
+                owner.uuid.to_owned(),
+                owner.uuid.to_owned(),
+            )
+            .await
+            .unwrap();
+        let bike = gifts.iter().find(|g| g.details.comment == "bike").unwrap();
+        let scooter = gifts
+            .iter()
+            .find(|g| g.details.comment == "scooter")
+            .unwrap();
+        assert_eq!(bike.alternate_to_uuid, None);
+        assert_eq!(scooter.alternate_to_uuid.as_ref(), Some(&bike.uuid));
+    }
+
+    #[rocket::async_test]
+    async fn losing_a_claim_race_names_the_winner() {
+        let conn = MemoryStore::default();
+        let owner = new_user(&conn, "Ada").await;
+        let winner = new_user(&conn, "Grace").await;
+        let loser = new_user(&conn, "Linus").await;
+        let winner = conn
+            .user_from_uuid(winner.to_owned(), winner)
+            .await
+            .unwrap();
+        let loser = conn.user_from_uuid(loser.to_owned(), loser).await.unwrap();
+        let list = new_list(&conn, &owner).await;
+        let gift = gift(&Uuid::new_v4().to_string(), &owner, "");
+        conn.add_gift(list, gift.to_owned()).await.unwrap();
+
+        // Both looked the last one up before either claimed it
+        let seen = conn
+            .gift_from_uuid(gift.uuid.to_owned(), loser.uuid.to_owned())
+            .await
+            .unwrap();
+        WebServer::claim_loaded_gift(&seen, 1, &winner, &conn)
+            .await
+            .unwrap();
+        match WebServer::claim_loaded_gift(&seen, 1, &loser, &conn).await {
+            Err(AppError::Validation(message)) => {
+                assert_eq!(message, "Item already claimed by Grace")
+            }
+            other => panic!("expected the claim to be refused, got {:?}", other),
+        }
     }
 }